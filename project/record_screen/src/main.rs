@@ -8,7 +8,6 @@ use scrap::{Capturer, Display};
 use vpx_encode;
 use cpal;
 use webm::mux;
-use webm::mux::Track;
 
 mod convert;
 mod sound;
@@ -82,10 +81,36 @@ fn main() -> io::Result<()> {
         println!("chunk cb: {}", chunk_file_name);
     };
 
-    let mut writer = mux::WebmWriter::new("", filename, chunk_cb);    
-    let mut webm =
+    let mut writer = match mux::WebmWriter::new("", filename, chunk_cb) {
+        Ok(writer) => writer,
+        Err(e) => {
+            error(e);
+            return Ok(());
+        }
+    };
+    // 定期落盘，进程崩溃时最多丢失几秒，可用 webm-repair 修复
+    writer.set_sync_interval(Some(Duration::from_secs(2)));
+    let segment =
         mux::Segment::new(writer).expect("Could not initialize the multiplexer.");
-    let mut vt = webm.add_video_track(width, height, None, mux_codec);
+    // 音轨的 OpusHead 等参数要在交给 Multiplexer 之前设置
+    let audio = match mic {
+        Some(mic) => match sound::add_track(&segment, &mic) {
+            Ok(number) => Some((mic, number)),
+            Err(e) => {
+                error(e);
+                None
+            }
+        },
+        None => {
+            error("mic invalid");
+            None
+        }
+    };
+    // 视频和音频在不同线程写入，由 Multiplexer 按时间戳交织
+    let webm = mux::Multiplexer::new(segment);
+    let vt = webm
+        .add_video_track(width, height, None, mux_codec)
+        .expect("Could not add the video track.");
             
     // 开始录制
     let start = Instant::now();
@@ -93,12 +118,14 @@ fn main() -> io::Result<()> {
 
     // 采集声音
     let ba = 96;
-    if let Some(mic) = mic {
-        if let Err(e) = sound::run(stop.clone(), mic, &mut webm, ba) {
+    if let Some((mic, number)) = audio {
+        let sent = webm
+            .sender(number)
+            .map_err(sound::Error::from)
+            .and_then(|at| sound::run(stop.clone(), mic, at, ba));
+        if let Err(e) = sent {
             error(e);
         }
-    } else {
-        error("mic invalid");
     }
 
     thread::spawn({
@@ -133,7 +160,12 @@ fn main() -> io::Result<()> {
                 convert::argb_to_i420(width as usize, height as usize, &frame, &mut yuv);
 
                 for frame in vpx.encode(ms as i64, &yuv).unwrap() {                    
-                    vt.add_frame(frame.data, frame.pts as u64 * 1_000_000, frame.key);
+                    // 写入失败（如磁盘已满）时停止录制，保留已写入的部分
+                    if let Err(e) = vt.add_frame(frame.data, frame.pts as u64 * 1_000_000, frame.key) {
+                        error(e);
+                        stop.store(true, Ordering::Release);
+                        break;
+                    }
                 }
             }
             Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {                
@@ -152,13 +184,20 @@ fn main() -> io::Result<()> {
     }
 
     // 结束
+    // 时长到达或采集出错时也要通知音频线程停止，避免 finalize 之后还在写入
+    stop.store(true, Ordering::Release);
 
     let mut frames = vpx.finish().unwrap();
     while let Some(frame) = frames.next().unwrap() {
-        vt.add_frame(frame.data, frame.pts as u64 * 1_000_000, frame.key);
+        if let Err(e) = vt.add_frame(frame.data, frame.pts as u64 * 1_000_000, frame.key) {
+            error(e);
+            break;
+        }
     }
 
-    let _ = webm.finalize(None);
+    if let Err(e) = webm.finalize(None) {
+        error(e);
+    }
 
     Ok(())
 }
@@ -5,17 +5,37 @@ use sample::{interpolate, signal};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::{error, fmt, thread};
-use webm::mux;
-use webm::mux::Track;
+use webm::mux::{self, Track};
 
 const SAMPLE_RATE: usize = 48_000;
 const FRAME_SIZE: usize = 960;
 const MAX_PACKET: usize = 4000;
+// libopus 在 48 kHz 下的前瞻样本数，解码时跳过
+const PRE_SKIP: u16 = 312;
+
+// 在 segment 交给 Multiplexer 之前添加并配置 Opus 音轨，返回轨道号
+pub fn add_track(segment: &mux::Segment, mic: &cpal::Device) -> Result<u64, Error> {
+    let fmt = mic.default_input_format()?;
+    let head = mux::opus_head(SAMPLE_RATE as _, fmt.channels as _, PRE_SKIP)
+        .filter(|_| fmt.channels <= 2)
+        .ok_or(Error::BadChannelCount(fmt.channels))?;
+
+    let mut at = segment.add_audio_track(
+        SAMPLE_RATE as _,
+        fmt.channels as _,
+        None,
+        mux::AudioCodecId::Opus,
+    )?;
+    at.set_codec_private(&head)?;
+    at.set_codec_delay(PRE_SKIP as u64 * 1_000_000_000 / SAMPLE_RATE as u64);
+    at.set_seek_pre_roll(mux::OPUS_SEEK_PRE_ROLL_NS);
+    Ok(at.number())
+}
 
 pub fn run(
     stop: Arc<AtomicBool>,
     mic: cpal::Device,
-    webm: &mut mux::Segment,
+    at: mux::TrackSender,
     bitrate: u32,
 ) -> Result<(), Error> {
     let fmt = mic.default_input_format()?;
@@ -32,13 +52,6 @@ pub fn run(
 
     opus.set_bitrate(opus::Bitrate::Bits((bitrate * 1000) as _))?;
 
-    let mut at = webm.add_audio_track(
-        SAMPLE_RATE as _,
-        fmt.channels as _,
-        None,
-        mux::AudioCodecId::Opus,
-    );
-
     let evs = cpal::EventLoop::new();
     let id = evs.build_input_stream(&mic, &fmt)?;
     evs.play_stream(id);
@@ -64,9 +77,17 @@ pub fn run(
         let mut $o = [0u8; MAX_PACKET];
         let mut p = interpolate::Linear::new([0 as _; $chan], [0 as _; $chan]);
         let mut time = 0; //TODO: Won't this drift?
+        let mut dropped = 0u64;
 
         evs.run(move |_, data| {
             if stop.load(Ordering::Acquire) {
+                if dropped > 0 {
+                    crate::error(format!(
+                        "Dropped {} audio packets that fell too far behind the video.",
+                        dropped
+                    ));
+                    dropped = 0;
+                }
                 thread::park(); //TODO: End the thread.
                 return;
             }
@@ -89,7 +110,16 @@ pub fn run(
                 $i.extend_from_slice(&frame);
                 if $i.len() >= target {
                     let n = $write;
-                    at.add_frame(&$o[..n], time, true); //TODO: Which frames are key?
+                    //TODO: Which frames are key?
+                    match at.add_frame(&$o[..n], time, true) {
+                        Ok(()) => {}
+                        // 落后于视频太多的音频包丢弃，结束时报告数量
+                        Err(webm::Error::NonMonotonicTimestamp { .. }) => dropped += 1,
+                        Err(e) => {
+                            crate::error(e);
+                            stop.store(true, Ordering::Release);
+                        }
+                    }
                     $i.clear();
                     time += 20_000_000;
                 }
@@ -130,6 +160,7 @@ pub enum Error {
     StreamCreation(cpal::CreationError),
     DefaultFormat(cpal::DefaultFormatError),
     BadChannelCount(u16),
+    Webm(webm::Error),
 }
 
 impl From<opus::Error> for Error {
@@ -150,12 +181,19 @@ impl From<cpal::CreationError> for Error {
     }
 }
 
+impl From<webm::Error> for Error {
+    fn from(e: webm::Error) -> Self {
+        Error::Webm(e)
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Error::Opus(e) => e.fmt(f),
             Error::StreamCreation(e) => e.fmt(f),
             Error::DefaultFormat(e) => e.fmt(f),
+            Error::Webm(e) => e.fmt(f),
             Error::BadChannelCount(n) => {
                 write!(f, "Expected 1 or 2 channels, but found {} channels.", n,)
             }
@@ -17,6 +17,7 @@ fn main() {
     c.flag("-fno-exceptions");
     c.include("libwebm");
     for &f in files.iter() {
+        println!("cargo:rerun-if-changed={}", f);
         c.file(f);
     }
     c.compile("libwebmadapter.a");
@@ -10,6 +10,7 @@
 #include <stdint.h>
 #include <assert.h>
 #include <stdio.h>
+#include <string.h>
 
 extern "C" {
 
@@ -115,6 +116,56 @@ extern "C" {
     segment->set_mode(mux_mode);
   }
 
+  bool mux_segment_set_cues_track(MuxSegmentPtr segment, mkvmuxer::Track* track) {
+    return segment->CuesTrack(track->number());
+  }
+  void mux_segment_output_cues(MuxSegmentPtr segment, const bool output_cues) {
+    segment->OutputCues(output_cues);
+  }
+
+  void mux_segment_set_max_cluster_duration(MuxSegmentPtr segment, const uint64_t duration_ns) {
+    segment->set_max_cluster_duration(duration_ns);
+  }
+  void mux_segment_set_max_cluster_size(MuxSegmentPtr segment, const uint64_t size) {
+    segment->set_max_cluster_size(size);
+  }
+  void mux_segment_force_new_cluster(MuxSegmentPtr segment) {
+    segment->ForceNewClusterOnNextFrame();
+  }
+
+  bool mux_segment_add_tag(MuxSegmentPtr segment, mkvmuxer::Track* track, const char **names,
+                           const char **values, const size_t count) {
+    mkvmuxer::Tag *tag = segment->AddTag();
+    if (tag == nullptr) {
+      return false;
+    }
+    if (track != nullptr) {
+      tag->set_track_uid(track->uid());
+    }
+    for (size_t i = 0; i < count; ++i) {
+      if (!tag->add_simple_tag(names[i], values[i])) {
+        return false;
+      }
+    }
+    return true;
+  }
+
+  bool mux_segment_add_chapter(MuxSegmentPtr segment, const uint64_t start_ns, const uint64_t end_ns,
+                               const char *id, const char **titles, const char **languages,
+                               const size_t title_count) {
+    mkvmuxer::Chapter *chapter = segment->AddChapter();
+    if (chapter == nullptr || !chapter->set_id(id)) {
+      return false;
+    }
+    chapter->set_time(*segment, start_ns, end_ns);
+    for (size_t i = 0; i < title_count; ++i) {
+      if (!chapter->add_string(titles[i], languages[i], nullptr)) {
+        return false;
+      }
+    }
+    return true;
+  }
+
   void mux_set_writing_app(MuxSegmentPtr segment, const char *name) {
     auto info = segment->GetSegmentInfo();
     info->set_writing_app(name);
@@ -129,6 +180,29 @@ extern "C" {
     delete segment;
   }
 
+  // Timestamp of the last frame written to a cluster. Frames before it are
+  // rejected.
+  uint64_t mux_segment_last_timestamp(MuxSegmentPtr segment) {
+    return segment->last_timestamp();
+  }
+
+  // The codec ID of the first track whose header can not be written: an
+  // unknown codec, or AV1 without its CodecPrivate. Null if there is none.
+  const char* mux_segment_invalid_codec_id(MuxSegmentPtr segment) {
+    const mkvmuxer::Tracks* tracks = segment->tracks();
+    for (uint32_t i = 0; i < tracks->track_entries_size(); ++i) {
+      const mkvmuxer::Track* track = tracks->GetTrackByIndex(i);
+      if (track->codec_id() == nullptr) {
+        return "";
+      }
+      if (!strcmp(track->codec_id(), mkvmuxer::Tracks::kAv1CodecId) &&
+          track->codec_private() == nullptr) {
+        return track->codec_id();
+      }
+    }
+    return nullptr;
+  }
+
   typedef mkvmuxer::Track* MuxTrackPtr;
   typedef mkvmuxer::VideoTrack* MuxVideoTrackPtr;
   typedef mkvmuxer::AudioTrack* MuxAudioTrackPtr;
@@ -153,6 +227,7 @@ extern "C" {
   // video
   const uint32_t VP8_CODEC_ID = 0;
   const uint32_t VP9_CODEC_ID = 1;
+  const uint32_t AV1_CODEC_ID = 2;
 
   MuxVideoTrackPtr mux_segment_add_video_track(MuxSegmentPtr segment, const int32_t width,
                                                const int32_t height, const int32_t number,
@@ -163,6 +238,7 @@ extern "C" {
     switch(codec_id) {
     case VP8_CODEC_ID: codec_id_str = mkvmuxer::Tracks::kVp8CodecId; break;
     case VP9_CODEC_ID: codec_id_str = mkvmuxer::Tracks::kVp9CodecId; break;
+    case AV1_CODEC_ID: codec_id_str = mkvmuxer::Tracks::kAv1CodecId; break;
     default: return nullptr;
     }
 
@@ -195,6 +271,62 @@ extern "C" {
     return audio;
   }
 
+  // text
+  const uint32_t TEXT_KIND_SUBTITLES = 0;
+  const uint32_t TEXT_KIND_CAPTIONS = 1;
+  const uint32_t TEXT_KIND_DESCRIPTIONS = 2;
+  const uint32_t TEXT_KIND_METADATA = 3;
+
+  MuxTrackPtr mux_segment_add_text_track(MuxSegmentPtr segment, const uint32_t kind,
+                                         const char* language) {
+    if(segment == nullptr) { return nullptr; }
+
+    const char* codec_id_str = nullptr;
+    uint64_t track_type = 0;
+    switch(kind) {
+    case TEXT_KIND_SUBTITLES: codec_id_str = mkvmuxer::Tracks::kWebVttSubtitlesId; track_type = 0x11; break;
+    case TEXT_KIND_CAPTIONS: codec_id_str = mkvmuxer::Tracks::kWebVttCaptionsId; track_type = 0x11; break;
+    case TEXT_KIND_DESCRIPTIONS: codec_id_str = mkvmuxer::Tracks::kWebVttDescriptionsId; track_type = 0x21; break;
+    case TEXT_KIND_METADATA: codec_id_str = mkvmuxer::Tracks::kWebVttMetadataId; track_type = 0x21; break;
+    default: return nullptr;
+    }
+
+    auto track = segment->AddTrack(0);
+    if(track == nullptr) { return nullptr; }
+
+    track->set_type(track_type);
+    track->set_codec_id(codec_id_str);
+    if(language != nullptr) {
+      track->set_language(language);
+    }
+    return track;
+  }
+
+  bool mux_segment_add_metadata(MuxSegmentPtr segment, MuxTrackPtr track,
+                                const uint8_t* data, const size_t length,
+                                const uint64_t timestamp_ns, const uint64_t duration_ns) {
+    if(segment == nullptr || track == nullptr) { return false; }
+
+    return segment->AddMetadata(data, length, track->number(), timestamp_ns, duration_ns);
+  }
+
+  uint64_t mux_track_number(MuxTrackPtr track) {
+    return track->number();
+  }
+  MuxTrackPtr mux_segment_get_track(MuxSegmentPtr segment, const uint64_t number) {
+    return segment->GetTrackByNumber(number);
+  }
+
+  bool mux_track_set_codec_private(MuxTrackPtr track, const uint8_t* data, const uint64_t length) {
+    return track->SetCodecPrivate(data, length);
+  }
+  void mux_track_set_codec_delay(MuxTrackPtr track, const uint64_t delay_ns) {
+    track->set_codec_delay(delay_ns);
+  }
+  void mux_track_set_seek_pre_roll(MuxTrackPtr track, const uint64_t pre_roll_ns) {
+    track->set_seek_pre_roll(pre_roll_ns);
+  }
+
   int mux_set_color(MuxVideoTrackPtr video, int bits, int sampling_horiz, int sampling_vert, int full_range) {
     mkvmuxer::Colour color;
 
@@ -206,6 +338,101 @@ extern "C" {
     return video->SetColour(color);
   }
 
+  void mux_video_track_set_display_size(MuxVideoTrackPtr video, const uint64_t width,
+                                        const uint64_t height) {
+    video->set_display_width(width);
+    video->set_display_height(height);
+  }
+  void mux_video_track_set_crop(MuxVideoTrackPtr video, const uint64_t left, const uint64_t right,
+                                const uint64_t top, const uint64_t bottom) {
+    video->set_crop_left(left);
+    video->set_crop_right(right);
+    video->set_crop_top(top);
+    video->set_crop_bottom(bottom);
+  }
+  bool mux_video_track_set_stereo_mode(MuxVideoTrackPtr video, const uint64_t stereo_mode) {
+    return video->SetStereoMode(stereo_mode);
+  }
+  bool mux_video_track_set_alpha_mode(MuxVideoTrackPtr video, const uint64_t alpha_mode) {
+    return video->SetAlphaMode(alpha_mode);
+  }
+  void mux_video_track_set_frame_rate(MuxVideoTrackPtr video, const double frame_rate) {
+    video->set_frame_rate(frame_rate);
+  }
+  bool mux_video_track_set_projection(MuxVideoTrackPtr video, const int type,
+                                      const uint8_t* private_data, const uint64_t private_length,
+                                      const float yaw, const float pitch, const float roll) {
+    mkvmuxer::Projection projection;
+    if (private_length > 0 && !projection.SetProjectionPrivate(private_data, private_length)) {
+      return false;
+    }
+    projection.set_type(static_cast<mkvmuxer::Projection::ProjectionType>(type));
+    projection.set_pose_yaw(yaw);
+    projection.set_pose_pitch(pitch);
+    projection.set_pose_roll(roll);
+    return video->SetProjection(projection);
+  }
+
+  // Mirrors mkvmuxer::Colour; UINT64_MAX marks a field as absent.
+  struct MuxColour {
+    uint64_t matrix_coefficients;
+    uint64_t bits_per_channel;
+    uint64_t chroma_subsampling_horz;
+    uint64_t chroma_subsampling_vert;
+    uint64_t cb_subsampling_horz;
+    uint64_t cb_subsampling_vert;
+    uint64_t chroma_siting_horz;
+    uint64_t chroma_siting_vert;
+    uint64_t range;
+    uint64_t transfer_characteristics;
+    uint64_t primaries;
+    uint64_t max_cll;
+    uint64_t max_fall;
+    bool has_mastering_metadata;
+    // Chromaticities are x/y pairs in r, g, b, white point order; the
+    // luminances use FLT_MAX and the chromaticities a negative x for absent.
+    float chromaticities[8];
+    float luminance_max;
+    float luminance_min;
+  };
+
+  bool mux_set_colour(MuxVideoTrackPtr video, const MuxColour* src) {
+    mkvmuxer::Colour colour;
+    colour.set_matrix_coefficients(src->matrix_coefficients);
+    colour.set_bits_per_channel(src->bits_per_channel);
+    colour.set_chroma_subsampling_horz(src->chroma_subsampling_horz);
+    colour.set_chroma_subsampling_vert(src->chroma_subsampling_vert);
+    colour.set_cb_subsampling_horz(src->cb_subsampling_horz);
+    colour.set_cb_subsampling_vert(src->cb_subsampling_vert);
+    colour.set_chroma_siting_horz(src->chroma_siting_horz);
+    colour.set_chroma_siting_vert(src->chroma_siting_vert);
+    colour.set_range(src->range);
+    colour.set_transfer_characteristics(src->transfer_characteristics);
+    colour.set_primaries(src->primaries);
+    colour.set_max_cll(src->max_cll);
+    colour.set_max_fall(src->max_fall);
+
+    if (src->has_mastering_metadata) {
+      mkvmuxer::MasteringMetadata mastering;
+      mastering.set_luminance_max(src->luminance_max);
+      mastering.set_luminance_min(src->luminance_min);
+
+      mkvmuxer::PrimaryChromaticity points[4];
+      const mkvmuxer::PrimaryChromaticity* present[4];
+      for (int i = 0; i < 4; ++i) {
+        points[i] = mkvmuxer::PrimaryChromaticity(src->chromaticities[2 * i],
+                                                  src->chromaticities[2 * i + 1]);
+        present[i] = src->chromaticities[2 * i] < 0 ? nullptr : &points[i];
+      }
+      if (!mastering.SetChromaticity(present[0], present[1], present[2], present[3]) ||
+          !colour.SetMasteringMetadata(mastering)) {
+        return false;
+      }
+    }
+
+    return colour.Valid() && video->SetColour(colour);
+  }
+
   bool mux_segment_add_frame(MuxSegmentPtr segment, MuxTrackPtr track,
                              const uint8_t* frame, const size_t length,
                              const uint64_t timestamp_ns, const bool keyframe) {
@@ -214,4 +441,304 @@ extern "C" {
     return segment->AddFrame(frame, length, track->number(),
                              timestamp_ns, keyframe);
   }
+  // Mirrors the fields of mkvmuxer::Frame a caller can set.
+  struct MuxFrame {
+    const uint8_t* data;
+    size_t length;
+    const uint8_t* additional;
+    size_t additional_length;
+    uint64_t add_id;
+    uint64_t timestamp_ns;
+    bool keyframe;
+    bool has_duration;
+    uint64_t duration_ns;
+    int64_t discard_padding_ns;
+    bool has_reference;
+    int64_t reference_timestamp_ns;
+    uint8_t lacing;
+  };
+
+  bool mux_segment_add_generic_frame(MuxSegmentPtr segment, MuxTrackPtr track,
+                                     const MuxFrame* src) {
+    if(segment == nullptr || track == nullptr) { return false; }
+
+    mkvmuxer::Frame frame;
+    if (!frame.Init(src->data, src->length)) {
+      return false;
+    }
+    if (src->additional_length > 0 &&
+        !frame.AddAdditionalData(src->additional, src->additional_length, src->add_id)) {
+      return false;
+    }
+    frame.set_track_number(track->number());
+    frame.set_timestamp(src->timestamp_ns);
+    frame.set_is_key(src->keyframe);
+    if (src->has_duration) {
+      frame.set_duration(src->duration_ns);
+    }
+    frame.set_discard_padding(src->discard_padding_ns);
+    if (src->has_reference) {
+      frame.set_reference_block_timestamp(src->reference_timestamp_ns);
+    }
+    frame.set_lacing(src->lacing);
+    return segment->AddGenericFrame(&frame);
+  }
+
+  bool mux_segment_add_frame_with_additional(MuxSegmentPtr segment, MuxTrackPtr track,
+                                             const uint8_t* frame, const size_t length,
+                                             const uint8_t* additional,
+                                             const size_t additional_length,
+                                             const uint64_t add_id,
+                                             const uint64_t timestamp_ns, const bool keyframe) {
+    if(segment == nullptr || track == nullptr) { return false; }
+
+    return segment->AddFrameWithAdditional(frame, length, additional, additional_length, add_id,
+                                           track->number(), timestamp_ns, keyframe);
+  }
+}
+
+extern "C" {
+
+  struct FfiMkvReader: public mkvparser::IMkvReader {
+  public:
+    typedef int (*ReadFun)(void*, int64_t, int64_t, unsigned char*);
+    typedef int (*LengthFun)(void*, int64_t*, int64_t*);
+
+    ReadFun   read_   = nullptr;
+    LengthFun length_ = nullptr;
+
+    void* user_data = nullptr;
+
+    FfiMkvReader() = default;
+    virtual ~FfiMkvReader() = default;
+
+    int Read(long long pos, long len, unsigned char* buf) override final {
+      assert(this->read_ != nullptr);
+
+      if(pos < 0 || len < 0) { return -1; }
+      if(len == 0) { return 0; }
+
+      return this->read_(this->user_data, pos, len, buf);
+    }
+    int Length(long long* total, long long* available) override final {
+      assert(this->length_ != nullptr);
+
+      int64_t t = 0, a = 0;
+      const int status = this->length_(this->user_data, &t, &a);
+      if(total) { *total = t; }
+      if(available) { *available = a; }
+      return status;
+    }
+  };
+
+  struct DemuxContext {
+    FfiMkvReader reader;
+    mkvparser::Segment* segment = nullptr;
+    const mkvparser::Cluster* cluster = nullptr;
+    const mkvparser::BlockEntry* entry = nullptr;
+    const mkvparser::Block::Frame* frame = nullptr;
+    int frame_index = 0;
+    bool truncated = false;
+
+    ~DemuxContext() {
+      delete segment;
+    }
+  };
+  typedef DemuxContext* DemuxContextPtr;
+
+  struct DemuxTrackInfo {
+    uint64_t number;
+    int32_t track_type;
+    const char* codec_id;
+    const unsigned char* codec_private;
+    size_t codec_private_len;
+    const char* name;
+    const char* language;
+    int64_t width;
+    int64_t height;
+    double sample_rate;
+    int64_t channels;
+    int64_t bit_depth;
+    uint64_t codec_delay;
+    uint64_t seek_pre_roll;
+  };
+
+  struct DemuxFrameInfo {
+    uint64_t track;
+    int64_t timestamp_ns;
+    bool keyframe;
+    size_t length;
+    int64_t cluster_offset;
+  };
+
+  DemuxContextPtr demux_open(FfiMkvReader::ReadFun read,
+                             FfiMkvReader::LengthFun length,
+                             void* user_data) {
+    if(read == nullptr || length == nullptr) {
+      return nullptr;
+    }
+
+    DemuxContext* ctx = new DemuxContext;
+    ctx->reader.read_ = read;
+    ctx->reader.length_ = length;
+    ctx->reader.user_data = user_data;
+
+    long long pos = 0;
+    mkvparser::EBMLHeader header;
+    if(header.Parse(&ctx->reader, pos) < 0) {
+      delete ctx;
+      return nullptr;
+    }
+
+    if(mkvparser::Segment::CreateInstance(&ctx->reader, pos, ctx->segment) != 0 ||
+       ctx->segment == nullptr) {
+      delete ctx;
+      return nullptr;
+    }
+
+    if(ctx->segment->ParseHeaders() < 0 || ctx->segment->GetTracks() == nullptr) {
+      delete ctx;
+      return nullptr;
+    }
+
+    // Load as many clusters as the source holds. A recording that was cut
+    // short still yields every cluster before the damaged one.
+    for(;;) {
+      const long status = ctx->segment->LoadCluster();
+      if(status < 0) { ctx->truncated = true; break; }
+      if(status >= 1) { break; }
+    }
+
+    return ctx;
+  }
+
+  void demux_close(DemuxContextPtr ctx) {
+    delete ctx;
+  }
+
+  bool demux_is_truncated(DemuxContextPtr ctx) {
+    return ctx->truncated;
+  }
+
+  int64_t demux_duration_ns(DemuxContextPtr ctx) {
+    return ctx->segment->GetDuration();
+  }
+
+  uint32_t demux_track_count(DemuxContextPtr ctx) {
+    return ctx->segment->GetTracks()->GetTracksCount();
+  }
+
+  bool demux_track_info(DemuxContextPtr ctx, uint32_t index, DemuxTrackInfo* info) {
+    const mkvparser::Track* track = ctx->segment->GetTracks()->GetTrackByIndex(index);
+    if(track == nullptr || info == nullptr) { return false; }
+
+    info->number = track->GetNumber();
+    info->track_type = track->GetType();
+    info->codec_id = track->GetCodecId();
+    info->codec_private = track->GetCodecPrivate(info->codec_private_len);
+    info->name = track->GetNameAsUTF8();
+    info->language = track->GetLanguage();
+    info->codec_delay = track->GetCodecDelay();
+    info->seek_pre_roll = track->GetSeekPreRoll();
+    info->width = 0;
+    info->height = 0;
+    info->sample_rate = 0.0;
+    info->channels = 0;
+    info->bit_depth = 0;
+
+    switch(track->GetType()) {
+    case mkvparser::Track::kVideo: {
+      auto video = static_cast<const mkvparser::VideoTrack*>(track);
+      info->width = video->GetWidth();
+      info->height = video->GetHeight();
+      break;
+    }
+    case mkvparser::Track::kAudio: {
+      auto audio = static_cast<const mkvparser::AudioTrack*>(track);
+      info->sample_rate = audio->GetSamplingRate();
+      info->channels = audio->GetChannels();
+      info->bit_depth = audio->GetBitDepth();
+      break;
+    }
+    default: break;
+    }
+
+    return true;
+  }
+
+  // Advances to the next frame. Returns 1 when `info` holds a frame and 0 at
+  // the end of the stream. A cluster or block the parser can not read also
+  // ends the stream, and sets the flag `demux_is_truncated` reports.
+  int demux_next_frame(DemuxContextPtr ctx, DemuxFrameInfo* info) {
+    mkvparser::Segment* segment = ctx->segment;
+
+    ctx->frame = nullptr;
+
+    for(;;) {
+      if(ctx->entry != nullptr) {
+        const mkvparser::Block* block = ctx->entry->GetBlock();
+        if(ctx->frame_index < block->GetFrameCount()) {
+          const mkvparser::Block::Frame& frame = block->GetFrame(ctx->frame_index);
+          info->track = block->GetTrackNumber();
+          info->timestamp_ns = block->GetTime(ctx->cluster);
+          info->keyframe = block->IsKey();
+          info->length = static_cast<size_t>(frame.len);
+          info->cluster_offset = ctx->cluster->m_element_start;
+          ctx->frame = &frame;
+          ctx->frame_index++;
+          return 1;
+        }
+
+        const mkvparser::BlockEntry* next = nullptr;
+        if(ctx->cluster->GetNext(ctx->entry, next) < 0) {
+          ctx->truncated = true;
+          next = nullptr;
+        }
+        ctx->entry = (next != nullptr && !next->EOS()) ? next : nullptr;
+        ctx->frame_index = 0;
+        if(ctx->entry != nullptr) { continue; }
+      }
+
+      ctx->cluster = ctx->cluster == nullptr ? segment->GetFirst()
+                                             : segment->GetNext(ctx->cluster);
+      if(ctx->cluster == nullptr || ctx->cluster->EOS()) {
+        ctx->cluster = nullptr;
+        return 0;
+      }
+
+      const mkvparser::BlockEntry* first = nullptr;
+      if(ctx->cluster->GetFirst(first) < 0) {
+        ctx->truncated = true;
+        first = nullptr;
+      }
+      ctx->entry = (first != nullptr && !first->EOS()) ? first : nullptr;
+      ctx->frame_index = 0;
+    }
+  }
+
+  // Copies the payload of the frame last returned by `demux_next_frame` into
+  // `buf`, which must hold at least `DemuxFrameInfo::length` bytes.
+  bool demux_read_frame(DemuxContextPtr ctx, unsigned char* buf) {
+    if(ctx->frame == nullptr) { return false; }
+
+    return ctx->frame->Read(&ctx->reader, buf) == 0;
+  }
+
+  // Rewrites a finalized file mode segment into `writer` with the Cues ahead
+  // of the clusters, reading the first pass back through the callbacks.
+  bool mux_segment_copy_and_move_cues(mkvmuxer::Segment* segment,
+                                      FfiMkvReader::ReadFun read,
+                                      FfiMkvReader::LengthFun length,
+                                      void* user_data,
+                                      MkvWriterPtr writer) {
+    if(segment == nullptr || read == nullptr || length == nullptr || writer == nullptr) {
+      return false;
+    }
+
+    FfiMkvReader reader;
+    reader.read_ = read;
+    reader.length_ = length;
+    reader.user_data = user_data;
+    return segment->CopyAndMoveCuesBeforeClusters(&reader, writer);
+  }
 }
@@ -19,12 +19,66 @@ pub mod mux {
     // video
     pub const VP8_CODEC_ID: u32 = 0;
     pub const VP9_CODEC_ID: u32 = 1;
+    pub const AV1_CODEC_ID: u32 = 2;
+
+    // text
+    pub const TEXT_KIND_SUBTITLES: u32 = 0;
+    pub const TEXT_KIND_CAPTIONS: u32 = 1;
+    pub const TEXT_KIND_DESCRIPTIONS: u32 = 2;
+    pub const TEXT_KIND_METADATA: u32 = 3;
 
     pub const SEGMENT_MODE_LIVE: u32 = 0x1;
     pub const SEGMENT_MODE_FILE: u32 = 0x2;   
     
     pub const ELEMENT_ID_MKVCLUSTE: u32 = 0x1F43B675;
 
+    /// Marks an integer `Colour` field as absent.
+    pub const COLOUR_VALUE_NOT_PRESENT: u64 = u64::MAX;
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug)]
+    pub struct Colour {
+        pub matrix_coefficients: u64,
+        pub bits_per_channel: u64,
+        pub chroma_subsampling_horz: u64,
+        pub chroma_subsampling_vert: u64,
+        pub cb_subsampling_horz: u64,
+        pub cb_subsampling_vert: u64,
+        pub chroma_siting_horz: u64,
+        pub chroma_siting_vert: u64,
+        pub range: u64,
+        pub transfer_characteristics: u64,
+        pub primaries: u64,
+        pub max_cll: u64,
+        pub max_fall: u64,
+        pub has_mastering_metadata: bool,
+        /// x/y of red, green, blue and the white point; a negative x marks
+        /// one as absent.
+        pub chromaticities: [f32; 8],
+        /// `f32::MAX` when absent.
+        pub luminance_max: f32,
+        /// `f32::MAX` when absent.
+        pub luminance_min: f32,
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug)]
+    pub struct Frame {
+        pub data: *const u8,
+        pub length: usize,
+        pub additional: *const u8,
+        pub additional_length: usize,
+        pub add_id: u64,
+        pub timestamp_ns: u64,
+        pub keyframe: bool,
+        pub has_duration: bool,
+        pub duration_ns: u64,
+        pub discard_padding_ns: i64,
+        pub has_reference: bool,
+        pub reference_timestamp_ns: i64,
+        pub lacing: u8,
+    }
+
     pub type Segment = c_void;
     pub type SegmentMutPtr = *mut Segment;
 
@@ -59,12 +113,67 @@ pub mod mux {
         pub fn initialize_segment(segment: SegmentMutPtr, writer: WriterMutPtr) -> bool;
         #[link_name = "mux_segment_set_mode"]
         pub fn segment_set_mode(segment: SegmentMutPtr, mode: u32);
+        #[link_name = "mux_segment_set_cues_track"]
+        pub fn segment_set_cues_track(segment: SegmentMutPtr, track: TrackMutPtr) -> bool;
+        #[link_name = "mux_segment_output_cues"]
+        pub fn segment_output_cues(segment: SegmentMutPtr, output_cues: bool);
+        #[link_name = "mux_segment_copy_and_move_cues"]
+        pub fn segment_copy_and_move_cues(segment: SegmentMutPtr,
+                                          read: Option<::demux::ReaderReadFn>,
+                                          length: Option<::demux::ReaderLengthFn>,
+                                          user_data: *mut c_void,
+                                          writer: WriterMutPtr) -> bool;
+        #[link_name = "mux_segment_set_max_cluster_duration"]
+        pub fn segment_set_max_cluster_duration(segment: SegmentMutPtr, duration_ns: u64);
+        #[link_name = "mux_segment_set_max_cluster_size"]
+        pub fn segment_set_max_cluster_size(segment: SegmentMutPtr, size: u64);
+        #[link_name = "mux_segment_force_new_cluster"]
+        pub fn segment_force_new_cluster(segment: SegmentMutPtr);
+        #[link_name = "mux_segment_add_tag"]
+        pub fn segment_add_tag(segment: SegmentMutPtr, track: TrackMutPtr,
+                               names: *const *const c_char, values: *const *const c_char,
+                               count: usize) -> bool;
+        #[link_name = "mux_segment_add_chapter"]
+        pub fn segment_add_chapter(segment: SegmentMutPtr, start_ns: u64, end_ns: u64,
+                                   id: *const c_char, titles: *const *const c_char,
+                                   languages: *const *const c_char, title_count: usize) -> bool;
+        #[link_name = "mux_track_number"]
+        pub fn track_number(track: TrackMutPtr) -> u64;
+        #[link_name = "mux_segment_get_track"]
+        pub fn segment_get_track(segment: SegmentMutPtr, number: u64) -> TrackMutPtr;
+        #[link_name = "mux_track_set_codec_private"]
+        pub fn track_set_codec_private(track: TrackMutPtr, data: *const u8, length: u64) -> bool;
+        #[link_name = "mux_track_set_codec_delay"]
+        pub fn track_set_codec_delay(track: TrackMutPtr, delay_ns: u64);
+        #[link_name = "mux_track_set_seek_pre_roll"]
+        pub fn track_set_seek_pre_roll(track: TrackMutPtr, pre_roll_ns: u64);
+        #[link_name = "mux_video_track_set_display_size"]
+        pub fn video_track_set_display_size(track: VideoTrackMutPtr, width: u64, height: u64);
+        #[link_name = "mux_video_track_set_crop"]
+        pub fn video_track_set_crop(track: VideoTrackMutPtr, left: u64, right: u64,
+                                    top: u64, bottom: u64);
+        #[link_name = "mux_video_track_set_stereo_mode"]
+        pub fn video_track_set_stereo_mode(track: VideoTrackMutPtr, stereo_mode: u64) -> bool;
+        #[link_name = "mux_video_track_set_alpha_mode"]
+        pub fn video_track_set_alpha_mode(track: VideoTrackMutPtr, alpha_mode: u64) -> bool;
+        #[link_name = "mux_video_track_set_frame_rate"]
+        pub fn video_track_set_frame_rate(track: VideoTrackMutPtr, frame_rate: f64);
+        #[link_name = "mux_video_track_set_projection"]
+        pub fn video_track_set_projection(track: VideoTrackMutPtr, projection_type: c_int,
+                                          private_data: *const u8, private_length: u64,
+                                          yaw: f32, pitch: f32, roll: f32) -> bool;
+        #[link_name = "mux_set_colour"]
+        pub fn video_track_set_colour(track: VideoTrackMutPtr, colour: *const Colour) -> bool;
         pub fn mux_set_color(segment: VideoTrackMutPtr, bits: c_int, sampling_horiz: c_int, sampling_vert: c_int, full_range: c_int) -> c_int;
         pub fn mux_set_writing_app(segment: SegmentMutPtr, name: *const c_char);
         #[link_name = "mux_finalize_segment"]
         pub fn finalize_segment(segment: SegmentMutPtr, duration: u64) -> bool;
         #[link_name = "mux_delete_segment"]
         pub fn delete_segment(segment: SegmentMutPtr);
+        #[link_name = "mux_segment_last_timestamp"]
+        pub fn segment_last_timestamp(segment: SegmentMutPtr) -> u64;
+        #[link_name = "mux_segment_invalid_codec_id"]
+        pub fn segment_invalid_codec_id(segment: SegmentMutPtr) -> *const c_char;
 
         #[link_name = "mux_video_track_base_mut"]
         pub fn video_track_base_mut(track: VideoTrackMutPtr) -> TrackMutPtr;
@@ -79,11 +188,96 @@ pub mod mux {
         pub fn segment_add_audio_track(segment: SegmentMutPtr,
                                        sample_rate: i32, channels: i32,
                                        number: i32, codec_id: u32) -> AudioTrackMutPtr;
+        #[link_name = "mux_segment_add_text_track"]
+        pub fn segment_add_text_track(segment: SegmentMutPtr, kind: u32,
+                                      language: *const c_char) -> TrackMutPtr;
+        #[link_name = "mux_segment_add_metadata"]
+        pub fn segment_add_metadata(segment: SegmentMutPtr,
+                                    track: TrackMutPtr,
+                                    data: *const u8, length: usize,
+                                    timestamp_ns: u64, duration_ns: u64) -> bool;
         #[link_name = "mux_segment_add_frame"]
         pub fn segment_add_frame(segment: SegmentMutPtr,
                                  track: TrackMutPtr,
                                  frame: *const u8, length: usize,
                                  timestamp_ns: u64, keyframe: bool) -> bool;
+        #[link_name = "mux_segment_add_generic_frame"]
+        pub fn segment_add_generic_frame(segment: SegmentMutPtr,
+                                         track: TrackMutPtr,
+                                         frame: *const Frame) -> bool;
+        #[link_name = "mux_segment_add_frame_with_additional"]
+        pub fn segment_add_frame_with_additional(segment: SegmentMutPtr,
+                                                 track: TrackMutPtr,
+                                                 frame: *const u8, length: usize,
+                                                 additional: *const u8, additional_length: usize,
+                                                 add_id: u64,
+                                                 timestamp_ns: u64, keyframe: bool) -> bool;
+    }
+}
+
+pub mod demux {
+    use std::os::raw::{c_void, c_char, c_int};
+
+    pub type ReaderReadFn = extern "C" fn(*mut c_void, i64, i64, *mut u8) -> c_int;
+    pub type ReaderLengthFn = extern "C" fn(*mut c_void, *mut i64, *mut i64) -> c_int;
+
+    pub const TRACK_TYPE_VIDEO: i32 = 1;
+    pub const TRACK_TYPE_AUDIO: i32 = 2;
+    pub const TRACK_TYPE_SUBTITLE: i32 = 0x11;
+    pub const TRACK_TYPE_METADATA: i32 = 0x21;
+
+    pub type Context = c_void;
+    pub type ContextMutPtr = *mut Context;
+
+    #[repr(C)]
+    pub struct TrackInfo {
+        pub number: u64,
+        pub track_type: i32,
+        pub codec_id: *const c_char,
+        pub codec_private: *const u8,
+        pub codec_private_len: usize,
+        pub name: *const c_char,
+        pub language: *const c_char,
+        pub width: i64,
+        pub height: i64,
+        pub sample_rate: f64,
+        pub channels: i64,
+        pub bit_depth: i64,
+        pub codec_delay: u64,
+        pub seek_pre_roll: u64,
+    }
+
+    #[repr(C)]
+    pub struct FrameInfo {
+        pub track: u64,
+        pub timestamp_ns: i64,
+        pub keyframe: bool,
+        pub length: usize,
+        pub cluster_offset: i64,
+    }
+
+    #[link(name = "webmadapter", kind = "static")]
+    extern "C" {
+        #[link_name = "demux_open"]
+        pub fn open(read: Option<ReaderReadFn>,
+                    length: Option<ReaderLengthFn>,
+                    user_data: *mut c_void) -> ContextMutPtr;
+        #[link_name = "demux_close"]
+        pub fn close(ctx: ContextMutPtr);
+        #[link_name = "demux_is_truncated"]
+        pub fn is_truncated(ctx: ContextMutPtr) -> bool;
+        #[link_name = "demux_duration_ns"]
+        pub fn duration_ns(ctx: ContextMutPtr) -> i64;
+
+        #[link_name = "demux_track_count"]
+        pub fn track_count(ctx: ContextMutPtr) -> u32;
+        #[link_name = "demux_track_info"]
+        pub fn track_info(ctx: ContextMutPtr, index: u32, info: *mut TrackInfo) -> bool;
+
+        #[link_name = "demux_next_frame"]
+        pub fn next_frame(ctx: ContextMutPtr, info: *mut FrameInfo) -> c_int;
+        #[link_name = "demux_read_frame"]
+        pub fn read_frame(ctx: ContextMutPtr, buf: *mut u8) -> bool;
     }
 }
 
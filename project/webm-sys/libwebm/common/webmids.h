@@ -183,6 +183,8 @@ enum MkvId {
   // Tags
   kMkvTags = 0x1254C367,
   kMkvTag = 0x7373,
+  kMkvTargets = 0x63C0,
+  kMkvTagTrackUID = 0x63C5,
   kMkvSimpleTag = 0x67C8,
   kMkvTagName = 0x45A3,
   kMkvTagString = 0x4487
@@ -182,7 +182,8 @@ Frame::Frame()
       timestamp_(0),
       discard_padding_(0),
       reference_block_timestamp_(0),
-      reference_block_timestamp_set_(false) {}
+      reference_block_timestamp_set_(false),
+      lacing_(0) {}
 
 Frame::~Frame() {
   delete[] frame_;
@@ -214,6 +215,7 @@ bool Frame::CopyFrom(const Frame& frame) {
   discard_padding_ = frame.discard_padding();
   reference_block_timestamp_ = frame.reference_block_timestamp();
   reference_block_timestamp_set_ = frame.reference_block_timestamp_set();
+  lacing_ = frame.lacing();
   return true;
 }
 
@@ -1888,12 +1890,11 @@ bool Tracks::Write(IMkvWriter* writer) const {
 
 bool Chapter::set_id(const char* id) { return StrCpy(id, &id_); }
 
-void Chapter::set_time(const Segment& segment, uint64_t start_ns,
+void Chapter::set_time(const Segment& /* segment */, uint64_t start_ns,
                        uint64_t end_ns) {
-  const SegmentInfo* const info = segment.GetSegmentInfo();
-  const uint64_t timecode_scale = info->timecode_scale();
-  start_timecode_ = start_ns / timecode_scale;
-  end_timecode_ = end_ns / timecode_scale;
+  // ChapterTimeStart and ChapterTimeEnd are not scaled by TimecodeScale.
+  start_timecode_ = start_ns;
+  end_timecode_ = end_ns;
 }
 
 bool Chapter::add_string(const char* title, const char* language,
@@ -2241,6 +2242,7 @@ Tag::Tag() {
   simple_tags_ = NULL;
   simple_tags_size_ = 0;
   simple_tags_count_ = 0;
+  track_uid_ = 0;
 }
 
 Tag::~Tag() {}
@@ -2249,6 +2251,7 @@ void Tag::ShallowCopy(Tag* dst) const {
   dst->simple_tags_ = simple_tags_;
   dst->simple_tags_size_ = simple_tags_size_;
   dst->simple_tags_count_ = simple_tags_count_;
+  dst->track_uid_ = track_uid_;
 }
 
 void Tag::Clear() {
@@ -2288,6 +2291,15 @@ bool Tag::ExpandSimpleTagsArray() {
 uint64_t Tag::Write(IMkvWriter* writer) const {
   uint64_t payload_size = 0;
 
+  uint64_t targets_size = 0;
+  if (track_uid_ != 0) {
+    targets_size = EbmlElementSize(libwebm::kMkvTagTrackUID,
+                                   static_cast<uint64>(track_uid_));
+    payload_size +=
+        EbmlMasterElementSize(libwebm::kMkvTargets, targets_size) +
+        targets_size;
+  }
+
   for (int idx = 0; idx < simple_tags_count_; ++idx) {
     const SimpleTag& st = simple_tags_[idx];
     payload_size += st.Write(NULL);
@@ -2304,6 +2316,13 @@ uint64_t Tag::Write(IMkvWriter* writer) const {
   if (!WriteEbmlMasterElement(writer, libwebm::kMkvTag, payload_size))
     return 0;
 
+  if (track_uid_ != 0) {
+    if (!WriteEbmlMasterElement(writer, libwebm::kMkvTargets, targets_size) ||
+        !WriteEbmlElement(writer, libwebm::kMkvTagTrackUID,
+                          static_cast<uint64>(track_uid_)))
+      return 0;
+  }
+
   for (int idx = 0; idx < simple_tags_count_; ++idx) {
     const SimpleTag& st = simple_tags_[idx];
 
@@ -132,6 +132,10 @@ class Frame {
   bool reference_block_timestamp_set() const {
     return reference_block_timestamp_set_;
   }
+  // Lacing of the data, as in the lacing bits of the block flags: 0 for
+  // none, 1 Xiph, 2 fixed-size, 3 EBML. The data must already be laced.
+  void set_lacing(uint8_t lacing) { lacing_ = lacing & 3; }
+  uint8_t lacing() const { return lacing_; }
 
  private:
   // Id of the Additional data.
@@ -175,6 +179,9 @@ class Frame {
   // Flag indicating if |reference_block_timestamp_| has been set.
   bool reference_block_timestamp_set_;
 
+  // Lacing written into the block flags.
+  uint8_t lacing_;
+
   LIBWEBM_DISALLOW_COPY_AND_ASSIGN(Frame);
 };
 
@@ -933,8 +940,8 @@ class Chapter {
   // MKV is pending.
   bool set_id(const char* id);
 
-  // Converts the nanosecond start and stop times of this chapter to
-  // their corresponding timecode values, and stores them that way.
+  // Sets the start and stop times of this chapter, in nanoseconds as
+  // Matroska stores them. |segment| is no longer used.
   void set_time(const Segment& segment, uint64_t start_time_ns,
                 uint64_t end_time_ns);
 
@@ -1026,10 +1033,10 @@ class Chapter {
   // identifier).
   char* id_;
 
-  // Start timecode of the chapter.
+  // Start time of the chapter, in nanoseconds.
   uint64_t start_timecode_;
 
-  // Stop timecode of the chapter.
+  // Stop time of the chapter, in nanoseconds.
   uint64_t end_timecode_;
 
   // The binary identifier for this chapter.
@@ -1094,6 +1101,11 @@ class Tag {
  public:
   bool add_simple_tag(const char* tag_name, const char* tag_string);
 
+  // Scopes the tag to the track with |uid| (0, the default, means the
+  // whole segment).
+  void set_track_uid(uint64_t uid) { track_uid_ = uid; }
+  uint64_t track_uid() const { return track_uid_; }
+
  private:
   // Tags calls Clear and the destructor of Tag
   friend class Tags;
@@ -1157,6 +1169,9 @@ class Tag {
   // array.
   int simple_tags_count_;
 
+  // UID of the track this tag applies to, or 0 for the whole segment.
+  uint64_t track_uid_;
+
   LIBWEBM_DISALLOW_COPY_AND_ASSIGN(Tag);
 };
 
@@ -1699,6 +1714,8 @@ class Segment {
   const SegmentInfo* segment_info() const { return &segment_info_; }
   void set_duration(double duration) { duration_ = duration; }
   double duration() const { return duration_; }
+  const Tracks* tracks() const { return &tracks_; }
+  uint64_t last_timestamp() const { return last_timestamp_; }
 
   // Returns true when codec IDs are valid for WebM.
   bool DocTypeIsWebm() const;
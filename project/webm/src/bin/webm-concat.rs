@@ -0,0 +1,41 @@
+//! Joins the chunk files of a `WebmWriter` recording into one seekable
+//! WebM:
+//!
+//!     webm-concat <dir> <base_name> <output.webm>
+
+extern crate webm;
+
+use std::env;
+use std::process;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 4 {
+        eprintln!("usage: {} <dir> <base_name> <output.webm>", args[0]);
+        process::exit(2);
+    }
+
+    let paths = webm::concat::chunk_paths(&args[1], &args[2]);
+    if paths.is_empty() {
+        eprintln!("no chunks named {}_*.webm in {}", args[2], args[1]);
+        process::exit(1);
+    }
+
+    match webm::concat::concat_files(&paths, &args[3]) {
+        Ok(report) => {
+            println!("joined {} chunks, {} frames, up to {:.3} s", report.chunks, report.frames,
+                     report.last_timestamp_ns.unwrap_or(0) as f64 / 1e9);
+            for track in report.skipped_tracks {
+                println!("left out track {}, which can not be muxed again", track);
+            }
+            if report.truncated_streams > 0 {
+                println!("{} of {} streams were cut off; frames after the damage are lost",
+                         report.truncated_streams, report.streams);
+            }
+        }
+        Err(e) => {
+            eprintln!("{}: {}", args[3], e);
+            process::exit(1);
+        }
+    }
+}
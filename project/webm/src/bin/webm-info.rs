@@ -0,0 +1,408 @@
+//! Shows what a WebM file or chunk holds: the element tree with offsets and
+//! sizes, the tracks, the clusters and where the keyframes are.
+//!
+//!     webm-info [--json] [--blocks] <file.webm>
+//!
+//! Blocks are left out of the element tree unless `--blocks` is given; the
+//! cluster list counts them either way.
+
+extern crate webm;
+
+use std::env;
+use std::fmt::Write as FmtWrite;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::process;
+use webm::ebml::{self, Element, ElementType, Reader, Value};
+
+struct Node {
+    element: Element,
+    value: Option<Value>,
+    children: Vec<Node>,
+    /// Blocks not shown in the tree.
+    omitted_blocks: usize,
+}
+
+#[derive(Default)]
+struct TrackSummary {
+    number: u64,
+    track_type: u64,
+    codec_id: String,
+    width: Option<u64>,
+    height: Option<u64>,
+    sample_rate: Option<f64>,
+    channels: Option<u64>,
+}
+
+struct Keyframe {
+    track: u64,
+    timestamp_ns: i64,
+    offset: u64,
+}
+
+struct ClusterSummary {
+    offset: u64,
+    /// Bytes the cluster takes up in the file, header included.
+    size: u64,
+    size_known: bool,
+    timestamp_ns: Option<u64>,
+    blocks: usize,
+    keyframes: Vec<Keyframe>,
+}
+
+struct Inspector<R> {
+    reader: Reader<R>,
+    show_blocks: bool,
+    timecode_scale: u64,
+    tracks: Vec<TrackSummary>,
+    clusters: Vec<ClusterSummary>,
+}
+
+impl<R: io::Read + io::Seek> Inspector<R> {
+    fn walk(&mut self, element: Element, depth: usize) -> io::Result<Node> {
+        if depth > ebml::MAX_DEPTH {
+            return Err(ebml::too_deep());
+        }
+        let mut node = Node { element, value: None, children: Vec::new(), omitted_blocks: 0 };
+        if ebml::element_type(element.id) != ElementType::Master {
+            node.value = self.reader.read_value(&element)?;
+            self.note_value(element.id, node.value.as_ref());
+            return Ok(node);
+        }
+
+        match element.id {
+            ebml::ID_TRACK_ENTRY => self.tracks.push(TrackSummary::default()),
+            ebml::ID_CLUSTER => {
+                let end = match element.end() {
+                    Some(end) => end.min(self.reader.stream_len()),
+                    None => self.reader.unknown_size_end(&element)?,
+                };
+                self.clusters.push(ClusterSummary {
+                    offset: element.offset,
+                    size: end - element.offset,
+                    size_known: element.size.is_some(),
+                    timestamp_ns: None,
+                    blocks: 0,
+                    keyframes: Vec::new(),
+                });
+            }
+            _ => {}
+        }
+
+        for child in self.reader.children(&element)? {
+            let is_block = child.id == ebml::ID_SIMPLE_BLOCK || child.id == ebml::ID_BLOCK_GROUP;
+            if is_block && element.id == ebml::ID_CLUSTER {
+                self.note_block(&child)?;
+                if !self.show_blocks {
+                    node.omitted_blocks += 1;
+                    continue;
+                }
+            }
+            node.children.push(self.walk(child, depth + 1)?);
+        }
+        Ok(node)
+    }
+
+    fn note_value(&mut self, id: u64, value: Option<&Value>) {
+        let uint = match value {
+            Some(&Value::Uint(v)) => Some(v),
+            _ => None,
+        };
+        match (id, value) {
+            (ebml::ID_TIMECODE_SCALE, _) => self.timecode_scale = uint.unwrap_or(1_000_000),
+            (ebml::ID_TIMECODE, _) => {
+                let scale = self.timecode_scale;
+                if let Some(cluster) = self.clusters.last_mut() {
+                    cluster.timestamp_ns = uint.and_then(|t| t.checked_mul(scale));
+                }
+            }
+            (ebml::ID_CODEC_ID, Some(Value::String(codec_id))) => {
+                if let Some(track) = self.tracks.last_mut() {
+                    track.codec_id = codec_id.clone();
+                }
+            }
+            (ebml::ID_SAMPLING_FREQUENCY, Some(&Value::Float(rate))) => {
+                if let Some(track) = self.tracks.last_mut() {
+                    track.sample_rate = Some(rate);
+                }
+            }
+            _ => {
+                let track = match (self.tracks.last_mut(), uint) {
+                    (Some(track), Some(_)) => track,
+                    _ => return,
+                };
+                match id {
+                    ebml::ID_TRACK_NUMBER => track.number = uint.unwrap(),
+                    ebml::ID_TRACK_TYPE => track.track_type = uint.unwrap(),
+                    ebml::ID_PIXEL_WIDTH => track.width = uint,
+                    ebml::ID_PIXEL_HEIGHT => track.height = uint,
+                    ebml::ID_CHANNELS => track.channels = uint,
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn note_block(&mut self, element: &Element) -> io::Result<()> {
+        let header = if element.id == ebml::ID_SIMPLE_BLOCK {
+            ebml::parse_block_header(&self.reader.read_data(element, 12)?, true)
+        } else {
+            let children = self.reader.children(element)?;
+            let referenced = children.iter().any(|c| c.id == ebml::ID_REFERENCE_BLOCK);
+            match children.iter().find(|c| c.id == ebml::ID_BLOCK) {
+                Some(block) => ebml::parse_block_header(&self.reader.read_data(block, 12)?, false)
+                    .map(|h| ebml::BlockHeader { keyframe: !referenced, ..h }),
+                None => None,
+            }
+        };
+
+        let scale = self.timecode_scale as i64;
+        let cluster = match self.clusters.last_mut() {
+            Some(cluster) => cluster,
+            None => return Ok(()),
+        };
+        cluster.blocks += 1;
+        if let Some(header) = header.filter(|h| h.keyframe) {
+            let cluster_time = cluster.timestamp_ns.unwrap_or(0) as i64;
+            let timestamp_ns = i64::from(header.timecode).checked_mul(scale)
+                .and_then(|t| t.checked_add(cluster_time));
+            // A timestamp out of range is left out rather than made up.
+            if let Some(timestamp_ns) = timestamp_ns {
+                cluster.keyframes.push(Keyframe { track: header.track, timestamp_ns, offset: element.offset });
+            }
+        }
+        Ok(())
+    }
+}
+
+fn track_kind(track_type: u64) -> &'static str {
+    match track_type {
+        1 => "video",
+        2 => "audio",
+        0x11 => "subtitle",
+        0x21 => "metadata",
+        _ => "other",
+    }
+}
+
+fn element_label(element: &Element) -> String {
+    match element.name() {
+        Some(name) => name.to_string(),
+        None => format!("0x{:X}", element.id),
+    }
+}
+
+fn seconds(ns: i64) -> String {
+    format!("{:.3} s", ns as f64 / 1e9)
+}
+
+fn print_text(nodes: &[Node], inspector: &Inspector<impl io::Read + io::Seek>) {
+    fn print_node(node: &Node, depth: usize, stream_len: u64) {
+        let element = &node.element;
+        let size = match element.size {
+            Some(size) => size.to_string(),
+            None => "unknown".to_string(),
+        };
+        let cut = if element.end().is_some_and(|end| end > stream_len) { " (cut off)" } else { "" };
+        let value = match node.value {
+            Some(Value::Uint(v)) => format!(" = {}", v),
+            Some(Value::Int(v)) | Some(Value::Date(v)) => format!(" = {}", v),
+            Some(Value::Float(v)) => format!(" = {}", v),
+            Some(Value::String(ref v)) => format!(" = {:?}", v),
+            Some(Value::Binary(len)) => format!(" ({} bytes)", len),
+            None => String::new(),
+        };
+        println!("{:>10} {:>10}  {}{}{}{}", element.offset, size, "  ".repeat(depth),
+                 element_label(element), value, cut);
+        for child in &node.children {
+            print_node(child, depth + 1, stream_len);
+        }
+        if node.omitted_blocks > 0 {
+            println!("{:>21}  {}[{} blocks]", "", "  ".repeat(depth + 1), node.omitted_blocks);
+        }
+    }
+
+    println!("Elements:");
+    println!("{:>10} {:>10}  name", "offset", "size");
+    for node in nodes {
+        print_node(node, 0, inspector.reader.stream_len());
+    }
+
+    println!();
+    println!("Tracks:");
+    for track in &inspector.tracks {
+        let mut details = String::new();
+        if let (Some(w), Some(h)) = (track.width, track.height) {
+            let _ = write!(details, "  {}x{}", w, h);
+        }
+        if let Some(rate) = track.sample_rate {
+            let _ = write!(details, "  {} Hz", rate);
+        }
+        if let Some(channels) = track.channels {
+            let _ = write!(details, "  {} ch", channels);
+        }
+        println!("  {:>3}  {:<8} {}{}", track.number, track_kind(track.track_type), track.codec_id, details);
+    }
+
+    println!();
+    println!("Clusters:");
+    println!("{:>10} {:>10} {:>12} {:>7} {:>10}", "offset", "size", "timestamp", "blocks", "keyframes");
+    for cluster in &inspector.clusters {
+        let size = if cluster.size_known { cluster.size.to_string() } else { format!("~{}", cluster.size) };
+        let timestamp = cluster.timestamp_ns.map_or("-".to_string(), |t| seconds(t as i64));
+        println!("{:>10} {:>10} {:>12} {:>7} {:>10}", cluster.offset, size, timestamp,
+                 cluster.blocks, cluster.keyframes.len());
+    }
+
+    println!();
+    println!("Keyframes:");
+    println!("{:>10} {:>6} {:>12}", "offset", "track", "timestamp");
+    for keyframe in inspector.clusters.iter().flat_map(|c| &c.keyframes) {
+        println!("{:>10} {:>6} {:>12}", keyframe.offset, keyframe.track, seconds(keyframe.timestamp_ns));
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json_option<T: ToString>(value: Option<T>) -> String {
+    value.map_or("null".to_string(), |v| v.to_string())
+}
+
+fn json_float(value: f64) -> String {
+    if value.is_finite() { value.to_string() } else { "null".to_string() }
+}
+
+fn node_json(node: &Node, out: &mut String) {
+    let element = &node.element;
+    let _ = write!(out, "{{\"id\":{},\"name\":{},\"offset\":{},\"header_size\":{},\"size\":{}",
+                   json_string(&format!("0x{:X}", element.id)),
+                   json_option(element.name().map(json_string)),
+                   element.offset, element.header_len, json_option(element.size));
+    match node.value {
+        Some(Value::Uint(v)) => { let _ = write!(out, ",\"value\":{}", v); }
+        Some(Value::Int(v)) | Some(Value::Date(v)) => { let _ = write!(out, ",\"value\":{}", v); }
+        Some(Value::Float(v)) => { let _ = write!(out, ",\"value\":{}", json_float(v)); }
+        Some(Value::String(ref v)) => { let _ = write!(out, ",\"value\":{}", json_string(v)); }
+        Some(Value::Binary(len)) => { let _ = write!(out, ",\"data_size\":{}", len); }
+        None => {}
+    }
+    if ebml::element_type(element.id) == ElementType::Master {
+        out.push_str(",\"children\":[");
+        for (i, child) in node.children.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            node_json(child, out);
+        }
+        out.push(']');
+        if node.omitted_blocks > 0 {
+            let _ = write!(out, ",\"omitted_blocks\":{}", node.omitted_blocks);
+        }
+    }
+    out.push('}');
+}
+
+fn print_json(nodes: &[Node], inspector: &Inspector<impl io::Read + io::Seek>) {
+    let mut out = String::from("{\"elements\":[");
+    for (i, node) in nodes.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        node_json(node, &mut out);
+    }
+
+    out.push_str("],\"tracks\":[");
+    for (i, track) in inspector.tracks.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(out, "{{\"number\":{},\"type\":{},\"codec_id\":{},\"width\":{},\"height\":{},\
+                             \"sample_rate\":{},\"channels\":{}}}",
+                       track.number, json_string(track_kind(track.track_type)), json_string(&track.codec_id),
+                       json_option(track.width), json_option(track.height),
+                       json_option(track.sample_rate.map(json_float)), json_option(track.channels));
+    }
+
+    out.push_str("],\"clusters\":[");
+    for (i, cluster) in inspector.clusters.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(out, "{{\"offset\":{},\"size\":{},\"size_known\":{},\"timestamp_ns\":{},\
+                             \"blocks\":{},\"keyframes\":[",
+                       cluster.offset, cluster.size, cluster.size_known,
+                       json_option(cluster.timestamp_ns), cluster.blocks);
+        for (j, keyframe) in cluster.keyframes.iter().enumerate() {
+            if j > 0 {
+                out.push(',');
+            }
+            let _ = write!(out, "{{\"track\":{},\"timestamp_ns\":{},\"offset\":{}}}",
+                           keyframe.track, keyframe.timestamp_ns, keyframe.offset);
+        }
+        out.push_str("]}");
+    }
+    out.push_str("]}");
+    println!("{}", out);
+}
+
+fn main() {
+    let mut json = false;
+    let mut show_blocks = false;
+    let mut paths = Vec::new();
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--blocks" => show_blocks = true,
+            _ => paths.push(arg),
+        }
+    }
+    let path = match paths.pop() {
+        Some(ref path) if paths.is_empty() && !path.starts_with("--") => path.clone(),
+        _ => {
+            eprintln!("usage: webm-info [--json] [--blocks] <file.webm>");
+            process::exit(2);
+        }
+    };
+
+    let result = File::open(&path).and_then(|file| {
+        let mut inspector = Inspector {
+            reader: Reader::new(BufReader::new(file))?,
+            show_blocks,
+            timecode_scale: 1_000_000,
+            tracks: Vec::new(),
+            clusters: Vec::new(),
+        };
+        let nodes = inspector.reader.elements_from(0)?.into_iter()
+            .map(|element| inspector.walk(element, 0))
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok((nodes, inspector))
+    });
+    match result {
+        Ok((nodes, inspector)) => {
+            if json {
+                print_json(&nodes, &inspector);
+            } else {
+                print_text(&nodes, &inspector);
+            }
+        }
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        }
+    }
+}
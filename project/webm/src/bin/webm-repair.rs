@@ -0,0 +1,36 @@
+//! Recovers a WebM recording that was cut short, e.g. by a crash:
+//!
+//!     webm-repair <damaged.webm> <repaired.webm>
+
+extern crate webm;
+
+use std::env;
+use std::process;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        eprintln!("usage: {} <damaged.webm> <repaired.webm>", args[0]);
+        process::exit(2);
+    }
+
+    match webm::repair::repair_file(&args[1], &args[2]) {
+        Ok(report) => {
+            println!("kept {} frames, up to {:.3} s", report.frames,
+                     report.last_timestamp_ns.unwrap_or(0) as f64 / 1e9);
+            if report.dropped_frames > 0 {
+                println!("dropped {} frames of an incomplete cluster", report.dropped_frames);
+            }
+            if report.truncated {
+                println!("the recording was cut off; frames after the damage are lost");
+            }
+            for track in report.skipped_tracks {
+                println!("left out track {}, which can not be muxed again", track);
+            }
+        }
+        Err(e) => {
+            eprintln!("{}: {}", args[1], e);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,64 @@
+//! Cuts a WebM without re-encoding it, by time or into equal pieces:
+//!
+//!     webm-trim <input.webm> <output.webm> <start_s> [end_s]
+//!     webm-trim --split <pieces> <input.webm> <output_prefix>
+//!
+//! Cuts move back to the video keyframe before them. Split pieces are
+//! written to `<output_prefix>_0.webm`, `<output_prefix>_1.webm` and so on.
+
+extern crate webm;
+
+use std::env;
+use std::process;
+use webm::trim::TrimReport;
+
+fn usage(program: &str) -> ! {
+    eprintln!("usage: {} <input.webm> <output.webm> <start_s> [end_s]", program);
+    eprintln!("       {} --split <pieces> <input.webm> <output_prefix>", program);
+    process::exit(2);
+}
+
+fn seconds(program: &str, arg: &str) -> u64 {
+    match arg.parse::<f64>() {
+        Ok(s) if s >= 0.0 => (s * 1e9).round() as u64,
+        _ => usage(program),
+    }
+}
+
+fn print(path: &str, report: &TrimReport) {
+    let end = report.end_ns.map_or("end".to_string(), |end| format!("{:.3} s", end as f64 / 1e9));
+    println!("{}: {:.3} s to {}, {} frames", path, report.start_ns as f64 / 1e9, end, report.frames);
+    if report.truncated {
+        println!("the input was cut off; frames after the damage are missing");
+    }
+    for track in &report.skipped_tracks {
+        println!("left out track {}, which can not be muxed again", track);
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let (input, result) = match args.len() {
+        5 if args[1] == "--split" => {
+            let pieces = args[2].parse().unwrap_or_else(|_| usage(&args[0]));
+            let outputs: Vec<String> = (0..pieces).map(|i| format!("{}_{}.webm", args[4], i)).collect();
+            (&args[3], webm::trim::split_file(&args[3], &outputs).map(|reports| {
+                for (output, report) in outputs.iter().zip(&reports) {
+                    print(output, report);
+                }
+            }))
+        }
+        4 | 5 => {
+            let start_ns = seconds(&args[0], &args[3]);
+            let end_ns = args.get(4).map(|end| seconds(&args[0], end));
+            (&args[1], webm::trim::trim_file(&args[1], &args[2], start_ns, end_ns)
+                .map(|report| print(&args[2], &report)))
+        }
+        _ => usage(&args[0]),
+    };
+
+    if let Err(e) = result {
+        eprintln!("{}: {}", input, e);
+        process::exit(1);
+    }
+}
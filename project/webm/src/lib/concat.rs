@@ -0,0 +1,294 @@
+//! Joining the chunk files `WebmWriter` writes back into one recording.
+//!
+//! Chunks are remuxed, not re-encoded, into a single file mode segment with
+//! element sizes, Duration and Cues, as if the recording had been muxed to
+//! one seekable file in the first place.
+
+use demux::{Demuxer, TrackInfo};
+use mux::{self, Track};
+use repair::add_track;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use {Error, Result};
+
+const EBML_HEADER_ID: [u8; 4] = [0x1A, 0x45, 0xDF, 0xA3];
+
+/// Gap left where a stream's timestamps start over, one block timecode.
+const RESTART_GAP_NS: u64 = 1_000_000;
+
+/// What `concat` put together.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConcatReport {
+    pub chunks: usize,
+    /// Streams the chunks made up: chunks that start with an EBML header
+    /// begin a stream, the others continue the one before.
+    pub streams: usize,
+    /// Frames copied to the output.
+    pub frames: u64,
+    /// Tracks the muxer can not recreate, such as text tracks, left out
+    /// along with their frames.
+    pub skipped_tracks: Vec<u64>,
+    /// Timestamp of the last frame in the output.
+    pub last_timestamp_ns: Option<u64>,
+    /// Streams that end in a cluster or block the demuxer could not read,
+    /// as left behind by a recorder that crashed. Their frames up to that
+    /// point are in the output; whatever followed is lost.
+    pub truncated_streams: usize,
+}
+
+trait Source: Read + Seek {}
+impl<T: Read + Seek> Source for T {}
+
+/// Chunks read back to back as one stream.
+struct Chain {
+    /// Each part with where it starts in the stream.
+    parts: Vec<(u64, Box<dyn Source>)>,
+    len: u64,
+    pos: u64,
+}
+
+impl Chain {
+    fn new() -> Chain {
+        Chain { parts: Vec::new(), len: 0, pos: 0 }
+    }
+
+    fn push(&mut self, mut part: Box<dyn Source>) -> io::Result<()> {
+        let len = part.seek(SeekFrom::End(0))?;
+        self.parts.push((self.len, part));
+        self.len += len;
+        Ok(())
+    }
+}
+
+impl Read for Chain {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len {
+            return Ok(0);
+        }
+        let pos = self.pos;
+        let (start, part) = match self.parts.iter_mut().rev().find(|(start, _)| *start <= pos) {
+            Some(part) => part,
+            None => return Ok(0),
+        };
+        part.seek(SeekFrom::Start(pos - *start))?;
+        let read = part.read(buf)?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for Chain {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => self.len.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        match pos {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "seek before the start")),
+        }
+    }
+}
+
+/// Muxes `chunks`, in recording order, into `dest` as one finalized file
+/// mode segment.
+///
+/// Takes the chunks of any `ChunkMode`: `Split` chunks and `InitSegment`
+/// media chunks continue the stream of the chunk before, `SelfContained`
+/// chunks each carry their own header. All streams must hold the same
+/// tracks. Timestamps are kept as they are unless a stream starts over,
+/// e.g. because the recorder was restarted; then it is moved to follow on
+/// from the stream before.
+pub fn concat<I, R, W>(chunks: I, dest: W) -> Result<(W, ConcatReport)>
+    where I: IntoIterator<Item = R>, R: Read + Seek + 'static, W: Write + Seek
+{
+    let mut report = ConcatReport::default();
+    let mut streams: Vec<Chain> = Vec::new();
+    for mut chunk in chunks {
+        report.chunks += 1;
+        let mut magic = [0u8; 4];
+        let starts_stream = match chunk.read_exact(&mut magic) {
+            Ok(()) => magic == EBML_HEADER_ID,
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => false,
+            Err(e) => return Err(e.into()),
+        };
+        if starts_stream || streams.is_empty() {
+            streams.push(Chain::new());
+        }
+        streams.last_mut().unwrap().push(Box::new(chunk))?;
+    }
+    report.streams = streams.len();
+
+    let segment = mux::Segment::with_mode(mux::Writer::new(dest), mux::SegmentMode::File)?;
+    let mut tracks: Vec<(u64, Box<dyn Track + '_>)> = Vec::new();
+    let mut track_infos: Option<Vec<TrackInfo>> = None;
+    let mut offset_ns = 0;
+    for stream in streams {
+        let mut demuxer = Demuxer::new(stream)?;
+        match track_infos {
+            Some(ref infos) => {
+                if !same_tracks(infos, demuxer.tracks()) {
+                    return Err(Error::InvalidArgument("chunks hold different tracks"));
+                }
+            }
+            None => {
+                for info in demuxer.tracks() {
+                    match add_track(&segment, info)? {
+                        Some(track) => tracks.push((info.number, track)),
+                        None => report.skipped_tracks.push(info.number),
+                    }
+                }
+                track_infos = Some(demuxer.tracks().to_vec());
+            }
+        }
+
+        let mut first = true;
+        while let Some(frame) = demuxer.next_frame()? {
+            if first {
+                first = false;
+                if let Some(last) = report.last_timestamp_ns {
+                    if frame.timestamp_ns + offset_ns < last {
+                        offset_ns = last + RESTART_GAP_NS - frame.timestamp_ns;
+                    }
+                }
+            }
+            let track = match tracks.iter_mut().find(|(number, _)| *number == frame.track) {
+                Some((_, track)) => track,
+                None => continue,
+            };
+            let timestamp_ns = frame.timestamp_ns + offset_ns;
+            track.add_frame(&frame.data, timestamp_ns, frame.keyframe)?;
+            report.frames += 1;
+            report.last_timestamp_ns = Some(report.last_timestamp_ns.map_or(timestamp_ns, |t| t.max(timestamp_ns)));
+        }
+        if demuxer.is_truncated() {
+            report.truncated_streams += 1;
+        }
+    }
+    if track_infos.is_none() {
+        return Err(Error::InvalidArgument("no chunks"));
+    }
+
+    drop(tracks);
+    let writer = segment.finalize(None)?;
+    Ok((writer.into_inner(), report))
+}
+
+/// Concatenates the chunk files at `paths` into a new file at `output`.
+pub fn concat_files<P: AsRef<Path>, Q: AsRef<Path>>(paths: &[P], output: Q) -> Result<ConcatReport> {
+    let chunks = paths.iter().map(File::open).collect::<io::Result<Vec<_>>>()?;
+    let (_, report) = concat(chunks, File::create(output)?)?;
+    Ok(report)
+}
+
+/// The chunk files `WebmWriter` wrote for `base_name` in `dir`, in order:
+/// `<base>_init.webm` if there is one, then `<base>_0.webm`, `<base>_1.webm`
+/// and so on up to the first number missing.
+pub fn chunk_paths<P: AsRef<Path>>(dir: P, base_name: &str) -> Vec<PathBuf> {
+    let dir = dir.as_ref();
+    let init = dir.join(format!("{}_init.webm", base_name));
+    let mut paths: Vec<PathBuf> = Some(init).filter(|p| p.is_file()).into_iter().collect();
+    paths.extend((0..).map(|i| dir.join(format!("{}_{}.webm", base_name, i)))
+                 .take_while(|p| p.is_file()));
+    paths
+}
+
+/// Whether two streams' tracks can share one output: the same codecs with
+/// the same setup, down to the CodecPrivate. Names and languages may differ.
+fn same_tracks(a: &[TrackInfo], b: &[TrackInfo]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(a, b)| {
+        a.number == b.number && a.track_type == b.track_type && a.codec_id == b.codec_id
+            && a.codec_private == b.codec_private && a.video == b.video && a.audio == b.audio
+    })
+}
+
+#[test]
+fn chunked_recordings() {
+    use add_test_video;
+    use demux::Frame;
+    use std::fs;
+
+    let dir = ::std::env::temp_dir().join(format!("webm-concat-{}", ::std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    for &mode in &[mux::ChunkMode::Split, mux::ChunkMode::SelfContained, mux::ChunkMode::InitSegment] {
+        let base = format!("{:?}", mode);
+        let writer = mux::WebmWriter::with_callback(dir.to_str().unwrap(), &base, mode, |_| {}).unwrap();
+        let segment = mux::Segment::new(writer).unwrap();
+        segment.set_max_cluster_duration(500_000_000);
+        let mut vt = add_test_video(&segment, mux::VideoCodecId::VP9);
+        for i in 0..60u64 {
+            vt.add_frame(&[i as u8; 100], i * 40_000_000, i % 15 == 0).unwrap();
+        }
+        drop(segment.finalize(None).ok().unwrap());
+
+        let paths = chunk_paths(&dir, &base);
+        assert!(paths.len() > 2, "{:?}", paths);
+        let output = dir.join(format!("{}.webm", base));
+        let report = concat_files(&paths, &output).unwrap();
+        assert_eq!(report.chunks, paths.len());
+        assert_eq!(report.frames, 60);
+
+        let mut demuxer = Demuxer::open(&output).unwrap();
+        assert!(demuxer.duration_ns().is_some());
+        let frames: Vec<Frame> = demuxer.frames().collect::<Result<_>>().unwrap();
+        assert_eq!(frames.len(), 60);
+        for (i, frame) in frames.iter().enumerate() {
+            assert_eq!((frame.timestamp_ns, frame.keyframe), (i as u64 * 40_000_000, i % 15 == 0));
+            assert_eq!(frame.data, vec![i as u8; 100]);
+        }
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn restarted_recording() {
+    use std::io::Cursor;
+    use {add_test_video, mux_to_vec};
+
+    fn add_frames(vt: &mut mux::VideoTrack) {
+        for i in 0..10u64 {
+            vt.add_frame(&[1; 10], i * 40_000_000, i == 0).unwrap();
+        }
+    }
+    let record = || mux_to_vec(mux::SegmentMode::Live, |segment| {
+        add_frames(&mut add_test_video(segment, mux::VideoCodecId::VP8));
+    });
+
+    let (joined, report) = concat(vec![Cursor::new(record()), Cursor::new(record())],
+                                  Cursor::new(Vec::new())).unwrap();
+    assert_eq!(report.streams, 2);
+    assert_eq!(report.truncated_streams, 0);
+    assert_eq!(report.last_timestamp_ns, Some(360_000_000 + RESTART_GAP_NS + 360_000_000));
+
+    let mut demuxer = Demuxer::new(Cursor::new(joined.into_inner())).unwrap();
+    let timestamps: Vec<u64> = demuxer.frames().map(|f| f.unwrap().timestamp_ns).collect();
+    assert_eq!(timestamps.len(), 20);
+    assert_eq!(timestamps[10], 360_000_000 + RESTART_GAP_NS);
+    assert!(timestamps.windows(2).all(|w| w[0] < w[1]));
+
+    let other = mux_to_vec(mux::SegmentMode::Live, |segment| {
+        let mut at = segment.add_audio_track(48_000, 1, None, mux::AudioCodecId::Opus).unwrap();
+        at.add_frame(&[1; 10], 0, true).unwrap();
+    });
+    assert!(matches!(concat(vec![Cursor::new(record()), Cursor::new(other)], Cursor::new(Vec::new())),
+                     Err(Error::InvalidArgument(_))));
+    let smaller = mux_to_vec(mux::SegmentMode::Live, |segment| {
+        add_frames(&mut segment.add_video_track(32, 24, None, mux::VideoCodecId::VP8).unwrap());
+    });
+    assert!(matches!(concat(vec![Cursor::new(record()), Cursor::new(smaller)], Cursor::new(Vec::new())),
+                     Err(Error::InvalidArgument(_))));
+
+    // A recording cut off in its last cluster.
+    let mut cut = record();
+    let len = cut.len();
+    cut.truncate(len / 2);
+    let (_, report) = concat(vec![Cursor::new(record()), Cursor::new(cut)], Cursor::new(Vec::new())).unwrap();
+    assert_eq!(report.truncated_streams, 1);
+    assert!(report.frames < 20);
+}
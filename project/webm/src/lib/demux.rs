@@ -0,0 +1,337 @@
+use ebml;
+use ffi;
+use {Error, Result};
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::raw::{c_char, c_int, c_void};
+use std::path::Path;
+use std::slice::from_raw_parts_mut;
+
+trait Source: Read + Seek {}
+impl<T: Read + Seek> Source for T {}
+
+struct Reader {
+    source: Box<dyn Source>,
+    length: i64,
+}
+
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum TrackType {
+    Video,
+    Audio,
+    Subtitle,
+    Metadata,
+    Other(i32),
+}
+impl TrackType {
+    fn from_id(id: i32) -> TrackType {
+        match id {
+            ffi::demux::TRACK_TYPE_VIDEO => TrackType::Video,
+            ffi::demux::TRACK_TYPE_AUDIO => TrackType::Audio,
+            ffi::demux::TRACK_TYPE_SUBTITLE => TrackType::Subtitle,
+            ffi::demux::TRACK_TYPE_METADATA => TrackType::Metadata,
+            other => TrackType::Other(other),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct VideoInfo {
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AudioInfo {
+    pub sample_rate: f64,
+    pub channels: u32,
+    pub bit_depth: Option<u32>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrackInfo {
+    pub number: u64,
+    pub track_type: TrackType,
+    pub codec_id: String,
+    pub codec_private: Vec<u8>,
+    pub name: Option<String>,
+    pub language: Option<String>,
+    /// Zero when the track does not set it.
+    pub codec_delay_ns: u64,
+    pub seek_pre_roll_ns: u64,
+    pub video: Option<VideoInfo>,
+    pub audio: Option<AudioInfo>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub track: u64,
+    pub timestamp_ns: u64,
+    pub keyframe: bool,
+    pub data: Vec<u8>,
+    /// Where the enclosing cluster starts in the stream. Frames with the
+    /// same offset share a cluster.
+    pub cluster_offset: u64,
+}
+
+/// Reads the tracks and frames of a WebM (or Matroska) stream.
+///
+/// A stream that ends in the middle of a cluster, as left behind by a
+/// recording that never finalized, is still readable up to the damaged
+/// cluster; see `is_truncated`.
+pub struct Demuxer {
+    ffi: ffi::demux::ContextMutPtr,
+    reader: Box<Reader>,
+    tracks: Vec<TrackInfo>,
+    /// Whether the stream ends inside an element.
+    cut_off: bool,
+}
+
+impl Demuxer {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Demuxer> {
+        Demuxer::new(File::open(path)?)
+    }
+
+    pub fn new<R: Read + Seek + 'static>(mut source: R) -> Result<Demuxer> {
+        let length = source.seek(SeekFrom::End(0))? as i64;
+        let cut_off = ebml::Reader::new(&mut source)?.ends_inside_element()?;
+        let mut reader = Box::new(Reader {
+            source: Box::new(source),
+            length,
+        });
+
+        let ctx = open_context(&mut reader);
+        if ctx.is_null() {
+            return Err(Error::Parse("not a WebM stream or unreadable headers"));
+        }
+
+        let mut demuxer = Demuxer {
+            ffi: ctx,
+            reader,
+            tracks: Vec::new(),
+            cut_off,
+        };
+        demuxer.tracks = demuxer.read_tracks();
+        Ok(demuxer)
+    }
+
+    fn read_tracks(&self) -> Vec<TrackInfo> {
+        let count = unsafe { ffi::demux::track_count(self.ffi) };
+        (0..count).filter_map(|index| {
+            let mut info: ffi::demux::TrackInfo = unsafe { ::std::mem::zeroed() };
+            if !unsafe { ffi::demux::track_info(self.ffi, index, &mut info) } {
+                return None;
+            }
+
+            let track_type = TrackType::from_id(info.track_type);
+            let codec_private = if info.codec_private.is_null() {
+                Vec::new()
+            } else {
+                unsafe { ::std::slice::from_raw_parts(info.codec_private, info.codec_private_len) }.to_vec()
+            };
+            Some(TrackInfo {
+                number: info.number,
+                track_type,
+                codec_id: c_string(info.codec_id).unwrap_or_default(),
+                codec_private,
+                name: c_string(info.name),
+                language: c_string(info.language),
+                codec_delay_ns: info.codec_delay,
+                seek_pre_roll_ns: info.seek_pre_roll,
+                video: if track_type == TrackType::Video {
+                    Some(VideoInfo {
+                        width: info.width as u32,
+                        height: info.height as u32,
+                    })
+                } else {
+                    None
+                },
+                audio: if track_type == TrackType::Audio {
+                    Some(AudioInfo {
+                        sample_rate: info.sample_rate,
+                        channels: info.channels as u32,
+                        bit_depth: if info.bit_depth > 0 { Some(info.bit_depth as u32) } else { None },
+                    })
+                } else {
+                    None
+                },
+            })
+        }).collect()
+    }
+
+    pub fn tracks(&self) -> &[TrackInfo] {
+        &self.tracks
+    }
+
+    pub fn track(&self, number: u64) -> Option<&TrackInfo> {
+        self.tracks.iter().find(|t| t.number == number)
+    }
+
+    /// The segment duration, if the muxer wrote one (file mode only).
+    pub fn duration_ns(&self) -> Option<u64> {
+        let duration = unsafe { ffi::demux::duration_ns(self.ffi) };
+        if duration > 0 { Some(duration as u64) } else { None }
+    }
+
+    /// True if the stream ends inside an element, or once the parser has
+    /// hit a cluster or block it could not read. Frames up to that point
+    /// are still returned.
+    pub fn is_truncated(&self) -> bool {
+        self.cut_off || unsafe { ffi::demux::is_truncated(self.ffi) }
+    }
+
+    /// Length of the whole stream in bytes.
+    pub fn stream_len(&self) -> u64 {
+        self.reader.length as u64
+    }
+
+    /// Reads raw stream bytes at `pos`, for looking at what the parser
+    /// does not report.
+    pub(crate) fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.reader.source.seek(SeekFrom::Start(pos))?;
+        self.reader.source.read_exact(buf)
+    }
+
+    pub fn next_frame(&mut self) -> Result<Option<Frame>> {
+        let mut info = ffi::demux::FrameInfo {
+            track: 0,
+            timestamp_ns: 0,
+            keyframe: false,
+            length: 0,
+            cluster_offset: 0,
+        };
+        if unsafe { ffi::demux::next_frame(self.ffi, &mut info) } == 0 {
+            return Ok(None);
+        }
+
+        let mut data = vec![0u8; info.length];
+        if !unsafe { ffi::demux::read_frame(self.ffi, data.as_mut_ptr()) } {
+            return Err(Error::Parse("frame payload extends past the end of the stream"));
+        }
+
+        Ok(Some(Frame {
+            track: info.track,
+            timestamp_ns: info.timestamp_ns.max(0) as u64,
+            keyframe: info.keyframe,
+            data,
+            cluster_offset: info.cluster_offset as u64,
+        }))
+    }
+
+    /// Starts over from the first frame, for another pass over the stream.
+    pub fn rewind(&mut self) -> Result<()> {
+        let ctx = open_context(&mut self.reader);
+        if ctx.is_null() {
+            return Err(Error::Parse("not a WebM stream or unreadable headers"));
+        }
+        unsafe {
+            ffi::demux::close(self.ffi);
+        }
+        self.ffi = ctx;
+        Ok(())
+    }
+
+    pub fn frames(&mut self) -> Frames<'_> {
+        Frames(self)
+    }
+}
+
+fn open_context(reader: &mut Reader) -> ffi::demux::ContextMutPtr {
+    extern "C" fn read_fn(src: *mut c_void, pos: i64, len: i64, buf: *mut u8) -> c_int {
+        let reader = unsafe { &mut *(src as *mut Reader) };
+        if pos + len > reader.length {
+            return -1;
+        }
+
+        let buf = unsafe { from_raw_parts_mut(buf, len as usize) };
+        let ok = reader.source.seek(SeekFrom::Start(pos as u64)).is_ok()
+            && reader.source.read_exact(buf).is_ok();
+        if ok { 0 } else { -1 }
+    }
+    extern "C" fn length_fn(src: *mut c_void, total: *mut i64, available: *mut i64) -> c_int {
+        let reader = unsafe { &*(src as *const Reader) };
+        unsafe {
+            *total = reader.length;
+            *available = reader.length;
+        }
+        0
+    }
+
+    unsafe {
+        ffi::demux::open(Some(read_fn),
+                         Some(length_fn),
+                         reader as *mut Reader as *mut c_void)
+    }
+}
+
+impl Drop for Demuxer {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::demux::close(self.ffi);
+        }
+    }
+}
+
+pub struct Frames<'a>(&'a mut Demuxer);
+
+impl<'a> Iterator for Frames<'a> {
+    type Item = Result<Frame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next_frame().transpose()
+    }
+}
+
+fn c_string(ptr: *const c_char) -> Option<String> {
+    if ptr.is_null() {
+        None
+    } else {
+        Some(unsafe { CStr::from_ptr(ptr) }.to_string_lossy().into_owned())
+    }
+}
+
+#[test]
+fn roundtrip_chunks() {
+    use add_test_video;
+    use mux::{self, Track};
+    use std::fs;
+    use std::io::Cursor;
+
+    let dir = ::std::env::temp_dir().join(format!("webm-demux-{}", ::std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    fn on_chunk(_: &str) {}
+    let writer = mux::WebmWriter::new(dir.to_str().unwrap(), "roundtrip", on_chunk).unwrap();
+    let segment = mux::Segment::new(writer).unwrap();
+    let mut vt = add_test_video(&segment, mux::VideoCodecId::VP9);
+    for i in 0..30u64 {
+        vt.add_frame(&[i as u8; 100], i * 33_000_000, i % 10 == 0).unwrap();
+    }
+    assert!(segment.finalize(None).is_ok());
+
+    let mut bytes = Vec::new();
+    let mut index = 0;
+    while let Ok(chunk) = fs::read(dir.join(format!("roundtrip_{}.webm", index))) {
+        bytes.extend(chunk);
+        index += 1;
+    }
+    fs::remove_dir_all(&dir).unwrap();
+
+    let mut demuxer = Demuxer::new(Cursor::new(bytes)).unwrap();
+    assert_eq!(demuxer.tracks().len(), 1);
+    let track = demuxer.tracks()[0].clone();
+    assert_eq!(track.track_type, TrackType::Video);
+    assert_eq!(track.codec_id, "V_VP9");
+    assert_eq!(track.video, Some(VideoInfo { width: 64, height: 48 }));
+
+    let frames: Vec<Frame> = demuxer.frames().collect::<Result<_>>().unwrap();
+    assert_eq!(frames.len(), 30);
+    assert!(!demuxer.is_truncated());
+    for (i, frame) in frames.iter().enumerate() {
+        assert_eq!(frame.track, track.number);
+        assert_eq!(frame.timestamp_ns, i as u64 * 33_000_000);
+        assert_eq!(frame.keyframe, i % 10 == 0);
+        assert_eq!(frame.data, vec![i as u8; 100]);
+    }
+}
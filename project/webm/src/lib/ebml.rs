@@ -0,0 +1,617 @@
+//! The raw element structure of a WebM stream, for tools that need to see
+//! what was actually written rather than what a player makes of it.
+//!
+//! Unlike the demuxer this works on any part of a stream: a media chunk
+//! without a header, a file cut off in the middle of an element.
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// How an element's payload is to be read.
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum ElementType {
+    Master,
+    Uint,
+    Int,
+    Float,
+    /// ASCII.
+    String,
+    Utf8,
+    /// Nanoseconds since 2001-01-01T00:00:00 UTC.
+    Date,
+    Binary,
+}
+
+/// An element ID that may appear in any master element.
+const ANY_PARENT: u64 = 0;
+const ROOT: u64 = 1;
+
+pub const ID_VOID: u64 = 0xEC;
+pub const ID_EBML: u64 = 0x1A45_DFA3;
+pub const ID_EBML_VERSION: u64 = 0x4286;
+pub const ID_EBML_READ_VERSION: u64 = 0x42F7;
+pub const ID_EBML_MAX_ID_LENGTH: u64 = 0x42F2;
+pub const ID_EBML_MAX_SIZE_LENGTH: u64 = 0x42F3;
+pub const ID_DOC_TYPE: u64 = 0x4282;
+pub const ID_DOC_TYPE_VERSION: u64 = 0x4287;
+pub const ID_DOC_TYPE_READ_VERSION: u64 = 0x4285;
+pub const ID_SEGMENT: u64 = 0x1853_8067;
+pub const ID_SEEK_HEAD: u64 = 0x114D_9B74;
+pub const ID_SEEK: u64 = 0x4DBB;
+pub const ID_SEEK_ID: u64 = 0x53AB;
+pub const ID_SEEK_POSITION: u64 = 0x53AC;
+pub const ID_INFO: u64 = 0x1549_A966;
+pub const ID_TIMECODE_SCALE: u64 = 0x2A_D7B1;
+pub const ID_DURATION: u64 = 0x4489;
+pub const ID_MUXING_APP: u64 = 0x4D80;
+pub const ID_WRITING_APP: u64 = 0x5741;
+pub const ID_CLUSTER: u64 = 0x1F43_B675;
+pub const ID_TIMECODE: u64 = 0xE7;
+pub const ID_SIMPLE_BLOCK: u64 = 0xA3;
+pub const ID_BLOCK_GROUP: u64 = 0xA0;
+pub const ID_BLOCK: u64 = 0xA1;
+pub const ID_BLOCK_DURATION: u64 = 0x9B;
+pub const ID_REFERENCE_BLOCK: u64 = 0xFB;
+pub const ID_DISCARD_PADDING: u64 = 0x75A2;
+pub const ID_BLOCK_ADDITIONS: u64 = 0x75A1;
+pub const ID_BLOCK_MORE: u64 = 0xA6;
+pub const ID_BLOCK_ADD_ID: u64 = 0xEE;
+pub const ID_BLOCK_ADDITIONAL: u64 = 0xA5;
+pub const ID_TRACKS: u64 = 0x1654_AE6B;
+pub const ID_TRACK_ENTRY: u64 = 0xAE;
+pub const ID_TRACK_NUMBER: u64 = 0xD7;
+pub const ID_TRACK_UID: u64 = 0x73C5;
+pub const ID_TRACK_TYPE: u64 = 0x83;
+pub const ID_CODEC_ID: u64 = 0x86;
+pub const ID_CODEC_PRIVATE: u64 = 0x63A2;
+pub const ID_CODEC_DELAY: u64 = 0x56AA;
+pub const ID_SEEK_PRE_ROLL: u64 = 0x56BB;
+pub const ID_VIDEO: u64 = 0xE0;
+pub const ID_STEREO_MODE: u64 = 0x53B8;
+pub const ID_ALPHA_MODE: u64 = 0x53C0;
+pub const ID_PIXEL_WIDTH: u64 = 0xB0;
+pub const ID_PIXEL_HEIGHT: u64 = 0xBA;
+pub const ID_PIXEL_CROP_BOTTOM: u64 = 0x54AA;
+pub const ID_PIXEL_CROP_TOP: u64 = 0x54BB;
+pub const ID_PIXEL_CROP_LEFT: u64 = 0x54CC;
+pub const ID_PIXEL_CROP_RIGHT: u64 = 0x54DD;
+pub const ID_DISPLAY_WIDTH: u64 = 0x54B0;
+pub const ID_DISPLAY_HEIGHT: u64 = 0x54BA;
+pub const ID_FRAME_RATE: u64 = 0x23_83E3;
+pub const ID_COLOUR: u64 = 0x55B0;
+pub const ID_MASTERING_METADATA: u64 = 0x55D0;
+pub const ID_LUMINANCE_MAX: u64 = 0x55D9;
+pub const ID_LUMINANCE_MIN: u64 = 0x55DA;
+pub const ID_PROJECTION: u64 = 0x7670;
+pub const ID_PROJECTION_TYPE: u64 = 0x7671;
+pub const ID_PROJECTION_PRIVATE: u64 = 0x7672;
+pub const ID_PROJECTION_POSE_YAW: u64 = 0x7673;
+pub const ID_PROJECTION_POSE_PITCH: u64 = 0x7674;
+pub const ID_PROJECTION_POSE_ROLL: u64 = 0x7675;
+pub const ID_AUDIO: u64 = 0xE1;
+pub const ID_SAMPLING_FREQUENCY: u64 = 0xB5;
+pub const ID_CHANNELS: u64 = 0x9F;
+pub const ID_CUES: u64 = 0x1C53_BB6B;
+pub const ID_CUE_POINT: u64 = 0xBB;
+pub const ID_CUE_TIME: u64 = 0xB3;
+pub const ID_CUE_TRACK_POSITIONS: u64 = 0xB7;
+pub const ID_CUE_TRACK: u64 = 0xF7;
+pub const ID_CUE_CLUSTER_POSITION: u64 = 0xF1;
+pub const ID_CUE_BLOCK_NUMBER: u64 = 0x5378;
+
+/// How many levels of nested master elements a `Reader` follows. WebM
+/// needs well under ten; `SimpleTag`s nest, so a damaged or hostile
+/// stream could otherwise nest them until the stack runs out.
+pub const MAX_DEPTH: usize = 64;
+
+use self::ElementType::*;
+
+/// ID, name, type and parent of the elements WebM uses.
+static SCHEMA: &[(u64, &str, ElementType, u64)] = &[
+    (ID_VOID, "Void", Binary, ANY_PARENT),
+    (0xBF, "CRC-32", Binary, ANY_PARENT),
+
+    (ID_EBML, "EBML", Master, ROOT),
+    (ID_EBML_VERSION, "EBMLVersion", Uint, ID_EBML),
+    (ID_EBML_READ_VERSION, "EBMLReadVersion", Uint, ID_EBML),
+    (ID_EBML_MAX_ID_LENGTH, "EBMLMaxIDLength", Uint, ID_EBML),
+    (ID_EBML_MAX_SIZE_LENGTH, "EBMLMaxSizeLength", Uint, ID_EBML),
+    (ID_DOC_TYPE, "DocType", String, ID_EBML),
+    (ID_DOC_TYPE_VERSION, "DocTypeVersion", Uint, ID_EBML),
+    (ID_DOC_TYPE_READ_VERSION, "DocTypeReadVersion", Uint, ID_EBML),
+
+    (ID_SEGMENT, "Segment", Master, ROOT),
+
+    (ID_SEEK_HEAD, "SeekHead", Master, ID_SEGMENT),
+    (ID_SEEK, "Seek", Master, ID_SEEK_HEAD),
+    (ID_SEEK_ID, "SeekID", Binary, ID_SEEK),
+    (ID_SEEK_POSITION, "SeekPosition", Uint, ID_SEEK),
+
+    (ID_INFO, "Info", Master, ID_SEGMENT),
+    (ID_TIMECODE_SCALE, "TimecodeScale", Uint, ID_INFO),
+    (ID_DURATION, "Duration", Float, ID_INFO),
+    (0x4461, "DateUTC", Date, ID_INFO),
+    (0x7BA9, "Title", Utf8, ID_INFO),
+    (ID_MUXING_APP, "MuxingApp", Utf8, ID_INFO),
+    (ID_WRITING_APP, "WritingApp", Utf8, ID_INFO),
+    (0x73A4, "SegmentUID", Binary, ID_INFO),
+
+    (ID_CLUSTER, "Cluster", Master, ID_SEGMENT),
+    (ID_TIMECODE, "Timecode", Uint, ID_CLUSTER),
+    (0xAB, "PrevSize", Uint, ID_CLUSTER),
+    (0xA7, "Position", Uint, ID_CLUSTER),
+    (ID_SIMPLE_BLOCK, "SimpleBlock", Binary, ID_CLUSTER),
+    (ID_BLOCK_GROUP, "BlockGroup", Master, ID_CLUSTER),
+    (ID_BLOCK, "Block", Binary, ID_BLOCK_GROUP),
+    (ID_BLOCK_DURATION, "BlockDuration", Uint, ID_BLOCK_GROUP),
+    (ID_REFERENCE_BLOCK, "ReferenceBlock", Int, ID_BLOCK_GROUP),
+    (ID_DISCARD_PADDING, "DiscardPadding", Int, ID_BLOCK_GROUP),
+    (ID_BLOCK_ADDITIONS, "BlockAdditions", Master, ID_BLOCK_GROUP),
+    (ID_BLOCK_MORE, "BlockMore", Master, ID_BLOCK_ADDITIONS),
+    (ID_BLOCK_ADD_ID, "BlockAddID", Uint, ID_BLOCK_MORE),
+    (ID_BLOCK_ADDITIONAL, "BlockAdditional", Binary, ID_BLOCK_MORE),
+
+    (ID_TRACKS, "Tracks", Master, ID_SEGMENT),
+    (ID_TRACK_ENTRY, "TrackEntry", Master, ID_TRACKS),
+    (ID_TRACK_NUMBER, "TrackNumber", Uint, ID_TRACK_ENTRY),
+    (ID_TRACK_UID, "TrackUID", Uint, ID_TRACK_ENTRY),
+    (ID_TRACK_TYPE, "TrackType", Uint, ID_TRACK_ENTRY),
+    (0xB9, "FlagEnabled", Uint, ID_TRACK_ENTRY),
+    (0x88, "FlagDefault", Uint, ID_TRACK_ENTRY),
+    (0x55AA, "FlagForced", Uint, ID_TRACK_ENTRY),
+    (0x9C, "FlagLacing", Uint, ID_TRACK_ENTRY),
+    (0x23_E383, "DefaultDuration", Uint, ID_TRACK_ENTRY),
+    (0x55EE, "MaxBlockAdditionID", Uint, ID_TRACK_ENTRY),
+    (0x536E, "Name", Utf8, ID_TRACK_ENTRY),
+    (0x22_B59C, "Language", String, ID_TRACK_ENTRY),
+    (ID_CODEC_ID, "CodecID", String, ID_TRACK_ENTRY),
+    (ID_CODEC_PRIVATE, "CodecPrivate", Binary, ID_TRACK_ENTRY),
+    (0x25_8688, "CodecName", Utf8, ID_TRACK_ENTRY),
+    (ID_CODEC_DELAY, "CodecDelay", Uint, ID_TRACK_ENTRY),
+    (ID_SEEK_PRE_ROLL, "SeekPreRoll", Uint, ID_TRACK_ENTRY),
+
+    (ID_VIDEO, "Video", Master, ID_TRACK_ENTRY),
+    (0x9A, "FlagInterlaced", Uint, ID_VIDEO),
+    (ID_STEREO_MODE, "StereoMode", Uint, ID_VIDEO),
+    (ID_ALPHA_MODE, "AlphaMode", Uint, ID_VIDEO),
+    (ID_PIXEL_WIDTH, "PixelWidth", Uint, ID_VIDEO),
+    (ID_PIXEL_HEIGHT, "PixelHeight", Uint, ID_VIDEO),
+    (ID_PIXEL_CROP_BOTTOM, "PixelCropBottom", Uint, ID_VIDEO),
+    (ID_PIXEL_CROP_TOP, "PixelCropTop", Uint, ID_VIDEO),
+    (ID_PIXEL_CROP_LEFT, "PixelCropLeft", Uint, ID_VIDEO),
+    (ID_PIXEL_CROP_RIGHT, "PixelCropRight", Uint, ID_VIDEO),
+    (ID_DISPLAY_WIDTH, "DisplayWidth", Uint, ID_VIDEO),
+    (ID_DISPLAY_HEIGHT, "DisplayHeight", Uint, ID_VIDEO),
+    (0x54B2, "DisplayUnit", Uint, ID_VIDEO),
+    (ID_FRAME_RATE, "FrameRate", Float, ID_VIDEO),
+
+    (ID_COLOUR, "Colour", Master, ID_VIDEO),
+    (0x55B1, "MatrixCoefficients", Uint, ID_COLOUR),
+    (0x55B2, "BitsPerChannel", Uint, ID_COLOUR),
+    (0x55B3, "ChromaSubsamplingHorz", Uint, ID_COLOUR),
+    (0x55B4, "ChromaSubsamplingVert", Uint, ID_COLOUR),
+    (0x55B5, "CbSubsamplingHorz", Uint, ID_COLOUR),
+    (0x55B6, "CbSubsamplingVert", Uint, ID_COLOUR),
+    (0x55B7, "ChromaSitingHorz", Uint, ID_COLOUR),
+    (0x55B8, "ChromaSitingVert", Uint, ID_COLOUR),
+    (0x55B9, "Range", Uint, ID_COLOUR),
+    (0x55BA, "TransferCharacteristics", Uint, ID_COLOUR),
+    (0x55BB, "Primaries", Uint, ID_COLOUR),
+    (0x55BC, "MaxCLL", Uint, ID_COLOUR),
+    (0x55BD, "MaxFALL", Uint, ID_COLOUR),
+    (ID_MASTERING_METADATA, "MasteringMetadata", Master, ID_COLOUR),
+    (0x55D1, "PrimaryRChromaticityX", Float, ID_MASTERING_METADATA),
+    (0x55D2, "PrimaryRChromaticityY", Float, ID_MASTERING_METADATA),
+    (0x55D3, "PrimaryGChromaticityX", Float, ID_MASTERING_METADATA),
+    (0x55D4, "PrimaryGChromaticityY", Float, ID_MASTERING_METADATA),
+    (0x55D5, "PrimaryBChromaticityX", Float, ID_MASTERING_METADATA),
+    (0x55D6, "PrimaryBChromaticityY", Float, ID_MASTERING_METADATA),
+    (0x55D7, "WhitePointChromaticityX", Float, ID_MASTERING_METADATA),
+    (0x55D8, "WhitePointChromaticityY", Float, ID_MASTERING_METADATA),
+    (ID_LUMINANCE_MAX, "LuminanceMax", Float, ID_MASTERING_METADATA),
+    (ID_LUMINANCE_MIN, "LuminanceMin", Float, ID_MASTERING_METADATA),
+
+    (ID_PROJECTION, "Projection", Master, ID_VIDEO),
+    (ID_PROJECTION_TYPE, "ProjectionType", Uint, ID_PROJECTION),
+    (ID_PROJECTION_PRIVATE, "ProjectionPrivate", Binary, ID_PROJECTION),
+    (ID_PROJECTION_POSE_YAW, "ProjectionPoseYaw", Float, ID_PROJECTION),
+    (ID_PROJECTION_POSE_PITCH, "ProjectionPosePitch", Float, ID_PROJECTION),
+    (ID_PROJECTION_POSE_ROLL, "ProjectionPoseRoll", Float, ID_PROJECTION),
+
+    (ID_AUDIO, "Audio", Master, ID_TRACK_ENTRY),
+    (ID_SAMPLING_FREQUENCY, "SamplingFrequency", Float, ID_AUDIO),
+    (0x78B5, "OutputSamplingFrequency", Float, ID_AUDIO),
+    (ID_CHANNELS, "Channels", Uint, ID_AUDIO),
+    (0x6264, "BitDepth", Uint, ID_AUDIO),
+
+    (ID_CUES, "Cues", Master, ID_SEGMENT),
+    (ID_CUE_POINT, "CuePoint", Master, ID_CUES),
+    (ID_CUE_TIME, "CueTime", Uint, ID_CUE_POINT),
+    (ID_CUE_TRACK_POSITIONS, "CueTrackPositions", Master, ID_CUE_POINT),
+    (ID_CUE_TRACK, "CueTrack", Uint, ID_CUE_TRACK_POSITIONS),
+    (ID_CUE_CLUSTER_POSITION, "CueClusterPosition", Uint, ID_CUE_TRACK_POSITIONS),
+    (0xF0, "CueRelativePosition", Uint, ID_CUE_TRACK_POSITIONS),
+    (0xB2, "CueDuration", Uint, ID_CUE_TRACK_POSITIONS),
+    (ID_CUE_BLOCK_NUMBER, "CueBlockNumber", Uint, ID_CUE_TRACK_POSITIONS),
+
+    (0x1043_A770, "Chapters", Master, ID_SEGMENT),
+    (0x45B9, "EditionEntry", Master, 0x1043_A770),
+    (0xB6, "ChapterAtom", Master, 0x45B9),
+    (0x73C4, "ChapterUID", Uint, 0xB6),
+    (0x5654, "ChapterStringUID", Utf8, 0xB6),
+    (0x91, "ChapterTimeStart", Uint, 0xB6),
+    (0x92, "ChapterTimeEnd", Uint, 0xB6),
+    (0x80, "ChapterDisplay", Master, 0xB6),
+    (0x85, "ChapString", Utf8, 0x80),
+    (0x437C, "ChapLanguage", String, 0x80),
+    (0x437E, "ChapCountry", String, 0x80),
+
+    (0x1254_C367, "Tags", Master, ID_SEGMENT),
+    (0x7373, "Tag", Master, 0x1254_C367),
+    (0x63C0, "Targets", Master, 0x7373),
+    (0x68CA, "TargetTypeValue", Uint, 0x63C0),
+    (0x63CA, "TargetType", String, 0x63C0),
+    (0x63C5, "TagTrackUID", Uint, 0x63C0),
+    (0x67C8, "SimpleTag", Master, 0x7373),
+    (0x45A3, "TagName", Utf8, 0x67C8),
+    (0x447A, "TagLanguage", String, 0x67C8),
+    (0x4484, "TagDefault", Uint, 0x67C8),
+    (0x4487, "TagString", Utf8, 0x67C8),
+    (0x4485, "TagBinary", Binary, 0x67C8),
+];
+
+fn schema(id: u64) -> Option<&'static (u64, &'static str, ElementType, u64)> {
+    SCHEMA.iter().find(|e| e.0 == id)
+}
+
+/// The element's name in the Matroska specification, if WebM uses it.
+pub fn element_name(id: u64) -> Option<&'static str> {
+    schema(id).map(|e| e.1)
+}
+
+/// How the payload of element `id` is read; `Binary` for unknown elements.
+pub fn element_type(id: u64) -> ElementType {
+    schema(id).map_or(Binary, |e| e.2)
+}
+
+/// Whether element `child` may appear directly inside element `parent`.
+/// `SimpleTag`s nest.
+fn is_child(parent: u64, child: u64) -> bool {
+    match schema(child) {
+        Some(&(_, _, _, ANY_PARENT)) => true,
+        Some(&(id, _, _, p)) => p == parent || (id == 0x67C8 && parent == 0x67C8),
+        None => false,
+    }
+}
+
+/// Where an element sits in the stream.
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub struct Element {
+    pub id: u64,
+    /// Position of the element's ID.
+    pub offset: u64,
+    /// Length of the ID and size fields.
+    pub header_len: u64,
+    /// Payload size as written, `None` if the element was left with an
+    /// unknown size.
+    pub size: Option<u64>,
+}
+
+impl Element {
+    pub fn name(&self) -> Option<&'static str> {
+        element_name(self.id)
+    }
+
+    pub fn data_offset(&self) -> u64 {
+        self.offset + self.header_len
+    }
+
+    /// Where the element ends by its size field.
+    pub fn end(&self) -> Option<u64> {
+        self.size.map(|size| self.data_offset() + size)
+    }
+}
+
+/// Decodes the ID and size at the start of `bytes`, returning the ID with
+/// its length marker, the size (`None` if unknown) and the header length.
+/// `None` if `bytes` does not start with a complete, valid header.
+pub fn parse_header(bytes: &[u8]) -> Option<(u64, Option<u64>, usize)> {
+    let id_len = match bytes.first() {
+        Some(&first) if first != 0 && first.leading_zeros() < 4 => first.leading_zeros() as usize + 1,
+        _ => return None,
+    };
+    let id = bytes.get(..id_len)?.iter().fold(0u64, |v, &b| (v << 8) | u64::from(b));
+
+    let size_bytes = &bytes[id_len..];
+    let size_len = match size_bytes.first() {
+        Some(&first) if first != 0 => first.leading_zeros() as usize + 1,
+        _ => return None,
+    };
+    let mask = (1u64 << (7 * size_len)) - 1;
+    let size = size_bytes.get(..size_len)?.iter().fold(0u64, |v, &b| (v << 8) | u64::from(b)) & mask;
+    let size = if size == mask { None } else { Some(size) };
+    Some((id, size, id_len + size_len))
+}
+
+/// An element's payload, decoded by its type.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Uint(u64),
+    Int(i64),
+    Float(f64),
+    String(::std::string::String),
+    Date(i64),
+    /// Only the length of binary payloads is kept.
+    Binary(u64),
+}
+
+/// Decodes `data`, the payload of element `id`. `None` for master
+/// elements and payloads of a length the type does not allow.
+pub fn decode_value(id: u64, data: &[u8]) -> Option<Value> {
+    let uint = || -> Option<u64> {
+        if data.len() > 8 {
+            return None;
+        }
+        Some(data.iter().fold(0u64, |v, &b| (v << 8) | u64::from(b)))
+    };
+    let int = || -> Option<i64> {
+        let value = uint()?;
+        let unused = 64 - 8 * data.len() as u32;
+        Some(if data.is_empty() { 0 } else { ((value << unused) as i64) >> unused })
+    };
+    Some(match element_type(id) {
+        Master => return None,
+        Uint => Value::Uint(uint()?),
+        Int => Value::Int(int()?),
+        Date => Value::Date(int()?),
+        Float => match data.len() {
+            0 => Value::Float(0.0),
+            4 => Value::Float(f64::from(f32::from_bits(uint()? as u32))),
+            8 => Value::Float(f64::from_bits(uint()?)),
+            _ => return None,
+        },
+        String | Utf8 => {
+            let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+            Value::String(::std::string::String::from_utf8_lossy(&data[..end]).into_owned())
+        }
+        Binary => Value::Binary(data.len() as u64),
+    })
+}
+
+/// The header of a `SimpleBlock` or `Block`.
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub struct BlockHeader {
+    pub track: u64,
+    /// Relative to the cluster's timecode.
+    pub timecode: i16,
+    /// Only `SimpleBlock`s carry a keyframe flag; a `Block` is a keyframe
+    /// unless its `BlockGroup` has a `ReferenceBlock`.
+    pub keyframe: bool,
+}
+
+/// Decodes the block header at the start of a `SimpleBlock` (`simple`) or
+/// `Block` payload.
+pub fn parse_block_header(data: &[u8], simple: bool) -> Option<BlockHeader> {
+    let first = *data.first()?;
+    if first == 0 {
+        return None;
+    }
+    let len = first.leading_zeros() as usize + 1;
+    let track = data.get(..len)?.iter().fold(0u64, |v, &b| (v << 8) | u64::from(b)) &
+        ((1u64 << (7 * len)) - 1);
+    let rest = data.get(len..len + 3)?;
+    Some(BlockHeader {
+        track,
+        timecode: i16::from_be_bytes([rest[0], rest[1]]),
+        keyframe: simple && rest[2] & 0x80 != 0,
+    })
+}
+
+/// Reads element headers and payloads from a seekable stream.
+pub struct Reader<R> {
+    source: R,
+    len: u64,
+}
+
+impl<R: Read + Seek> Reader<R> {
+    pub fn new(mut source: R) -> io::Result<Reader<R>> {
+        let len = source.seek(SeekFrom::End(0))?;
+        Ok(Reader { source, len })
+    }
+
+    /// Length of the stream in bytes.
+    pub fn stream_len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn into_inner(self) -> R {
+        self.source
+    }
+
+    /// The element at `pos`, or `None` if the stream ends within its header
+    /// or there is no valid header there.
+    pub fn element_at(&mut self, pos: u64) -> io::Result<Option<Element>> {
+        let mut buf = [0u8; 12];
+        let available = self.len.saturating_sub(pos).min(buf.len() as u64) as usize;
+        let buf = &mut buf[..available];
+        self.read_at(pos, buf)?;
+        Ok(parse_header(buf).map(|(id, size, header_len)| Element {
+            id,
+            offset: pos,
+            header_len: header_len as u64,
+            size,
+        }))
+    }
+
+    /// Reads up to `max` bytes of the element's payload, fewer if the
+    /// stream ends first.
+    pub fn read_data(&mut self, element: &Element, max: usize) -> io::Result<Vec<u8>> {
+        let start = element.data_offset().min(self.len);
+        let end = element.end().unwrap_or(self.len).min(self.len).min(start + max as u64);
+        let mut data = vec![0; (end - start) as usize];
+        self.read_at(start, &mut data)?;
+        Ok(data)
+    }
+
+    /// Decodes the payload of a non-master element.
+    pub fn read_value(&mut self, element: &Element) -> io::Result<Option<Value>> {
+        match element_type(element.id) {
+            Master => Ok(None),
+            Binary => Ok(Some(Value::Binary(self.available_size(element)))),
+            _ => {
+                let data = self.read_data(element, 1 << 16)?;
+                Ok(decode_value(element.id, &data))
+            }
+        }
+    }
+
+    /// The payload size actually in the stream, which for a cut off or
+    /// unknown-size element is less than or unlike its size field.
+    pub fn available_size(&self, element: &Element) -> u64 {
+        let end = element.end().unwrap_or(self.len).min(self.len);
+        end.saturating_sub(element.data_offset())
+    }
+
+    /// The top level elements from `pos` on, as in a stream that starts
+    /// with an EBML header, or the Segment children found in a media chunk.
+    pub fn elements_from(&mut self, mut pos: u64) -> io::Result<Vec<Element>> {
+        let mut elements = Vec::new();
+        while pos < self.len {
+            let element = match self.element_at(pos)? {
+                Some(element) => element,
+                None => break,
+            };
+            elements.push(element);
+            pos = match element.end() {
+                Some(end) => end,
+                None => self.unknown_size_end(&element)?,
+            };
+        }
+        Ok(elements)
+    }
+
+    /// The children of a master element. An unknown-size element ends at
+    /// the first element that can not be its child, or at the end of the
+    /// stream; so does one cut off by the end of the stream.
+    pub fn children(&mut self, parent: &Element) -> io::Result<Vec<Element>> {
+        self.children_at(parent, 0)
+    }
+
+    /// Where an element of unknown size ends: after its last child.
+    pub fn unknown_size_end(&mut self, element: &Element) -> io::Result<u64> {
+        self.unknown_size_end_at(element, 0)
+    }
+
+    /// Whether the stream ends inside an element, as a file does that was
+    /// cut off while it was written. Follows the last element down to its
+    /// last child, where the cut is.
+    pub fn ends_inside_element(&mut self) -> io::Result<bool> {
+        let mut elements = self.elements_from(0)?;
+        while let Some(last) = elements.pop() {
+            if last.end().is_some_and(|end| end > self.len) {
+                return Ok(true);
+            }
+            if element_type(last.id) != Master {
+                break;
+            }
+            elements = self.children(&last)?;
+        }
+        Ok(false)
+    }
+
+    fn children_at(&mut self, parent: &Element, depth: usize) -> io::Result<Vec<Element>> {
+        if depth > MAX_DEPTH {
+            return Err(too_deep());
+        }
+        let end = parent.end().unwrap_or(self.len).min(self.len);
+        let mut children = Vec::new();
+        let mut pos = parent.data_offset();
+        while pos < end {
+            let child = match self.element_at(pos)? {
+                Some(child) => child,
+                None => break,
+            };
+            if parent.size.is_none() && !is_child(parent.id, child.id) {
+                break;
+            }
+            children.push(child);
+            pos = match child.end() {
+                Some(end) => end,
+                None => self.unknown_size_end_at(&child, depth + 1)?,
+            };
+        }
+        Ok(children)
+    }
+
+    fn unknown_size_end_at(&mut self, element: &Element, depth: usize) -> io::Result<u64> {
+        let children = self.children_at(element, depth)?;
+        match children.last() {
+            Some(last) => match last.end() {
+                Some(end) => Ok(end.min(self.len)),
+                None => self.unknown_size_end_at(last, depth + 1),
+            },
+            None => Ok(element.data_offset().min(self.len)),
+        }
+    }
+
+    fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.source.seek(SeekFrom::Start(pos))?;
+        self.source.read_exact(buf)
+    }
+}
+
+/// The error for elements nested deeper than `MAX_DEPTH`.
+pub fn too_deep() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "elements nested too deeply")
+}
+
+#[test]
+fn element_tree() {
+    use mux::{self, Track};
+    use std::io::Cursor;
+    use {add_test_video, mux_to_vec};
+
+    let bytes = mux_to_vec(mux::SegmentMode::Live, |segment| {
+        let mut vt = add_test_video(segment, mux::VideoCodecId::VP8);
+        for i in 0..10u64 {
+            vt.add_frame(&[0; 100], i * 40_000_000, i == 0).unwrap();
+        }
+    });
+    let len = bytes.len() as u64;
+
+    let mut reader = Reader::new(Cursor::new(bytes)).unwrap();
+    let top = reader.elements_from(0).unwrap();
+    assert_eq!(top.iter().map(|e| e.name().unwrap()).collect::<Vec<_>>(), ["EBML", "Segment"]);
+    let children = reader.children(&top[0]).unwrap();
+    let doc_type = children.iter().find(|e| e.name() == Some("DocType")).unwrap();
+    assert_eq!(reader.read_value(doc_type).unwrap(), Some(Value::String("webm".into())));
+
+    // Live mode leaves the segment and cluster sizes unknown.
+    assert_eq!(top[1].size, None);
+    assert_eq!(reader.unknown_size_end(&top[1]).unwrap(), len);
+    let cluster = *reader.children(&top[1]).unwrap().iter().find(|e| e.id == ID_CLUSTER).unwrap();
+    assert_eq!(cluster.size, None);
+    let blocks: Vec<BlockHeader> = reader.children(&cluster).unwrap().iter()
+        .filter(|e| e.id == ID_SIMPLE_BLOCK)
+        .map(|e| parse_block_header(&reader.read_data(e, 4).unwrap(), true).unwrap())
+        .collect();
+    assert_eq!(blocks.len(), 10);
+    assert_eq!(blocks[0], BlockHeader { track: 1, timecode: 0, keyframe: true });
+    assert_eq!(blocks[9], BlockHeader { track: 1, timecode: 360, keyframe: false });
+
+    assert!(!reader.ends_inside_element().unwrap());
+
+    // A cut off cluster yields the blocks that are there.
+    let cut = Cursor::new(reader.into_inner().into_inner()[..len as usize - 50].to_vec());
+    let mut reader = Reader::new(cut).unwrap();
+    let children = reader.children(&cluster).unwrap();
+    assert_eq!(children.iter().filter(|e| e.id == ID_SIMPLE_BLOCK).count(), 10);
+    assert!(children.last().unwrap().end().unwrap() > len - 50);
+    assert!(reader.ends_inside_element().unwrap());
+
+    // SimpleTags of unknown size nested without end.
+    let nested: Vec<u8> = [0x67, 0xC8, 0xFF].iter().cloned().cycle().take(3 * 100_000).collect();
+    let mut reader = Reader::new(Cursor::new(nested)).unwrap();
+    let outer = reader.element_at(0).unwrap().unwrap();
+    assert_eq!(reader.children(&outer).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    assert_eq!(reader.elements_from(0).unwrap_err().kind(), io::ErrorKind::InvalidData);
+}
@@ -0,0 +1,116 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+/// What went wrong while muxing or demuxing.
+#[derive(Debug)]
+pub enum Error {
+    /// The sink or source failed, e.g. because the disk is full. Once a
+    /// writer has failed it keeps reporting the same error.
+    Io(io::Error),
+    /// The track could not be added (its number is taken, say), or the
+    /// handle does not belong to the segment.
+    InvalidTrack,
+    /// A frame came before the last frame written to the segment. Frames of
+    /// all tracks must be added in timestamp order.
+    NonMonotonicTimestamp {
+        timestamp_ns: u64,
+        last_timestamp_ns: u64,
+    },
+    /// The track header could not be written for this codec, e.g. `V_AV1`
+    /// without its CodecPrivate.
+    BadCodecId(String),
+    /// `finalize` failed to write the closing elements.
+    Finalize,
+    /// A value the muxer can not store, such as a string with a NUL byte or
+    /// a colour value out of range.
+    InvalidArgument(&'static str),
+    /// The muxer refused the operation for a reason it does not report.
+    Muxer(&'static str),
+    /// The stream is not WebM, or is damaged beyond what the demuxer skips.
+    Parse(&'static str),
+}
+
+pub type Result<T, E = Error> = ::std::result::Result<T, E>;
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => e.fmt(f),
+            Error::InvalidTrack => write!(f, "invalid track"),
+            Error::NonMonotonicTimestamp { timestamp_ns, last_timestamp_ns } => {
+                write!(f, "frame at {} ns comes before the last frame at {} ns",
+                       timestamp_ns, last_timestamp_ns)
+            }
+            Error::BadCodecId(id) => write!(f, "can not write a track header for codec {:?}", id),
+            Error::Finalize => write!(f, "could not finalize the segment"),
+            Error::InvalidArgument(what) => write!(f, "invalid argument: {}", what),
+            Error::Muxer(what) => write!(f, "muxer error: {}", what),
+            Error::Parse(what) => write!(f, "parse error: {}", what),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Returned by a failed `finalize`, with the writer so that whatever made
+/// it to the sink can still be used or cleaned up.
+pub struct FinalizeError<W> {
+    error: Error,
+    writer: W,
+}
+
+impl<W> FinalizeError<W> {
+    pub(crate) fn new(error: Error, writer: W) -> Self {
+        FinalizeError { error, writer }
+    }
+
+    pub fn error(&self) -> &Error {
+        &self.error
+    }
+
+    pub fn into_writer(self) -> W {
+        self.writer
+    }
+
+    pub fn into_parts(self) -> (Error, W) {
+        (self.error, self.writer)
+    }
+}
+
+impl<W> From<FinalizeError<W>> for Error {
+    fn from(e: FinalizeError<W>) -> Self {
+        e.error
+    }
+}
+
+impl<W> fmt::Debug for FinalizeError<W> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FinalizeError").field("error", &self.error).finish_non_exhaustive()
+    }
+}
+
+impl<W> fmt::Display for FinalizeError<W> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl<W> error::Error for FinalizeError<W> {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
@@ -1,205 +1,888 @@
 
+#[cfg(feature = "libwebm")]
 extern crate webm_sys as ffi;
+#[cfg(feature = "tokio")]
+extern crate tokio;
 
+#[cfg(not(any(feature = "libwebm", feature = "pure-rust")))]
+compile_error!("enable the `libwebm` feature, the `pure-rust` feature, or both");
+
+mod error;
+#[cfg(feature = "libwebm")]
+pub mod concat;
+#[cfg(feature = "libwebm")]
+pub mod demux;
+pub mod ebml;
+#[cfg(feature = "libwebm")]
+pub mod repair;
+#[cfg(feature = "libwebm")]
+pub mod trim;
+pub mod validate;
+
+pub use error::{Error, FinalizeError, Result};
+
+/// The muxer API. With the `libwebm` feature `Segment` and its tracks are
+/// backed by libwebm; with only `pure-rust` by a muxer written in Rust that
+/// needs no C++ toolchain and leaves out text tracks, chapters, tags and
+/// `finalize_cues_first`. With both, libwebm is used, so enabling
+/// `pure-rust` never takes API away from another crate in the build.
 pub mod mux {        
-    use ffi;
+    use ebml;
+    use {Error, FinalizeError, Result};
+    use std::cell::Cell;
     use std::os::raw::c_void;
+    use std::ptr;
     use std::fs::{File, OpenOptions};
-    use std::path::Path;
+    use std::path::{Path, PathBuf};
+    use std::mem;
+    use std::sync::mpsc::{self, Receiver};
+    use std::time::{Duration, Instant};
+    use std::slice::from_raw_parts;
 
-    use std::io::{Write, Seek};   
+    use std::io::{Write, Seek, SeekFrom};   
     use std::io; 
     
+    #[cfg(feature = "tokio")]
+    mod async_sink;
+    mod codec_private;
+    mod colour;
+    mod frame;
+    mod interleave;
+    #[cfg(feature = "libwebm")]
+    mod libwebm;
+    #[cfg(all(feature = "pure-rust", not(feature = "libwebm")))]
+    mod muxer;
+    #[cfg(all(feature = "pure-rust", not(feature = "libwebm")))]
+    mod native;
+    mod tap;
+    mod video;
+
+    #[cfg(feature = "libwebm")]
+    use self::libwebm as backend;
+    #[cfg(all(feature = "pure-rust", not(feature = "libwebm")))]
+    use self::native as backend;
+    use self::backend::{AudioHandle, TrackHandle, VideoHandle};
+
+    #[cfg(feature = "tokio")]
+    pub use self::async_sink::{async_sink, AsyncSink, Forward, SinkLevel};
+    #[doc(hidden)]
+    pub use self::backend::SegmentCore;
+    pub use self::codec_private::{av1_codec_private, opus_head, vorbis_codec_private,
+                                  OPUS_SEEK_PRE_ROLL_NS};
+    pub use self::colour::{ChromaSiting, Colour, MasteringMetadata, MatrixCoefficients, Primaries,
+                           Range, TransferCharacteristics};
+    pub use self::frame::{lace, Frame, Lacing};
+    pub use self::interleave::{Multiplexer, TrackSender, DEFAULT_MAX_INTERLEAVE_DELAY_NS};
+    #[cfg(feature = "libwebm")]
+    pub use self::libwebm::{TextTrack, TextTrackKind};
+    pub use self::video::{Crop, Projection, ProjectionType, StereoMode};
+
+    /// How `WebmWriter` lays a recording out across chunk files.
+    #[derive(Eq, PartialEq, Clone, Copy, Debug)]
+    pub enum ChunkMode {
+        /// `<base>_0.webm` holds the EBML header, Segment and Tracks, and every
+        /// cluster after that goes to its own `<base>_N.webm`. Chunks only play
+        /// once concatenated in order.
+        Split,
+        /// Every `<base>_N.webm` repeats the header and starts at a keyframe,
+        /// so each chunk plays on its own.
+        SelfContained,
+        /// The header goes to `<base>_init.webm`. Media chunks `<base>_N.webm`
+        /// start at keyframes and play when appended to the init file.
+        InitSegment,
+    }
+
+    /// What a finished chunk file holds.
+    #[derive(Eq, PartialEq, Clone, Copy, Debug)]
+    pub enum ChunkKind {
+        /// EBML header, Segment and Tracks, no frames.
+        Init,
+        /// Clusters that need the init chunk in front of them.
+        Media,
+        /// Header and clusters; playable by itself.
+        SelfContained,
+    }
+
+    /// When `WebmWriter` may start a new chunk.
+    ///
+    /// Chunks always begin at a cluster boundary, and in the keyframe aligned
+    /// modes, or with `keyframes_only`, only at a cluster that opens with a
+    /// video keyframe. With neither limit set every such boundary starts a
+    /// new chunk; otherwise the first boundary after either limit is reached
+    /// does. For chunks of predictable
+    /// length, keep the encoder's keyframe interval and the segment's
+    /// `set_max_cluster_duration`/`set_max_cluster_size` below the limits.
+    #[derive(Eq, PartialEq, Clone, Copy, Debug, Default)]
+    pub struct RotationPolicy {
+        /// Media time a chunk should span.
+        pub duration_ns: Option<u64>,
+        /// Bytes a chunk file should hold.
+        pub size_bytes: Option<u64>,
+        /// Rotate only where a video keyframe opens the cluster, in
+        /// `ChunkMode::Split` too; the other modes always do.
+        pub keyframes_only: bool,
+    }
+
+    impl RotationPolicy {
+        fn is_due(&self, duration_ns: u64, size_bytes: u64) -> bool {
+            if self.duration_ns.is_none() && self.size_bytes.is_none() {
+                return true;
+            }
+            self.duration_ns.is_some_and(|d| duration_ns >= d) ||
+                self.size_bytes.is_some_and(|s| size_bytes >= s)
+        }
+    }
+
+    /// libwebm's default, which this crate never changes.
+    const TIMECODE_SCALE_NS: u64 = 1_000_000;
+
+    /// Describes a chunk file `WebmWriter` has finished writing.
+    #[derive(Eq, PartialEq, Clone, Debug)]
+    pub struct ChunkInfo {
+        /// Position among the chunks of this writer, counting from 0.
+        pub index: u32,
+        pub path: PathBuf,
+        pub kind: ChunkKind,
+        pub size: u64,
+        /// Earliest and latest block timestamps in the chunk; `None` for a
+        /// chunk without frames.
+        pub first_timestamp_ns: Option<u64>,
+        pub last_timestamp_ns: Option<u64>,
+        /// Whether the first video frame (the first frame, without a video
+        /// track) in the chunk is a keyframe.
+        pub starts_with_keyframe: bool,
+        /// Set on the last chunk, reported when the writer is dropped.
+        pub is_final: bool,
+    }
+
+    type ChunkCallback = Box<dyn FnMut(ChunkInfo) + Send>;
+
+    #[derive(Default)]
+    struct ChunkStats {
+        first_ns: Option<u64>,
+        last_ns: Option<u64>,
+        keyframe_start: Option<bool>,
+    }
+
+    impl ChunkStats {
+        fn record(&mut self, timestamp_ns: u64, leads: bool, keyframe: bool) {
+            self.first_ns = Some(self.first_ns.map_or(timestamp_ns, |t| t.min(timestamp_ns)));
+            self.last_ns = Some(self.last_ns.map_or(timestamp_ns, |t| t.max(timestamp_ns)));
+            if leads && self.keyframe_start.is_none() {
+                self.keyframe_start = Some(keyframe);
+            }
+        }
+
+        fn merge(&mut self, other: ChunkStats) {
+            self.first_ns = match (self.first_ns, other.first_ns) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+            self.last_ns = self.last_ns.max(other.last_ns);
+            self.keyframe_start = self.keyframe_start.or(other.keyframe_start);
+        }
+    }
+
+    struct PendingCluster {
+        start: u64,
+        timecode: Option<u64>,
+        data: Vec<u8>,
+        stats: ChunkStats,
+    }
+
     pub struct WebmWriter
     {
-        webm_writer: ffi::mux::WriterMutPtr,
+        mkv_writer: RawWriter,
         file: Box<File>,        
-        chunk_cb: fn(&str),
+        chunk_cb: ChunkCallback,
+        mode: ChunkMode,
+        rotation: RotationPolicy,
+        chunk_kind: ChunkKind,
+        chunk_stats: ChunkStats,
+        chunks_done: u32,
         chunk_count: u32,
         base_name: String,
         chunk_name: String,
         file_path: String,
+        // Positions handed to libwebm are offsets into one continuous stream;
+        // the current file holds it from `chunk_start` on, after `prefix_len`
+        // bytes of repeated header.
+        pos: u64,
+        end: u64,
+        chunk_start: u64,
+        prefix_len: u64,
+        chunk_timecode: Option<u64>,
+        cluster_timecode: u64,
+        header: Vec<u8>,
+        header_done: bool,
+        // A cluster whose bytes are held back until its timecode, or in the
+        // keyframe aligned modes its first video block, shows whether a new
+        // chunk starts there.
+        pending: Option<PendingCluster>,
+        tap: tap::ElementTap,
+        last_track_number: u64,
+        video_tracks: Vec<u64>,
+        sync_interval: Option<Duration>,
+        last_sync: Instant,
+        error: ErrorCell,
     }
 
-    //unsafe impl<T: Send + Write + Seek > Send for Writer<T> {}
-
-    impl WebmWriter
-    {
-        pub fn new(file_path: &str, base_name: &str, chunk_cb: fn(&str)) -> Box<WebmWriter> {
-            use std::io::SeekFrom;
-            use std::slice::from_raw_parts;
-            use std::mem::transmute;
-
-            let chunk_count = 0;
-            let chunk_name = format!("{}_{}.webm", base_name, chunk_count);
-            let mut path = Path::new(file_path);
-            let path = path.join(&chunk_name);
-            let file = Box::new(match { OpenOptions::new()
+    fn create_chunk_file(path: &Path) -> io::Result<File> {
+        match OpenOptions::new()
             .write(true)
             .create_new(true)
-            .open(&path)} {
-                Ok(file) => file,
+            .open(path) {
+                Ok(file) => Ok(file),
                 Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
-                    File::create(&path).unwrap()
+                    File::create(path)
                 },
-                Err(e) => panic!(),
-            });            
+                Err(e) => Err(e),
+            }
+    }
+
+    impl WebmWriter
+    {
+        /// Writes `<file_path>/<base_name>_N.webm` chunks in `ChunkMode::Split`
+        /// layout, calling `chunk_cb` with the path of each finished chunk.
+        pub fn new(file_path: &str, base_name: &str, chunk_cb: fn(&str)) -> Result<Box<WebmWriter>> {
+            Self::create(file_path, base_name, ChunkMode::Split,
+                         Box::new(move |info: ChunkInfo| chunk_cb(info.path.to_str().unwrap())))
+        }
+
+        /// Like `new`, with a choice of chunk layout and a callback that can
+        /// hold state, such as an upload queue. It runs on whichever thread
+        /// is muxing when a chunk is done.
+        ///
+        /// Chunking relies on the segment staying in live mode.
+        pub fn with_callback<F>(file_path: &str, base_name: &str, mode: ChunkMode,
+                                chunk_cb: F) -> Result<Box<WebmWriter>>
+            where F: FnMut(ChunkInfo) + Send + 'static
+        {
+            Self::create(file_path, base_name, mode, Box::new(chunk_cb))
+        }
+
+        /// Like `with_callback`, delivering each `ChunkInfo` over a channel.
+        pub fn with_channel(file_path: &str, base_name: &str, mode: ChunkMode)
+                            -> Result<(Box<WebmWriter>, Receiver<ChunkInfo>)> {
+            let (sender, receiver) = mpsc::channel();
+            let writer = Self::with_callback(file_path, base_name, mode, move |info| {
+                let _ = sender.send(info);
+            })?;
+            Ok((writer, receiver))
+        }
+
+        fn create(file_path: &str, base_name: &str, mode: ChunkMode,
+                  chunk_cb: ChunkCallback) -> Result<Box<WebmWriter>> {
+            let (chunk_name, chunk_kind) = match mode {
+                ChunkMode::Split => (format!("{}_0.webm", base_name), ChunkKind::Init),
+                ChunkMode::SelfContained => (format!("{}_0.webm", base_name), ChunkKind::SelfContained),
+                ChunkMode::InitSegment => (format!("{}_init.webm", base_name), ChunkKind::Init),
+            };
+            let path = Path::new(file_path).join(&chunk_name);
+            let file = Box::new(create_chunk_file(&path)?);
 
             let mut w = Box::new(WebmWriter {                
-                webm_writer: 0 as ffi::mux::WriterMutPtr,
+                mkv_writer: RawWriter {
+                    dest: ptr::null_mut(),
+                    write: Self::write_fn,
+                    get_pos: Self::get_pos_fn,
+                    set_pos: Some(Self::set_pos_fn),
+                    element_start: Some(Self::element_start_fn),
+                },
                 file,
                 chunk_cb,
-                chunk_count,
+                mode,
+                rotation: RotationPolicy::default(),
+                chunk_kind,
+                chunk_stats: ChunkStats::default(),
+                chunks_done: 0,
+                chunk_count: 0,
                 base_name: base_name.to_string(),
                 chunk_name,
                 file_path: file_path.to_string(),
+                pos: 0,
+                end: 0,
+                chunk_start: 0,
+                prefix_len: 0,
+                chunk_timecode: None,
+                cluster_timecode: 0,
+                header: Vec::new(),
+                header_done: false,
+                pending: None,
+                tap: tap::ElementTap::default(),
+                last_track_number: 0,
+                video_tracks: Vec::new(),
+                sync_interval: None,
+                last_sync: Instant::now(),
+                error: Cell::new(None),
             });
-            
-            extern "C" fn write_fn(dest: *mut c_void,
-                                      buf: *const c_void,
-                                      len: usize) -> bool                
-            {
-                let writer: &mut WebmWriter = unsafe { transmute(dest) };
-
-                let buf = unsafe {
-                    from_raw_parts(buf as *const u8, len as usize)
-                };                
-                writer.file.write(buf).is_ok()
-            }
-            extern "C" fn get_pos_fn(dest: *mut c_void) -> u64                
-            {
-                let writer: &mut WebmWriter = unsafe { transmute(dest) };
-                
-                let pos = writer.file.seek(SeekFrom::Current(0))
-                    .unwrap();                
-                pos
-            }
-            extern "C" fn set_pos_fn(dest: *mut c_void,
-                                        pos: u64) -> bool                
-            {
-                let writer: &mut WebmWriter = unsafe { transmute(dest) };                
-                writer.file.seek(SeekFrom::Start(pos)).is_ok()
-            }
-            extern "C" fn element_start_notify_fn(dest: *mut c_void,
-                element_id: u64, pos: i64) -> ()                
-            {
-                
-                unsafe {                    
-                    if (element_id == ffi::mux::ELEMENT_ID_MKVCLUSTE as u64) {
-                        let writer: &mut WebmWriter = unsafe { transmute(dest) };                
-                        writer.update_chunk();
-                    }                     
-                }                
-            }            
-
-            w.webm_writer = unsafe {
-                ffi::mux::new_writer(Some(write_fn),
-                                     Some(get_pos_fn),
-                                     Some(set_pos_fn),
-                                     Some(element_start_notify_fn),
-                                     transmute(&mut *w))
-            };
-            debug_assert!(w.webm_writer != 0 as *mut _);
-            w
+            w.mkv_writer.dest = &mut *w as *mut WebmWriter as *mut c_void;
+            Ok(w)
         }        
 
-        fn update_chunk(&mut self) {
-            let mut path = Path::new(&self.file_path);
-            let path = path.join(&self.chunk_name);
-            (self.chunk_cb)(path.to_str().unwrap());
+        extern "C" fn write_fn(dest: *mut c_void,
+                               buf: *const c_void,
+                               len: usize) -> bool
+        {
+            let writer = unsafe { &mut *(dest as *mut WebmWriter) };
+            let buf = unsafe { from_raw_parts(buf as *const u8, len) };
+            match writer.write(buf) {
+                Ok(()) => true,
+                Err(e) => {
+                    record_error(&writer.error, e);
+                    false
+                }
+            }
+        }
+        extern "C" fn get_pos_fn(dest: *mut c_void) -> u64
+        {
+            let writer = unsafe { &*(dest as *const WebmWriter) };
+            writer.pos
+        }
+        extern "C" fn set_pos_fn(dest: *mut c_void, pos: u64) -> bool
+        {
+            let writer = unsafe { &mut *(dest as *mut WebmWriter) };
+            match writer.set_pos(pos) {
+                Ok(()) => true,
+                Err(e) => {
+                    record_error(&writer.error, e);
+                    false
+                }
+            }
+        }
+        extern "C" fn element_start_fn(dest: *mut c_void, element_id: u64, _pos: i64)
+        {
+            let writer = unsafe { &mut *(dest as *mut WebmWriter) };
+            if let Err(e) = writer.element_start(element_id) {
+                record_error(&writer.error, e);
+            }
+        }
+
+        fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+            if let Some(e) = peek_error(&self.error) {
+                return Err(e);
+            }
+
+            match self.pending {
+                Some(ref mut pending) if self.pos >= pending.start => {
+                    splice(&mut pending.data, (self.pos - pending.start) as usize, buf);
+                }
+                _ => {
+                    self.file.write_all(buf)?;
+                    if !self.header_done && self.mode == ChunkMode::SelfContained {
+                        splice(&mut self.header, self.pos as usize, buf);
+                    }
+                    self.sync_if_due()?;
+                }
+            }
+
+            let appended = self.pos == self.end;
+            self.pos += buf.len() as u64;
+            self.end = self.end.max(self.pos);
+            if appended {
+                if let Some(event) = self.tap.feed(buf) {
+                    self.on_event(event)?;
+                }
+            }
+            Ok(())
+        }
+
+        fn set_pos(&mut self, pos: u64) -> io::Result<()> {
+            if let Some(ref pending) = self.pending {
+                if pos >= pending.start {
+                    self.pos = pos;
+                    return Ok(());
+                }
+            }
+            if pos < self.chunk_start {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "position lies in a chunk that was already closed"));
+            }
+            self.file.seek(SeekFrom::Start(pos - self.chunk_start + self.prefix_len))?;
+            self.pos = pos;
+            Ok(())
+        }
+
+        fn element_start(&mut self, element_id: u64) -> io::Result<()> {
+            if let Some(event) = self.tap.start(element_id) {
+                self.on_event(event)?;
+            }
+            if element_id != ebml::ID_CLUSTER {
+                return Ok(());
+            }
+
+            if !self.header_done {
+                self.header_done = true;
+                return match self.mode {
+                    ChunkMode::SelfContained => Ok(()),
+                    _ => self.update_chunk(self.pos),
+                };
+            }
+            self.flush_pending(false)?;
+            self.pending = Some(PendingCluster {
+                start: self.pos,
+                timecode: None,
+                data: Vec::new(),
+                stats: ChunkStats::default(),
+            });
+            Ok(())
+        }
+
+        /// Whether the held back cluster is far enough into the current chunk
+        /// for the rotation policy.
+        fn rotation_due(&self) -> bool {
+            let pending = match self.pending {
+                Some(ref pending) => pending,
+                None => return false,
+            };
+            let duration = match (pending.timecode, self.chunk_timecode) {
+                (Some(tc), Some(start)) => tc.saturating_sub(start) * TIMECODE_SCALE_NS,
+                _ => 0,
+            };
+            let size = self.prefix_len + pending.start - self.chunk_start;
+            self.rotation.is_due(duration, size)
+        }
+
+        /// Whether chunks may only start at a video keyframe, so that a held
+        /// back cluster waits for its first video block.
+        fn keyframe_aligned(&self) -> bool {
+            self.mode != ChunkMode::Split || self.rotation.keyframes_only
+        }
+
+        fn on_event(&mut self, event: tap::Event) -> io::Result<()> {
+            match event {
+                tap::Event::TrackNumber(number) => self.last_track_number = number,
+                tap::Event::TrackType(1) => self.video_tracks.push(self.last_track_number),
+                tap::Event::ClusterTimecode(timecode) => {
+                    self.cluster_timecode = timecode;
+                    match self.pending {
+                        Some(ref mut pending) => pending.timecode = Some(timecode),
+                        None => {
+                            if self.chunk_timecode.is_none() {
+                                self.chunk_timecode = Some(timecode);
+                            }
+                        }
+                    }
+                    if self.pending.is_some() && !self.keyframe_aligned() {
+                        let due = self.rotation_due();
+                        self.flush_pending(due)?;
+                    }
+                }
+                tap::Event::Block { track, timecode, keyframe } => {
+                    let leads = self.video_tracks.is_empty() || self.video_tracks.contains(&track);
+                    let timestamp = (self.cluster_timecode as i64 + i64::from(timecode)).max(0) as u64 *
+                        TIMECODE_SCALE_NS;
+                    match self.pending {
+                        Some(ref mut pending) => pending.stats.record(timestamp, leads, keyframe),
+                        None => self.chunk_stats.record(timestamp, leads, keyframe),
+                    }
+                    if leads && self.pending.is_some() && self.keyframe_aligned() {
+                        let due = keyframe && self.rotation_due();
+                        self.flush_pending(due)?;
+                    }
+                }
+                _ => {}
+            }
+            Ok(())
+        }
+
+        /// Writes out a held back cluster, first starting a new chunk with it
+        /// if `new_chunk` is set.
+        fn flush_pending(&mut self, new_chunk: bool) -> io::Result<()> {
+            let pending = match self.pending.take() {
+                Some(pending) => pending,
+                None => return Ok(()),
+            };
+            if new_chunk {
+                self.update_chunk(pending.start)?;
+                self.chunk_timecode = pending.timecode;
+            }
+            self.chunk_stats.merge(pending.stats);
+
+            self.file.seek(SeekFrom::Start(pending.start - self.chunk_start + self.prefix_len))?;
+            self.file.write_all(&pending.data)?;
+            if self.pos != pending.start + pending.data.len() as u64 {
+                self.file.seek(SeekFrom::Start(self.pos - self.chunk_start + self.prefix_len))?;
+            }
+            self.sync_if_due()
+        }
 
-            self.chunk_count = self.chunk_count + 1;
+        /// Makes `fsync` run on the chunk being written at most `interval`
+        /// apart, and on every chunk as it is closed, so that a crash loses
+        /// no more than about `interval` of recording; see `webm::repair`
+        /// for recovering the rest. `None`, the default, leaves flushing to
+        /// the OS.
+        pub fn set_sync_interval(&mut self, interval: Option<Duration>) {
+            self.sync_interval = interval;
+        }
+
+        fn sync_if_due(&mut self) -> io::Result<()> {
+            match self.sync_interval {
+                Some(interval) if self.last_sync.elapsed() >= interval => self.sync(),
+                _ => Ok(()),
+            }
+        }
+
+        fn sync(&mut self) -> io::Result<()> {
+            self.file.sync_data()?;
+            self.last_sync = Instant::now();
+            Ok(())
+        }
+
+        /// Sets when chunks rotate. Takes effect from the next cluster on.
+        pub fn set_rotation(&mut self, policy: RotationPolicy) {
+            self.rotation = policy;
+        }
+
+        /// Reports the current chunk as done.
+        fn finish_chunk(&mut self, is_final: bool) -> io::Result<()> {
+            let stats = mem::take(&mut self.chunk_stats);
+            let info = ChunkInfo {
+                index: self.chunks_done,
+                path: Path::new(&self.file_path).join(&self.chunk_name),
+                kind: self.chunk_kind,
+                size: self.file.metadata()?.len(),
+                first_timestamp_ns: stats.first_ns,
+                last_timestamp_ns: stats.last_ns,
+                starts_with_keyframe: stats.keyframe_start.unwrap_or(false),
+                is_final,
+            };
+            self.chunks_done += 1;
+            (self.chunk_cb)(info);
+            Ok(())
+        }
+
+        /// Closes the current chunk and opens the next one, which receives
+        /// the stream from `chunk_start` on.
+        fn update_chunk(&mut self, chunk_start: u64) -> io::Result<()> {
+            if self.sync_interval.is_some() {
+                self.sync()?;
+            }
+            self.finish_chunk(false)?;
+
+            if self.chunk_kind != ChunkKind::Init || self.mode == ChunkMode::Split {
+                self.chunk_count += 1;
+            }
             self.chunk_name = format!("{}_{}.webm", self.base_name, self.chunk_count);
+            self.chunk_kind = match self.mode {
+                ChunkMode::SelfContained => ChunkKind::SelfContained,
+                _ => ChunkKind::Media,
+            };
 
-            let mut path = Path::new(&self.file_path);
-            let path = path.join(&self.chunk_name);
-            self.file = Box::new(match { OpenOptions::new()
-                .write(true)
-                .create_new(true)
-                .open(&path)} {
-                    Ok(file) => file,
-                    Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
-                        File::create(&path).unwrap()
-                    },
-                    Err(e) => panic!(),
-                });        
+            let path = Path::new(&self.file_path).join(&self.chunk_name);
+            *self.file = create_chunk_file(&path)?;
+            self.chunk_start = chunk_start;
+            self.prefix_len = 0;
+            if self.mode == ChunkMode::SelfContained {
+                self.file.write_all(&self.header)?;
+                self.prefix_len = self.header.len() as u64;
+            }
+            Ok(())
         }
 
-        fn webm_writer(&self) -> ffi::mux::WriterMutPtr {
-            self.webm_writer
+    }
+
+    /// Writes `buf` into `data` at `offset`, growing it as needed.
+    fn splice(data: &mut Vec<u8>, offset: usize, buf: &[u8]) {
+        let end = offset + buf.len();
+        if data.len() < end {
+            data.resize(end, 0);
         }
+        data[offset..end].copy_from_slice(buf);
+    }
+
+    /// Where a writer keeps the first I/O error it ran into. The muxer only
+    /// sees `false` from the callbacks, so the error is picked up from here
+    /// when a muxer call fails.
+    type ErrorCell = Cell<Option<io::Error>>;
+
+    fn record_error(cell: &ErrorCell, e: io::Error) {
+        let first = cell.take().unwrap_or(e);
+        cell.set(Some(first));
+    }
+
+    /// A copy of the recorded error, which stays in place so that every
+    /// later write fails the same way.
+    fn peek_error(cell: &ErrorCell) -> Option<io::Error> {
+        let error = cell.take();
+        let copy = error.as_ref().map(|e| io::Error::new(e.kind(), e.to_string()));
+        cell.set(error);
+        copy
     }
 
+    // The muxer only calls back into the writer for whoever owns the
+    // segment it belongs to.
+    unsafe impl Send for WebmWriter {}
+
     impl Drop for WebmWriter {
         fn drop(&mut self) {
-            let mut path = Path::new(&self.file_path);
-            let path = path.join(&self.chunk_name);
-            (self.chunk_cb)(path.to_str().unwrap());
-            
-            unsafe {
-                ffi::mux::delete_writer(self.webm_writer);
-            }            
+            if let Some(event) = self.tap.finish() {
+                let _ = self.on_event(event);
+            }
+            let _ = self.flush_pending(false);
+            if self.sync_interval.is_some() {
+                let _ = self.sync();
+            }
+            let _ = self.finish_chunk(true);
+        }
+    }
+
+    /// The callbacks a muxer writes through, shaped like libwebm's
+    /// `IMkvWriter` so that either backend can drive them. Without `set_pos`
+    /// the output is written front to back; `element_start` hears of each
+    /// element just before its ID is written.
+    #[doc(hidden)]
+    #[derive(Clone, Copy)]
+    pub struct RawWriter {
+        dest: *mut c_void,
+        write: extern "C" fn(*mut c_void, *const c_void, usize) -> bool,
+        get_pos: extern "C" fn(*mut c_void) -> u64,
+        set_pos: Option<extern "C" fn(*mut c_void, u64) -> bool>,
+        element_start: Option<extern "C" fn(*mut c_void, u64, i64)>,
+    }
+
+    /// Muxer output into any `Write` sink: a file, an in-memory buffer, a
+    /// pipe or a socket.
+    ///
+    /// Sinks created with `new` must also be `Seek`; the muxer then goes back
+    /// to patch element sizes when a segment is finalized. Sinks created with
+    /// `new_non_seekable` are written strictly front to back, which only
+    /// suits live mode.
+    pub struct Writer<T> {
+        mkv_writer: RawWriter,
+        dest: T,
+        pos: u64,
+        error: ErrorCell,
+    }
+
+    unsafe impl<T: Send> Send for Writer<T> {}
+
+    impl<T: Write> Writer<T> {
+        extern "C" fn write_fn(dest: *mut c_void,
+                               buf: *const c_void,
+                               len: usize) -> bool
+        {
+            let writer = unsafe { &mut *(dest as *mut Writer<T>) };
+            let buf = unsafe { from_raw_parts(buf as *const u8, len) };
+            if peek_error(&writer.error).is_some() {
+                return false;
+            }
+            if let Err(e) = writer.dest.write_all(buf) {
+                record_error(&writer.error, e);
+                return false;
+            }
+            writer.pos += len as u64;
+            true
+        }
+        extern "C" fn get_pos_fn(dest: *mut c_void) -> u64 {
+            let writer = unsafe { &*(dest as *const Writer<T>) };
+            writer.pos
+        }
+
+        fn with_callbacks(dest: T, set_pos: Option<extern "C" fn(*mut c_void, u64) -> bool>)
+                          -> Box<Writer<T>> {
+            let mut w = Box::new(Writer {
+                mkv_writer: RawWriter {
+                    dest: ptr::null_mut(),
+                    write: Self::write_fn,
+                    get_pos: Self::get_pos_fn,
+                    set_pos,
+                    element_start: None,
+                },
+                dest,
+                pos: 0,
+                error: Cell::new(None),
+            });
+            w.mkv_writer.dest = &mut *w as *mut Writer<T> as *mut c_void;
+            w
+        }
+
+        /// Wraps a sink that can only be appended to, such as a pipe or a
+        /// socket.
+        pub fn new_non_seekable(dest: T) -> Box<Writer<T>> {
+            Self::with_callbacks(dest, None)
+        }
+
+        pub fn get_ref(&self) -> &T {
+            &self.dest
+        }
+
+        pub fn get_mut(&mut self) -> &mut T {
+            &mut self.dest
         }
+
+        #[allow(clippy::boxed_local)]
+        pub fn into_inner(self: Box<Self>) -> T {
+            self.dest
+        }
+    }
+
+    impl<T: Write + Seek> Writer<T> {
+        extern "C" fn set_pos_fn(dest: *mut c_void, pos: u64) -> bool {
+            let writer = unsafe { &mut *(dest as *mut Writer<T>) };
+            if let Err(e) = writer.dest.seek(SeekFrom::Start(pos)) {
+                record_error(&writer.error, e);
+                return false;
+            }
+            writer.pos = pos;
+            true
+        }
+
+        pub fn new(dest: T) -> Box<Writer<T>> {
+            Self::with_callbacks(dest, Some(Self::set_pos_fn))
+        }
+    }
+
+    /// Anything a `Segment` can mux into.
+    pub trait MkvWriter {
+        #[doc(hidden)]
+        fn mkv_writer(&self) -> RawWriter;
+        #[doc(hidden)]
+        fn error_cell(&self) -> *const ErrorCell;
+    }
+
+    impl MkvWriter for Box<WebmWriter> {
+        #[doc(hidden)]
+        fn mkv_writer(&self) -> RawWriter { self.mkv_writer }
+        #[doc(hidden)]
+        fn error_cell(&self) -> *const ErrorCell { &self.error }
+    }
+
+    impl<T> MkvWriter for Box<Writer<T>> {
+        #[doc(hidden)]
+        fn mkv_writer(&self) -> RawWriter { self.mkv_writer }
+        #[doc(hidden)]
+        fn error_cell(&self) -> *const ErrorCell { &self.error }
     }
 
     #[derive(Eq, PartialEq, Clone, Copy)]
-    pub struct VideoTrack(ffi::mux::SegmentMutPtr,
-                          ffi::mux::VideoTrackMutPtr);
+    pub struct VideoTrack<'a>(&'a SegmentCore, VideoHandle);
     #[derive(Eq, PartialEq, Clone, Copy)]
-    pub struct AudioTrack(ffi::mux::SegmentMutPtr,
-                          ffi::mux::AudioTrackMutPtr);
-
-    unsafe impl Send for VideoTrack {}
-    unsafe impl Send for AudioTrack {}
+    pub struct AudioTrack<'a>(&'a SegmentCore, AudioHandle);
 
     pub trait Track {
         fn is_audio(&self) -> bool { false }
         fn is_video(&self) -> bool { false }
 
-        fn add_frame(&mut self, data: &[u8], timestamp_ns: u64, keyframe: bool) -> bool {
-            unsafe {
-                ffi::mux::segment_add_frame(self.get_segment(),
-                                            self.get_track(),
-                                            data.as_ptr(),
-                                            data.len() as usize,
-                                            timestamp_ns, keyframe)
-            }
+        /// The number blocks refer to the track by; see `Multiplexer::sender`.
+        fn number(&self) -> u64 {
+            self.get_segment().track_number(self.get_track())
+        }
+
+        fn add_frame(&mut self, data: &[u8], timestamp_ns: u64, keyframe: bool) -> Result<()> {
+            self.get_segment().add_frame(self.get_track(), data, timestamp_ns, keyframe)
+        }
+
+        /// Like `add_frame`, with `additional` stored next to the frame in a
+        /// BlockAdditional tagged `add_id`. For WebM transparent video the
+        /// additional is the VP8/VP9 alpha-plane bitstream with `add_id` 1;
+        /// other IDs carry application side data.
+        fn add_frame_with_additional(&mut self, data: &[u8], additional: &[u8], add_id: u64,
+                                     timestamp_ns: u64, keyframe: bool) -> Result<()> {
+            self.get_segment().add_frame_with_additional(self.get_track(), data, additional, add_id,
+                                                         timestamp_ns, keyframe)
+        }
+
+        /// Adds a frame described by a `Frame` builder, for anything beyond
+        /// what `add_frame` takes: duration, discard padding, an explicit
+        /// reference, additional data.
+        fn add_generic_frame(&mut self, frame: &Frame) -> Result<()> {
+            self.get_segment().add_generic_frame(self.get_track(), frame)
+        }
+
+        /// Sets the track's CodecPrivate, e.g. from `opus_head` or
+        /// `vorbis_codec_private`. Must be called before the first frame.
+        fn set_codec_private(&mut self, data: &[u8]) -> Result<()> {
+            self.get_segment().set_codec_private(self.get_track(), data)
         }
 
         #[doc(hidden)]
-        fn get_segment(&self) -> ffi::mux::SegmentMutPtr;
+        fn get_segment(&self) -> &SegmentCore;
 
         #[doc(hidden)]
-        fn get_track(&self) -> ffi::mux::TrackMutPtr;
+        fn get_track(&self) -> TrackHandle;
     }
-    impl VideoTrack {
-        pub fn set_color(&mut self, bit_depth: u8, subsampling: (bool, bool), full_range: bool) -> bool {
+    impl<'a> VideoTrack<'a> {
+        pub fn set_color(&mut self, bit_depth: u8, subsampling: (bool, bool), full_range: bool) -> Result<()> {
             let (sampling_horiz, sampling_vert) = subsampling;
-            fn to_int(b: bool) -> i32 { if b {1} else {0}};
-            unsafe {
-                ffi::mux::mux_set_color(self.get_track(), bit_depth.into(), to_int(sampling_horiz), to_int(sampling_vert), to_int(full_range)) != 0
-            }
+            self.set_colour(&Colour {
+                bits_per_channel: Some(bit_depth),
+                chroma_subsampling: Some((sampling_horiz as u8, sampling_vert as u8)),
+                range: Some(if full_range { Range::Full } else { Range::Broadcast }),
+                ..Colour::default()
+            })
+        }
+
+        /// Describes the track's colour fully, including HDR metadata.
+        /// Replaces anything `set_color` set. Fails with `InvalidArgument` if
+        /// a value is out of range, e.g. a mastering luminance above
+        /// 9999.99 cd/m².
+        pub fn set_colour(&mut self, colour: &Colour) -> Result<()> {
+            self.0.set_colour(self.1, colour)
+        }
+
+        /// Size to show the picture at, in pixels. Differs from the coded size
+        /// for anamorphic video or captures downscaled from a HiDPI screen;
+        /// the ratio of the two sets the display aspect ratio.
+        pub fn set_display_size(&mut self, width: u64, height: u64) {
+            self.0.set_display_size(self.1, width, height);
+        }
+
+        pub fn set_crop(&mut self, crop: Crop) {
+            self.0.set_crop(self.1, crop);
+        }
+
+        /// Every `StereoMode` is valid for both backends; libwebm only
+        /// refuses values outside it.
+        pub fn set_stereo_mode(&mut self, mode: StereoMode) -> Result<()> {
+            self.0.set_stereo_mode(self.1, mode)
+        }
+
+        /// Marks the track as carrying alpha data in BlockAdditionals.
+        pub fn set_alpha_mode(&mut self, has_alpha: bool) -> Result<()> {
+            self.0.set_alpha_mode(self.1, has_alpha)
+        }
+
+        /// Nominal frame rate, informational only; frame timing always comes
+        /// from the timestamps.
+        pub fn set_frame_rate(&mut self, frame_rate: f64) {
+            self.0.set_frame_rate(self.1, frame_rate);
+        }
+
+        /// Pose angles and private data are stored as given, with either
+        /// backend. libwebm only fails if it can not allocate the copy.
+        pub fn set_projection(&mut self, projection: &Projection) -> Result<()> {
+            self.0.set_projection(self.1, projection)
         }
     }
-    impl Track for VideoTrack {
+    impl<'a> Track for VideoTrack<'a> {
         fn is_video(&self) -> bool { true }
 
         #[doc(hidden)]
-        fn get_segment(&self) -> ffi::mux::SegmentMutPtr { self.0 }
+        fn get_segment(&self) -> &SegmentCore { self.0 }
         #[doc(hidden)]
-        fn get_track(&self) -> ffi::mux::TrackMutPtr {
-            unsafe { ffi::mux::video_track_base_mut(self.1) }
+        fn get_track(&self) -> TrackHandle { self.0.video_track(self.1) }
+    }
+    impl<'a> AudioTrack<'a> {
+        /// Samples the decoder drops from the start, as time. For Opus this
+        /// is the OpusHead pre-skip: `pre_skip * 1_000_000_000 / 48_000`.
+        pub fn set_codec_delay(&mut self, delay_ns: u64) {
+            self.0.set_codec_delay(self.1, delay_ns);
+        }
+
+        /// How much audio must be decoded before a seek target to get
+        /// correct output; `OPUS_SEEK_PRE_ROLL_NS` for Opus.
+        pub fn set_seek_pre_roll(&mut self, pre_roll_ns: u64) {
+            self.0.set_seek_pre_roll(self.1, pre_roll_ns);
         }
     }
-    impl Track for AudioTrack {
+    impl<'a> Track for AudioTrack<'a> {
         fn is_audio(&self) -> bool { true }
 
         #[doc(hidden)]
-        fn get_segment(&self) -> ffi::mux::SegmentMutPtr { self.0 }
+        fn get_segment(&self) -> &SegmentCore { self.0 }
         #[doc(hidden)]
-        fn get_track(&self) -> ffi::mux::TrackMutPtr {
-            unsafe { ffi::mux::audio_track_base_mut(self.1) }
-        }
+        fn get_track(&self) -> TrackHandle { self.0.audio_track(self.1) }
     }
 
     #[derive(Eq, PartialEq, Clone, Copy, Debug)]
@@ -207,87 +890,1136 @@ pub mod mux {
         Opus,
         Vorbis,
     }
-    impl AudioCodecId {
-        fn get_id(&self) -> u32 {
-            match self {
-                &AudioCodecId::Opus => ffi::mux::OPUS_CODEC_ID,
-                &AudioCodecId::Vorbis => ffi::mux::VORBIS_CODEC_ID,
-            }
-        }
-    }
     #[derive(Eq, PartialEq, Clone, Copy, Debug)]
     pub enum VideoCodecId {
         VP8,
         VP9,
-    }
-    impl VideoCodecId {
-        fn get_id(&self) -> u32 {
-            match self {
-                &VideoCodecId::VP8 => ffi::mux::VP8_CODEC_ID,
-                &VideoCodecId::VP9 => ffi::mux::VP9_CODEC_ID,
-            }
-        }
+        /// Needs its `av1C` CodecPrivate (see `av1_codec_private`) set before
+        /// the first frame, or the track header fails to write.
+        AV1,
     }
 
-    //unsafe impl<W: Send> Send for Segment<W> {}
+    #[derive(Eq, PartialEq, Clone, Copy, Debug)]
+    pub enum SegmentMode {
+        /// Streamable output: unknown element sizes, no Cues and no Duration.
+        Live,
+        /// Seekable output: sizes, Duration and Cues are written by
+        /// `finalize`. Needs a seekable `Writer`; chunking `WebmWriter`s only
+        /// work in live mode.
+        File,
+    }
 
-    pub struct Segment {
-        ffi: ffi::mux::SegmentMutPtr,
-        _writer: Box<WebmWriter>,
+    /// Tracks added to a segment borrow it, and `finalize` consumes it, so
+    /// frames can not be added once it is finalized:
+    ///
+    /// ```compile_fail
+    /// # use webm::mux::{Segment, Track, VideoCodecId, Writer};
+    /// # use std::io::Cursor;
+    /// let segment = Segment::new(Writer::new(Cursor::new(Vec::new()))).unwrap();
+    /// let mut vt = segment.add_video_track(64, 48, None, VideoCodecId::VP8).unwrap();
+    /// segment.finalize(None).unwrap();
+    /// vt.add_frame(&[0; 10], 0, true).unwrap();
+    /// ```
+    pub struct Segment<W = Box<WebmWriter>> {
+        core: SegmentCore,
+        writer: W,
     }
 
-    impl Segment {
-        /// Note: the supplied writer must have a lifetime larger than the segment.
-        pub fn new(dest: Box<WebmWriter>) -> Option<Self>            
+    impl<W: MkvWriter> Segment<W> {
+        /// Takes ownership of `dest`; `finalize` hands it back.
+        pub fn new(dest: W) -> Result<Self>            
         {
-            let ffi = unsafe { ffi::mux::new_segment() };
-            let success = unsafe {
-                let ret = ffi::mux::initialize_segment(ffi, dest.webm_writer());
-                ffi::mux::segment_set_mode(ffi, ffi::mux::SEGMENT_MODE_LIVE);
-                ret
-            };
-            if !success { return None; }
+            Self::with_mode(dest, SegmentMode::Live)
+        }
 
-            Some(Segment {
-                ffi: ffi,
-                _writer: dest,
+        pub fn with_mode(dest: W, mode: SegmentMode) -> Result<Self> {
+            Ok(Segment {
+                core: SegmentCore::new(dest.mkv_writer(), dest.error_cell(), mode)?,
+                writer: dest,
             })
         }
 
-        pub fn set_app_name(&mut self, name: &str) {
-            use std::ffi::CString;
-            unsafe {
-                ffi::mux::mux_set_writing_app(self.ffi, CString::new(name).unwrap().as_ptr());
-            }
+        /// File mode only: puts cue points on `track` instead of the first
+        /// video track.
+        pub fn set_cues_track<T: Track>(&self, track: &T) -> Result<()> {
+            self.check_owns(track)?;
+            self.core.set_cues_track(track.get_track())
+        }
+
+        /// File mode only: whether `finalize` writes Cues (the default).
+        pub fn output_cues(&self, output_cues: bool) {
+            self.core.output_cues(output_cues);
+        }
+
+        /// Starts a new cluster once a cluster spans this much media time.
+        pub fn set_max_cluster_duration(&self, duration_ns: u64) {
+            self.core.set_max_cluster_duration(duration_ns);
+        }
+
+        /// Starts a new cluster once a cluster's payload reaches this size.
+        pub fn set_max_cluster_size(&self, size_bytes: u64) {
+            self.core.set_max_cluster_size(size_bytes);
+        }
+
+        /// Makes the next frame added to any track open a new cluster.
+        pub fn force_new_cluster(&self) {
+            self.core.force_new_cluster();
+        }
+
+        pub fn set_app_name(&self, name: &str) -> Result<()> {
+            self.core.set_app_name(name)
         }
 
-        pub fn add_video_track(&mut self, width: u32, height: u32,
-                               id: Option<i32>, codec: VideoCodecId) -> VideoTrack
+        /// Tracks of another segment are `InvalidTrack` here.
+        fn check_owns<T: Track>(&self, track: &T) -> Result<()> {
+            if *track.get_segment() == self.core { Ok(()) } else { Err(Error::InvalidTrack) }
+        }
+
+        /// Fails with `InvalidTrack` if the muxer refuses the track, e.g.
+        /// because `id` is already taken.
+        pub fn add_video_track(&self, width: u32, height: u32,
+                               id: Option<i32>, codec: VideoCodecId) -> Result<VideoTrack<'_>>
         {
-            let vt = unsafe {
-                ffi::mux::segment_add_video_track(self.ffi, width as i32, height as i32,
-                                                  id.unwrap_or(0), codec.get_id())
-            };
-            VideoTrack(self.ffi, vt)
+            let vt = self.core.add_video_track(width, height, id.unwrap_or(0), codec)?;
+            Ok(VideoTrack(&self.core, vt))
+        }
+        pub fn add_audio_track(&self, sample_rate: i32, channels: i32,
+                               id: Option<i32>, codec: AudioCodecId) -> Result<AudioTrack<'_>> {
+            let at = self.core.add_audio_track(sample_rate, channels, id.unwrap_or(0), codec)?;
+            Ok(AudioTrack(&self.core, at))
         }
-        pub fn add_audio_track(&mut self, sample_rate: i32, channels: i32,
-                               id: Option<i32>, codec: AudioCodecId) -> AudioTrack {
-            let at = unsafe {
-                ffi::mux::segment_add_audio_track(self.ffi, sample_rate, channels,
-                                                  id.unwrap_or(0), codec.get_id())
+
+        /// Consumes the segment, which its tracks borrow, so none of them can
+        /// be used afterwards.
+        /// The writer is handed back either way; on failure it comes inside
+        /// the `FinalizeError`, whose error is `Io` if the writer failed at
+        /// any point. Otherwise it is `Finalize` from libwebm, while the
+        /// pure-Rust backend gives the cause, e.g. `BadCodecId`.
+        pub fn finalize(self, duration: Option<u64>) -> Result<W, FinalizeError<W>> {
+            let result = self.core.finalize(duration.unwrap_or(0));
+            // A write that failed earlier leaves the output incomplete even
+            // if the closing elements went through.
+            let error = match (peek_error(unsafe { &*self.writer.error_cell() }), result) {
+                (Some(e), _) => Error::Io(e),
+                (None, Ok(())) => return Ok(self.writer),
+                (None, Err(e)) => e,
             };
-            AudioTrack(self.ffi, at)
+            Err(FinalizeError::new(error, self.writer))
+        }
+    }
+
+    #[test]
+    fn sync_interval() {
+        use std::{fs, thread};
+
+        let dir = ::std::env::temp_dir().join(format!("webm-sync-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fn on_chunk(_: &str) {}
+        let mut writer = WebmWriter::new(dir.to_str().unwrap(), "sync", on_chunk).unwrap();
+        // A Void element, which the tap passes over.
+        let void = [0xEC, 0x81, 0x00];
+        let tick = || thread::sleep(Duration::from_millis(1));
+
+        let created = writer.last_sync;
+        tick();
+        writer.write(&void).unwrap();
+        assert_eq!(writer.last_sync, created);
+
+        // Due on every write.
+        writer.set_sync_interval(Some(Duration::from_secs(0)));
+        writer.write(&void).unwrap();
+        let synced = writer.last_sync;
+        assert!(synced > created);
+
+        // Not due again for an hour, but a closed chunk is synced anyway.
+        writer.set_sync_interval(Some(Duration::from_secs(3600)));
+        tick();
+        writer.write(&void).unwrap();
+        assert_eq!(writer.last_sync, synced);
+        let pos = writer.pos;
+        writer.update_chunk(pos).unwrap();
+        assert!(writer.last_sync > synced);
+
+        drop(writer);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+/// The in-memory segments tests mux into.
+#[cfg(test)]
+type MemorySegment = mux::Segment<Box<mux::Writer<::std::io::Cursor<Vec<u8>>>>>;
+
+/// Muxes a segment in `mode` into memory: `add` adds its tracks and frames,
+/// and the finalized stream comes back.
+#[cfg(test)]
+fn mux_to_vec<F: FnOnce(&MemorySegment)>(mode: mux::SegmentMode, add: F) -> Vec<u8> {
+    use std::io::Cursor;
+
+    let segment = mux::Segment::with_mode(mux::Writer::new(Cursor::new(Vec::new())), mode).unwrap();
+    add(&segment);
+    segment.finalize(None).ok().unwrap().into_inner().into_inner()
+}
+
+/// A 64x48 video track, the size the tests mux video at.
+#[cfg(test)]
+fn add_test_video<W: mux::MkvWriter>(segment: &mux::Segment<W>, codec: mux::VideoCodecId) -> mux::VideoTrack<'_> {
+    segment.add_video_track(64, 48, None, codec).unwrap()
+}
+
+/// A block as `read_blocks` finds it.
+#[cfg(test)]
+#[derive(Debug)]
+struct ReadBlock {
+    track: u64,
+    timestamp_ns: i64,
+    keyframe: bool,
+    /// The lacing bits of the block flags.
+    lacing: u8,
+    data: Vec<u8>,
+}
+
+/// The blocks of a muxed stream or media chunk in file order, read back
+/// through `ebml::Reader` so that tests check what either muxer backend
+/// wrote.
+#[cfg(test)]
+fn read_blocks(bytes: &[u8]) -> Vec<ReadBlock> {
+    use ebml::{parse_block_header, Reader, Value, ID_BLOCK, ID_BLOCK_GROUP, ID_CLUSTER,
+               ID_REFERENCE_BLOCK, ID_SEGMENT, ID_SIMPLE_BLOCK, ID_TIMECODE};
+    use std::io::Cursor;
+
+    let mut reader = Reader::new(Cursor::new(bytes)).unwrap();
+    let mut clusters = Vec::new();
+    for element in reader.elements_from(0).unwrap() {
+        match element.id {
+            ID_SEGMENT => clusters.extend(reader.children(&element).unwrap().into_iter()
+                                          .filter(|e| e.id == ID_CLUSTER)),
+            ID_CLUSTER => clusters.push(element),
+            _ => {}
         }
+    }
 
-        /// After calling, all tracks are freed (ie you can't use them).
-        pub fn finalize(self, duration: Option<u64>) -> bool {
-            let result = unsafe {
-                ffi::mux::finalize_segment(self.ffi, duration.unwrap_or(0))
+    let mut blocks = Vec::new();
+    for cluster in clusters {
+        let mut timecode = 0;
+        for child in reader.children(&cluster).unwrap() {
+            let (block, keyframe) = match child.id {
+                ID_TIMECODE => {
+                    if let Some(Value::Uint(value)) = reader.read_value(&child).unwrap() {
+                        timecode = value as i64;
+                    }
+                    continue;
+                }
+                ID_SIMPLE_BLOCK => (child, None),
+                ID_BLOCK_GROUP => {
+                    let group = reader.children(&child).unwrap();
+                    let block = *group.iter().find(|e| e.id == ID_BLOCK).unwrap();
+                    (block, Some(!group.iter().any(|e| e.id == ID_REFERENCE_BLOCK)))
+                }
+                _ => continue,
             };
-            unsafe {
-                ffi::mux::delete_segment(self.ffi);
+            let data = reader.read_data(&block, 1 << 20).unwrap();
+            let header = parse_block_header(&data, keyframe.is_none()).unwrap();
+            let header_len = data[0].leading_zeros() as usize + 4;
+            blocks.push(ReadBlock {
+                track: header.track,
+                timestamp_ns: (timecode + i64::from(header.timecode)) * 1_000_000,
+                keyframe: keyframe.unwrap_or(header.keyframe),
+                lacing: (data[header_len - 1] >> 1) & 3,
+                data: data[header_len..].to_vec(),
+            });
+        }
+    }
+    blocks
+}
+
+/// Every element of a muxed stream in file order, with the value of each
+/// non-master element, read back through `ebml::Reader`.
+#[cfg(test)]
+fn read_elements(bytes: &[u8]) -> Vec<(ebml::Element, Option<ebml::Value>)> {
+    use ebml::{element_type, Element, ElementType, Reader, Value};
+    use std::io::{Cursor, Read, Seek};
+
+    fn walk<R: Read + Seek>(reader: &mut Reader<R>, elements: Vec<Element>,
+                            out: &mut Vec<(Element, Option<Value>)>) {
+        for element in elements {
+            if element_type(element.id) == ElementType::Master {
+                out.push((element, None));
+                let children = reader.children(&element).unwrap();
+                walk(reader, children, out);
+            } else {
+                let value = reader.read_value(&element).unwrap();
+                out.push((element, value));
             }
-            result
         }
     }
+
+    let mut reader = Reader::new(Cursor::new(bytes)).unwrap();
+    let top = reader.elements_from(0).unwrap();
+    let mut elements = Vec::new();
+    walk(&mut reader, top, &mut elements);
+    elements
+}
+
+/// The values of every element `id` in `bytes`, in file order.
+#[cfg(test)]
+fn read_values(bytes: &[u8], id: u64) -> Vec<ebml::Value> {
+    read_elements(bytes).into_iter().filter(|(e, _)| e.id == id).filter_map(|(_, v)| v).collect()
+}
+
+/// The payloads of every element `id` in `bytes`, in file order, for the
+/// binary elements `read_values` only gives the length of.
+#[cfg(test)]
+fn read_payloads(bytes: &[u8], id: u64) -> Vec<Vec<u8>> {
+    use std::io::Cursor;
+
+    let mut reader = ebml::Reader::new(Cursor::new(bytes)).unwrap();
+    read_elements(bytes).into_iter()
+        .filter(|(e, _)| e.id == id)
+        .map(|(e, _)| reader.read_data(&e, 1 << 20).unwrap())
+        .collect()
+}
+
+#[test]
+fn writer_sinks() {
+    use mux::Track;
+    use std::io::Cursor;
+
+    fn mux_audio<W: mux::MkvWriter>(writer: W) -> W {
+        let segment = mux::Segment::new(writer).unwrap();
+        let mut at = segment.add_audio_track(48000, 2, None, mux::AudioCodecId::Opus).unwrap();
+        for i in 0..50u64 {
+            at.add_frame(&[i as u8; 20], i * 20_000_000, true).unwrap();
+        }
+        segment.finalize(None).ok().unwrap()
+    }
+
+    let seekable = mux_audio(mux::Writer::new(Cursor::new(Vec::new()))).into_inner().into_inner();
+    let streamed = mux_audio(mux::Writer::new_non_seekable(Vec::new())).into_inner();
+
+    for bytes in [seekable, streamed] {
+        // SamplingFrequency and Channels.
+        assert_eq!(read_values(&bytes, 0xB5), [ebml::Value::Float(48000.0)]);
+        assert_eq!(read_values(&bytes, 0x9F), [ebml::Value::Uint(2)]);
+        let blocks = read_blocks(&bytes);
+        assert_eq!(blocks.len(), 50);
+        assert_eq!(blocks[49].data, vec![49; 20]);
+    }
+}
+
+#[test]
+fn chunk_modes() {
+    use mux::{ChunkKind, ChunkMode, Track};
+    use std::fs;
+
+    let dir = ::std::env::temp_dir().join(format!("webm-chunk-modes-{}", ::std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    for &mode in &[ChunkMode::SelfContained, ChunkMode::InitSegment] {
+        let (writer, chunk_rx) = mux::WebmWriter::with_channel(dir.to_str().unwrap(), "rec", mode).unwrap();
+        let segment = mux::Segment::new(writer).unwrap();
+        let mut vt = add_test_video(&segment, mux::VideoCodecId::VP9);
+        let mut at = segment.add_audio_track(48000, 1, None, mux::AudioCodecId::Opus).unwrap();
+        for ms in 0..3000u64 {
+            if ms % 20 == 0 {
+                at.add_frame(&[1; 10], ms * 1_000_000, true).unwrap();
+            }
+            if ms % 40 == 0 {
+                vt.add_frame(&[2; 50], ms * 1_000_000, ms % 1000 == 0).unwrap();
+            }
+        }
+        assert!(segment.finalize(None).is_ok());
+
+        let chunks: Vec<_> = chunk_rx.try_iter().collect();
+        let init = match mode {
+            ChunkMode::InitSegment => {
+                assert_eq!(chunks[0].kind, ChunkKind::Init);
+                fs::read(&chunks[0].path).unwrap()
+            }
+            _ => Vec::new(),
+        };
+        let media = &chunks[init.len().min(1)..];
+        assert_eq!(media.len(), 3);
+
+        let mut total = 0;
+        for chunk in media {
+            assert_eq!(chunk.kind, if init.is_empty() { ChunkKind::SelfContained } else { ChunkKind::Media });
+            assert!(chunk.starts_with_keyframe);
+            let mut bytes = init.clone();
+            bytes.extend(fs::read(&chunk.path).unwrap());
+
+            assert_eq!(read_elements(&bytes).iter().filter(|(e, _)| e.id == ebml::ID_TRACK_ENTRY).count(), 2);
+            let blocks = read_blocks(&bytes);
+            let first_video = blocks.iter().find(|b| b.track == 1).unwrap();
+            assert!(first_video.keyframe);
+            total += blocks.len();
+        }
+        assert_eq!(total, 75 + 150);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn chunk_rotation() {
+    use mux::{ChunkInfo, ChunkMode, RotationPolicy, Track};
+    use std::fs;
+    use std::sync::{Arc, Mutex};
+
+    let dir = ::std::env::temp_dir().join(format!("webm-chunk-rotation-{}", ::std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    let chunks = Arc::new(Mutex::new(Vec::<ChunkInfo>::new()));
+    let mut writer = mux::WebmWriter::with_callback(dir.to_str().unwrap(), "rec", ChunkMode::SelfContained, {
+        let chunks = chunks.clone();
+        move |info| chunks.lock().unwrap().push(info)
+    }).unwrap();
+    writer.set_rotation(RotationPolicy { duration_ns: Some(2_000_000_000), size_bytes: None, keyframes_only: false });
+    let segment = mux::Segment::new(writer).unwrap();
+    segment.set_max_cluster_duration(500_000_000);
+    let mut at = segment.add_audio_track(48000, 1, None, mux::AudioCodecId::Opus).unwrap();
+    for i in 0..500u64 {
+        at.add_frame(&[1; 10], i * 20_000_000, true).unwrap();
+    }
+    assert!(segment.finalize(None).is_ok());
+
+    let chunks = chunks.lock().unwrap();
+    assert_eq!(chunks.len(), 5);
+    for (index, chunk) in chunks.iter().enumerate() {
+        let bytes = fs::read(&chunk.path).unwrap();
+        assert_eq!(chunk.index, index as u32);
+        assert_eq!(chunk.size, bytes.len() as u64);
+        assert_eq!(chunk.first_timestamp_ns, Some(index as u64 * 2_000_000_000));
+        assert_eq!(chunk.last_timestamp_ns, Some(index as u64 * 2_000_000_000 + 1_980_000_000));
+        assert_eq!(chunk.is_final, index == 4);
+
+        let blocks = read_blocks(&bytes);
+        assert_eq!(blocks.len(), 100);
+        assert_eq!(blocks[0].timestamp_ns, index as i64 * 2_000_000_000);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn split_keyframe_rotation() {
+    use mux::{ChunkInfo, ChunkKind, ChunkMode, RotationPolicy, Track};
+    use std::fs;
+    use std::sync::{Arc, Mutex};
+
+    let dir = ::std::env::temp_dir().join(format!("webm-split-keyframes-{}", ::std::process::id()));
+    for &keyframes_only in &[false, true] {
+        fs::create_dir_all(&dir).unwrap();
+        let chunks = Arc::new(Mutex::new(Vec::<ChunkInfo>::new()));
+        let mut writer = mux::WebmWriter::with_callback(dir.to_str().unwrap(), "rec", ChunkMode::Split, {
+            let chunks = chunks.clone();
+            move |info| chunks.lock().unwrap().push(info)
+        }).unwrap();
+        writer.set_rotation(RotationPolicy { duration_ns: Some(400_000_000), size_bytes: None, keyframes_only });
+        let segment = mux::Segment::new(writer).unwrap();
+        segment.set_max_cluster_duration(200_000_000);
+        let mut vt = add_test_video(&segment, mux::VideoCodecId::VP8);
+        for i in 0..100u64 {
+            vt.add_frame(&[3; 40], i * 40_000_000, i % 25 == 0).unwrap();
+        }
+        assert!(segment.finalize(None).is_ok());
+
+        let chunks = chunks.lock().unwrap();
+        assert_eq!(chunks[0].kind, ChunkKind::Init);
+        let starts: Vec<ReadBlock> = chunks[1..].iter()
+            .map(|chunk| read_blocks(&fs::read(&chunk.path).unwrap()).remove(0))
+            .collect();
+        if keyframes_only {
+            assert_eq!(starts.iter().map(|b| b.timestamp_ns).collect::<Vec<_>>(),
+                       vec![0, 1_000_000_000, 2_000_000_000, 3_000_000_000]);
+            assert!(starts.iter().all(|b| b.keyframe));
+        } else {
+            assert!(starts.len() > 4);
+            assert!(starts.iter().any(|b| !b.keyframe));
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+#[cfg(feature = "libwebm")]
+#[test]
+fn file_mode_cues() {
+    use ebml::{ID_CLUSTER, ID_CUES};
+    use mux::{SegmentMode, Track};
+    use std::io::Cursor;
+
+    fn record<W: mux::MkvWriter>(segment: &mux::Segment<W>) {
+        let mut vt = add_test_video(segment, mux::VideoCodecId::VP8);
+        for i in 0..100u64 {
+            vt.add_frame(&[3; 40], i * 40_000_000, i % 25 == 0).unwrap();
+        }
+    }
+    fn position(bytes: &[u8], id: u64) -> usize {
+        read_elements(bytes).iter().position(|(e, _)| e.id == id).unwrap()
+    }
+
+    let segment = mux::Segment::with_mode(mux::Writer::new(Cursor::new(Vec::new())), SegmentMode::File).unwrap();
+    record(&segment);
+    let cues_last = segment.finalize(None).ok().unwrap().into_inner().into_inner();
+    assert!(position(&cues_last, ID_CUES) > position(&cues_last, ID_CLUSTER));
+
+    let segment = mux::Segment::with_mode(mux::Writer::new(Cursor::new(Vec::new())), SegmentMode::File).unwrap();
+    record(&segment);
+    let cues_first = segment.finalize_cues_first(None, Cursor::new(Vec::new())).ok().unwrap().into_inner();
+    assert!(position(&cues_first, ID_CUES) < position(&cues_first, ID_CLUSTER));
+
+    for bytes in [cues_last, cues_first] {
+        let mut demuxer = demux::Demuxer::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(demuxer.duration_ns(), Some(99 * 40_000_000));
+        assert_eq!(demuxer.frames().count(), 100);
+    }
+}
+
+#[cfg(feature = "libwebm")]
+#[test]
+fn chapters() {
+    use ebml::Value::{String, Uint};
+    use mux::Track;
+
+    let bytes = mux_to_vec(mux::SegmentMode::Live, |segment| {
+        let mut vt = add_test_video(segment, mux::VideoCodecId::VP8);
+        segment.add_chapter(0, 1_000_000_000, "intro", &[("Intro", "eng"), ("Einleitung", "ger")]).unwrap();
+        segment.add_chapter(1_000_000_000, 2_000_000_000, "setup", &[("Setup", "eng")]).unwrap();
+        assert!(matches!(segment.add_chapter(0, 1, "bad\0id", &[]), Err(Error::InvalidArgument(_))));
+        for i in 0..50u64 {
+            vt.add_frame(&[1; 10], i * 40_000_000, i == 0).unwrap();
+        }
+    });
+
+    let elements = read_elements(&bytes);
+    let position = |id| elements.iter().position(|(e, _)| e.id == id).unwrap();
+    assert!(position(0x1043_A770) < position(ebml::ID_CLUSTER)); // Chapters
+    let strings = |values: &[&str]| values.iter().map(|v| String(v.to_string())).collect::<Vec<_>>();
+    assert_eq!(read_values(&bytes, 0x5654), strings(&["intro", "setup"])); // ChapterStringUID
+    // Chapter times are in nanoseconds, not TimestampScale units.
+    assert_eq!(read_values(&bytes, 0x91), vec![Uint(0), Uint(1_000_000_000)]); // ChapterTimeStart
+    assert_eq!(read_values(&bytes, 0x92), vec![Uint(1_000_000_000), Uint(2_000_000_000)]); // ChapterTimeEnd
+    assert_eq!(read_values(&bytes, 0x85), strings(&["Intro", "Einleitung", "Setup"])); // ChapString
+    assert_eq!(read_values(&bytes, 0x437C), strings(&["eng", "ger", "eng"])); // ChapLanguage
+}
+
+#[cfg(feature = "libwebm")]
+#[test]
+fn tags() {
+    use ebml::Value::String;
+    use mux::Track;
+
+    let bytes = mux_to_vec(mux::SegmentMode::Live, |segment| {
+        let mut vt = add_test_video(segment, mux::VideoCodecId::VP8);
+        segment.add_tag(&[("TITLE", "Tutorial"), ("SESSION_ID", "42")]).unwrap();
+        segment.add_track_tag(&vt, &[("TITLE", "Screen")]).unwrap();
+        assert!(matches!(segment.add_tag(&[("BAD", "nul\0")]), Err(Error::InvalidArgument(_))));
+        vt.add_frame(&[1; 10], 0, true).unwrap();
+    });
+
+    let elements = read_elements(&bytes);
+    let position = |id, value: Option<&str>| elements.iter()
+        .position(|(e, v)| e.id == id && value.is_none_or(|value| *v == Some(String(value.to_string()))))
+        .unwrap();
+    assert!(position(0x1254_C367, None) < position(ebml::ID_CLUSTER, None)); // Tags
+    let strings = |values: &[&str]| values.iter().map(|v| String(v.to_string())).collect::<Vec<_>>();
+    assert_eq!(read_values(&bytes, 0x45A3), strings(&["TITLE", "SESSION_ID", "TITLE"])); // TagName
+    assert_eq!(read_values(&bytes, 0x4487), strings(&["Tutorial", "42", "Screen"])); // TagString
+
+    // Only the track tag carries Targets/TagTrackUID, naming the track's TrackUID.
+    assert_eq!(elements.iter().filter(|(e, _)| e.id == 0x63C0).count(), 1);
+    let targets = position(0x63C0, None);
+    assert!(targets > position(0x4487, Some("42")) && targets < position(0x4487, Some("Screen")));
+    assert_eq!(read_values(&bytes, 0x63C5), read_values(&bytes, 0x73C5));
+}
+
+#[test]
+fn opus_codec_private() {
+    use ebml::Value::{String, Uint};
+    use mux::Track;
+
+    let head = mux::opus_head(48_000, 2, 312).unwrap();
+    let bytes = mux_to_vec(mux::SegmentMode::Live, |segment| {
+        let mut at = segment.add_audio_track(48_000, 2, None, mux::AudioCodecId::Opus).unwrap();
+        at.set_codec_private(&head).unwrap();
+        at.set_codec_delay(312 * 1_000_000_000 / 48_000);
+        at.set_seek_pre_roll(mux::OPUS_SEEK_PRE_ROLL_NS);
+        at.add_frame(&[0xFC; 20], 0, true).unwrap();
+    });
+
+    assert_eq!(read_values(&bytes, 0x86), vec![String("A_OPUS".to_string())]); // CodecID
+    assert_eq!(read_payloads(&bytes, 0x63A2), vec![head]); // CodecPrivate
+    assert_eq!(read_values(&bytes, 0x56AA), vec![Uint(6_500_000)]); // CodecDelay
+    assert_eq!(read_values(&bytes, 0x56BB), vec![Uint(80_000_000)]); // SeekPreRoll
+}
+
+#[test]
+fn hdr_colour() {
+    use ebml::Value::{Float, Uint};
+    use mux::{ChromaSiting, Colour, MasteringMetadata, MatrixCoefficients, Primaries, Range,
+              TransferCharacteristics, Track};
+
+    let bytes = mux_to_vec(mux::SegmentMode::Live, |segment| {
+        let mut vt = add_test_video(segment, mux::VideoCodecId::VP9);
+        let mut colour = Colour {
+            matrix_coefficients: Some(MatrixCoefficients::Bt2020NonConstantLuminance),
+            bits_per_channel: Some(10),
+            chroma_subsampling: Some((1, 1)),
+            chroma_siting: Some((ChromaSiting::Collocated, ChromaSiting::Half)),
+            range: Some(Range::Broadcast),
+            transfer_characteristics: Some(TransferCharacteristics::SmpteSt2084),
+            primaries: Some(Primaries::Bt2020),
+            max_cll: Some(1000),
+            max_fall: Some(400),
+            mastering_metadata: Some(MasteringMetadata {
+                red: Some((0.708, 0.292)),
+                green: Some((0.170, 0.797)),
+                blue: Some((0.131, 0.046)),
+                white_point: Some((0.3127, 0.3290)),
+                luminance_max: Some(10_000.5),
+                luminance_min: Some(0.0001),
+            }),
+            ..Colour::default()
+        };
+        assert!(matches!(vt.set_colour(&colour), Err(Error::InvalidArgument(_))));
+        colour.mastering_metadata.as_mut().unwrap().luminance_max = Some(1000.0);
+        vt.set_colour(&colour).unwrap();
+        vt.add_frame(&[1; 10], 0, true).unwrap();
+    });
+
+    let uints = [
+        (0x55B1, 9), // MatrixCoefficients
+        (0x55B2, 10), // BitsPerChannel
+        (0x55B3, 1), // ChromaSubsamplingHorz
+        (0x55B4, 1), // ChromaSubsamplingVert
+        (0x55B7, 1), // ChromaSitingHorz
+        (0x55B8, 2), // ChromaSitingVert
+        (0x55B9, 1), // Range
+        (0x55BA, 16), // TransferCharacteristics
+        (0x55BB, 9), // Primaries
+        (0x55BC, 1000), // MaxCLL
+        (0x55BD, 400), // MaxFALL
+    ];
+    for &(id, value) in &uints {
+        assert_eq!(read_values(&bytes, id), vec![Uint(value)], "{:#X}", id);
+    }
+    // Chromaticities and luminance are stored as 32 bit floats.
+    let floats = [
+        (0x55D1, 0.708), (0x55D2, 0.292), (0x55D3, 0.170), (0x55D4, 0.797), (0x55D5, 0.131),
+        (0x55D6, 0.046), (0x55D7, 0.3127), (0x55D8, 0.3290), (0x55D9, 1000.0), (0x55DA, 0.0001),
+    ];
+    for &(id, value) in &floats {
+        match read_values(&bytes, id)[..] {
+            [Float(read)] => assert!((read - value).abs() < 1e-6, "{:#X}: {}", id, read),
+            ref other => panic!("{:#X}: {:?}", id, other),
+        }
+    }
+}
+
+#[test]
+fn video_display() {
+    use ebml::Value::{Binary, Float, Uint};
+    use mux::{Crop, Projection, ProjectionType, StereoMode, Track};
+
+    let bytes = mux_to_vec(mux::SegmentMode::Live, |segment| {
+        let mut vt = segment.add_video_track(640, 480, None, mux::VideoCodecId::VP9).unwrap();
+        vt.set_display_size(320, 180);
+        vt.set_crop(Crop { left: 0, right: 0, top: 30, bottom: 30 });
+        vt.set_stereo_mode(StereoMode::TopBottomLeftFirst).unwrap();
+        vt.set_alpha_mode(true).unwrap();
+        vt.set_frame_rate(30.0);
+        vt.set_projection(&Projection {
+            projection_type: ProjectionType::Equirectangular,
+            private: vec![0; 20],
+            yaw: 90.0,
+            ..Projection::default()
+        }).unwrap();
+        vt.add_frame(&[1; 10], 0, true).unwrap();
+    });
+
+    let expected = [
+        (0x54B0, Uint(320)), // DisplayWidth
+        (0x54BA, Uint(180)), // DisplayHeight
+        (0x54BB, Uint(30)), // PixelCropTop
+        (0x54AA, Uint(30)), // PixelCropBottom
+        (0x53B8, Uint(3)), // StereoMode
+        (0x53C0, Uint(1)), // AlphaMode
+        (0x7671, Uint(1)), // ProjectionType
+        (0x7672, Binary(20)), // ProjectionPrivate
+        (0x7673, Float(90.0)), // ProjectionPoseYaw
+    ];
+    for (id, value) in expected {
+        assert_eq!(read_values(&bytes, id), vec![value], "{:#X}", id);
+    }
+}
+
+#[test]
+fn block_additional() {
+    use mux::{ChunkMode, RotationPolicy, Track};
+    use std::fs;
+
+    let dir = std::env::temp_dir().join(format!("webm-additional-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    let (mut writer, chunks) = mux::WebmWriter::with_channel(dir.to_str().unwrap(), "alpha", ChunkMode::SelfContained).unwrap();
+    writer.set_rotation(RotationPolicy { duration_ns: Some(1_000_000_000), size_bytes: None, keyframes_only: false });
+    let segment = mux::Segment::new(writer).unwrap();
+    let mut vt = add_test_video(&segment, mux::VideoCodecId::VP8);
+    vt.set_alpha_mode(true).unwrap();
+    for i in 0..60u64 {
+        vt.add_frame_with_additional(&[i as u8; 30], &[0xAA; 12], 1, i * 50_000_000, i % 10 == 0).unwrap();
+    }
+    assert!(segment.finalize(None).is_ok());
+
+    let chunks: Vec<_> = chunks.iter().collect();
+    assert_eq!(chunks.len(), 3);
+    let mut total = 0;
+    for chunk in &chunks {
+        let bytes = fs::read(&chunk.path).unwrap();
+        let blocks = read_blocks(&bytes);
+        assert!(blocks[0].keyframe);
+        assert_eq!(blocks.iter().filter(|b| b.keyframe).count(), 2);
+        // Every block carries the alpha payload as its BlockAdditional.
+        assert_eq!(read_payloads(&bytes, 0xA5), vec![vec![0xAA; 12]; blocks.len()]);
+        for block in &blocks {
+            assert_eq!(block.data, vec![(block.timestamp_ns / 50_000_000) as u8; 30]);
+        }
+        total += blocks.len();
+    }
+    assert_eq!(total, 60);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(feature = "libwebm")]
+#[test]
+fn text_tracks() {
+    use mux::{TextTrackKind, Track};
+    use std::io::Cursor;
+
+    let bytes = mux_to_vec(mux::SegmentMode::Live, |segment| {
+        let mut vt = add_test_video(segment, mux::VideoCodecId::VP8);
+        let mut captions = segment.add_text_track(TextTrackKind::Captions, Some("ger")).unwrap();
+        let mut events = segment.add_text_track(TextTrackKind::Metadata, None).unwrap();
+        for i in 0..20u64 {
+            let ts = i * 100_000_000;
+            vt.add_frame(&[1; 10], ts, i % 10 == 0).unwrap();
+            if i % 5 == 0 {
+                captions.add_cue(ts, 400_000_000, format!("line {}", i).as_bytes()).unwrap();
+            }
+            if i == 7 {
+                events.add_cue(ts, 0, br#"{"key":"F5"}"#).unwrap();
+            }
+        }
+    });
+
+    let mut demuxer = demux::Demuxer::new(Cursor::new(bytes)).unwrap();
+    let tracks = demuxer.tracks().to_vec();
+    assert_eq!(tracks.len(), 3);
+    assert_eq!(tracks[1].codec_id, "D_WEBVTT/CAPTIONS");
+    assert_eq!(tracks[1].track_type, demux::TrackType::Subtitle);
+    assert_eq!(tracks[1].language.as_deref(), Some("ger"));
+    assert_eq!(tracks[2].codec_id, "D_WEBVTT/METADATA");
+    assert_eq!(tracks[2].track_type, demux::TrackType::Metadata);
+
+    let frames: Vec<_> = demuxer.frames().collect::<Result<_>>().unwrap();
+    let cues: Vec<_> = frames.iter().filter(|f| f.track == tracks[1].number).collect();
+    assert_eq!(cues.len(), 4);
+    assert_eq!(cues[2].data, b"line 10");
+    assert_eq!(cues[2].timestamp_ns, 1_000_000_000);
+    let event = frames.iter().find(|f| f.track == tracks[2].number).unwrap();
+    assert_eq!(event.timestamp_ns, 700_000_000);
+}
+
+#[test]
+fn generic_frames() {
+    use ebml::Value::{Int, Uint};
+    use mux::{Frame, Track};
+
+    let mut video_track = 0;
+    let bytes = mux_to_vec(mux::SegmentMode::Live, |segment| {
+        let mut vt = add_test_video(segment, mux::VideoCodecId::VP8);
+        let mut at = segment.add_audio_track(48_000, 2, None, mux::AudioCodecId::Opus).unwrap();
+        for i in 0..10u64 {
+            let ts = i * 20_000_000;
+            let video = Frame::new(&[2; 16], ts).keyframe(i == 0).duration_ns(20_000_000);
+            vt.add_generic_frame(&if i == 5 { video.reference_timestamp_ns(0) } else { video }).unwrap();
+            let mut audio = Frame::new(&[1; 8], ts);
+            if i == 9 {
+                audio = audio.duration_ns(20_000_000).discard_padding_ns(6_500_000);
+            }
+            at.add_generic_frame(&audio).unwrap();
+        }
+        match at.add_generic_frame(&Frame::new(&[1; 8], 0)) {
+            Err(Error::NonMonotonicTimestamp { timestamp_ns: 0, last_timestamp_ns }) => assert!(last_timestamp_ns > 0),
+            other => panic!("{:?}", other),
+        }
+        video_track = vt.number();
+    });
+
+    assert_eq!(read_values(&bytes, 0x75A2), vec![Int(6_500_000)]); // DiscardPadding
+    assert_eq!(read_values(&bytes, 0x9B), vec![Uint(20); 11]); // BlockDuration
+
+    let blocks = read_blocks(&bytes);
+    assert_eq!(blocks.len(), 20);
+    let video: Vec<_> = blocks.iter().filter(|b| b.track == video_track).collect();
+    assert_eq!(video.len(), 10);
+    assert!(video[0].keyframe);
+    assert!(video[1..].iter().all(|b| !b.keyframe));
+}
+
+#[test]
+fn lace_sizes() {
+    use mux::{lace, Lacing};
+
+    let frames: [&[u8]; 3] = [&[1; 300], &[2; 10], &[3; 5]];
+    let xiph = lace(&frames, Lacing::Xiph).unwrap();
+    assert_eq!(xiph[..4], [2, 255, 45, 10]);
+    assert_eq!(xiph.len(), 4 + 315);
+    // 300 as a two-byte size, then 10 - 300 biased by 8191.
+    let ebml = lace(&frames, Lacing::Ebml).unwrap();
+    assert_eq!(ebml[..5], [2, 0x41, 0x2C, 0x5E, 0xDD]);
+    assert_eq!(ebml[5..], xiph[4..]);
+    assert_eq!(lace(&frames, Lacing::FixedSize), None);
+    assert_eq!(lace(&[&[1; 4], &[2; 4]], Lacing::FixedSize).unwrap(), [1, 1, 1, 1, 1, 2, 2, 2, 2]);
+    assert_eq!(lace(&[&[7; 3]], Lacing::Ebml).unwrap(), [0, 7, 7, 7]);
+    assert_eq!(lace(&[], Lacing::Xiph), None);
+    assert_eq!(lace(&[&[0u8][..]; 257], Lacing::Xiph), None);
+}
+
+#[test]
+fn laced_frames() {
+    use mux::{lace, Frame, Lacing, Track};
+
+    let frames: [&[u8]; 3] = [&[1; 30], &[2; 30], &[3; 25]];
+    let lacings = [Lacing::Xiph, Lacing::FixedSize, Lacing::Ebml];
+    let mut payloads = Vec::new();
+    let bytes = mux_to_vec(mux::SegmentMode::Live, |segment| {
+        let mut at = segment.add_audio_track(48_000, 2, None, mux::AudioCodecId::Opus).unwrap();
+        for (i, &lacing) in lacings.iter().enumerate() {
+            let frames = if lacing == Lacing::FixedSize { &frames[..2] } else { &frames[..] };
+            payloads.push(lace(frames, lacing).unwrap());
+            let frame = Frame::new(&payloads[i], i as u64 * 60_000_000).laced(lacing);
+            // The last one goes into a BlockGroup.
+            at.add_generic_frame(&if i == 2 { frame.duration_ns(60_000_000) } else { frame }).unwrap();
+        }
+    });
+
+    let blocks = read_blocks(&bytes);
+    assert_eq!(blocks.iter().map(|b| b.lacing).collect::<Vec<_>>(), vec![1, 2, 3]);
+    assert_eq!(blocks.iter().map(|b| &b.data).collect::<Vec<_>>(), payloads.iter().collect::<Vec<_>>());
+    assert!(blocks[..2].iter().all(|b| b.keyframe));
+}
+
+#[cfg(feature = "libwebm")]
+#[test]
+fn demux_laced_frames() {
+    use mux::{lace, Frame, Lacing, Track};
+    use std::io::Cursor;
+
+    let frames: [&[u8]; 3] = [&[1; 300], &[2; 30], &[3; 25]];
+    let bytes = mux_to_vec(mux::SegmentMode::Live, |segment| {
+        let mut at = segment.add_audio_track(48_000, 2, None, mux::AudioCodecId::Opus).unwrap();
+        for (i, &lacing) in [Lacing::Xiph, Lacing::Ebml].iter().enumerate() {
+            let data = lace(&frames, lacing).unwrap();
+            at.add_generic_frame(&Frame::new(&data, i as u64 * 60_000_000).laced(lacing)).unwrap();
+        }
+    });
+
+    let mut demuxer = demux::Demuxer::new(Cursor::new(bytes)).unwrap();
+    let read: Vec<_> = demuxer.frames().collect::<Result<_>>().unwrap();
+    let data: Vec<&[u8]> = read.iter().map(|f| &f.data[..]).collect();
+    assert_eq!(data, [frames[0], frames[1], frames[2], frames[0], frames[1], frames[2]]);
+}
+
+#[test]
+fn av1_track() {
+    use ebml::Value::String;
+    use mux::Track;
+    use std::io::Cursor;
+
+    let segment = mux::Segment::new(mux::Writer::new(Cursor::new(Vec::new()))).unwrap();
+    let mut vt = add_test_video(&segment, mux::VideoCodecId::AV1);
+    match vt.add_frame(&[0x12, 0x00], 0, true) {
+        Err(Error::BadCodecId(id)) => assert_eq!(id, "V_AV1"),
+        other => panic!("{:?}", other),
+    }
+    // The pure-Rust muxer passes the cause on from finalize as well.
+    #[cfg(not(feature = "libwebm"))]
+    match segment.finalize(None).map_err(|e| e.into_parts().0) {
+        Err(Error::BadCodecId(id)) => assert_eq!(id, "V_AV1"),
+        other => panic!("{:?}", other.map(|_| ())),
+    }
+
+    let config = [0x81, 0x08, 0x0C, 0x00];
+    let bytes = mux_to_vec(mux::SegmentMode::Live, |segment| {
+        let mut vt = add_test_video(segment, mux::VideoCodecId::AV1);
+        vt.set_codec_private(&config).unwrap();
+        vt.add_frame(&[0x12, 0x00], 0, true).unwrap();
+    });
+
+    assert_eq!(read_values(&bytes, 0x86), vec![String("V_AV1".to_string())]); // CodecID
+    assert_eq!(read_payloads(&bytes, 0x63A2), vec![config.to_vec()]); // CodecPrivate
+}
+
+#[test]
+fn full_disk() {
+    use mux::Track;
+    use std::io::{self, Write};
+
+    struct FullDisk(usize);
+    impl Write for FullDisk {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.0 < buf.len() {
+                return Err(io::Error::other("no space left"));
+            }
+            self.0 -= buf.len();
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let segment = mux::Segment::new(mux::Writer::new_non_seekable(FullDisk(4096))).unwrap();
+    let mut vt = add_test_video(&segment, mux::VideoCodecId::VP8);
+    let failed = (0..1000u64).find_map(|i| vt.add_frame(&[1; 100], i * 40_000_000, i % 10 == 0).err());
+    match failed {
+        Some(Error::Io(e)) => assert_eq!(e.to_string(), "no space left"),
+        other => panic!("{:?}", other),
+    }
+    assert!(matches!(vt.add_frame(&[1; 100], 40_000_000_000, true), Err(Error::Io(_))));
+    match segment.finalize(None) {
+        Err(e) => assert!(matches!(e.error(), Error::Io(_))),
+        Ok(_) => panic!("finalize succeeded on a full disk"),
+    }
+}
+
+#[test]
+fn unfinalized_drop() {
+    use mux::Track;
+    use std::cell::RefCell;
+    use std::io::{self, Cursor, Seek, SeekFrom, Write};
+    use std::rc::Rc;
+
+    #[derive(Clone)]
+    struct Shared(Rc<RefCell<Cursor<Vec<u8>>>>);
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    impl Seek for Shared {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.0.borrow_mut().seek(pos)
+        }
+    }
+
+    // Dropping a segment, as a `?` or a panic after `with_mode` does, frees
+    // it without writing the closing elements.
+    let sink = Shared(Rc::new(RefCell::new(Cursor::new(Vec::new()))));
+    let segment = mux::Segment::with_mode(mux::Writer::new(sink.clone()), mux::SegmentMode::File).unwrap();
+    let mut vt = add_test_video(&segment, mux::VideoCodecId::VP8);
+    for i in 0..100u64 {
+        vt.add_frame(&[1; 100], i * 40_000_000, i % 25 == 0).unwrap();
+    }
+    let written = sink.0.borrow().get_ref().len();
+    drop(segment);
+    assert_eq!(Rc::strong_count(&sink.0), 1);
+    assert_eq!(sink.0.borrow().get_ref().len(), written);
+}
+
+#[cfg(feature = "libwebm")]
+#[test]
+fn foreign_track() {
+    use std::io::Cursor;
+
+    let segment = mux::Segment::with_mode(mux::Writer::new(Cursor::new(Vec::new())), mux::SegmentMode::File).unwrap();
+    let other = mux::Segment::new(mux::Writer::new(Cursor::new(Vec::new()))).unwrap();
+    let vt = add_test_video(&segment, mux::VideoCodecId::VP8);
+    let foreign = other.add_video_track(64, 48, None, mux::VideoCodecId::VP8).unwrap();
+    assert!(matches!(segment.set_cues_track(&foreign), Err(Error::InvalidTrack)));
+    assert!(matches!(segment.add_track_tag(&foreign, &[("TITLE", "x")]), Err(Error::InvalidTrack)));
+    segment.set_cues_track(&vt).unwrap();
+    assert!(matches!(segment.add_video_track(64, 48, Some(1), mux::VideoCodecId::VP8), Err(Error::InvalidTrack)));
+}
+
+#[test]
+fn multiplexer_threads() {
+    use std::io::Cursor;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn assert_send_sync<T: Send + Sync>(_: &T) {}
+
+    let segment = mux::Segment::new(mux::Writer::new(Cursor::new(Vec::new()))).unwrap();
+    let muxer = Arc::new(mux::Multiplexer::new(segment));
+    assert_send_sync(&*muxer);
+    // Wait for both producers however far apart they get.
+    muxer.set_max_interleave_delay(u64::MAX);
+    let video = muxer.add_video_track(64, 48, None, mux::VideoCodecId::VP8).unwrap();
+    let audio = muxer.add_audio_track(48_000, 1, None, mux::AudioCodecId::Opus).unwrap();
+
+    let producers = vec![
+        thread::spawn(move || for i in 0..50u64 {
+            video.add_frame(&[2; 30], i * 40_000_000, i % 25 == 0).unwrap();
+            thread::sleep(Duration::from_micros(300));
+        }),
+        thread::spawn(move || for i in 0..100u64 {
+            audio.add_frame(&[1; 10], i * 20_000_000, true).unwrap();
+            thread::sleep(Duration::from_micros(100));
+        }),
+    ];
+    for producer in producers {
+        producer.join().unwrap();
+    }
+    let muxer = Arc::try_unwrap(muxer).ok().unwrap();
+    let bytes = muxer.finalize(None).ok().unwrap().into_inner().into_inner();
+
+    let blocks = read_blocks(&bytes);
+    assert_eq!(blocks.len(), 150);
+    assert!(blocks.windows(2).all(|w| w[0].timestamp_ns <= w[1].timestamp_ns));
+}
+
+#[test]
+fn interleave_delay() {
+    use std::io::Cursor;
+
+    let segment = mux::Segment::new(mux::Writer::new(Cursor::new(Vec::new()))).unwrap();
+    let muxer = mux::Multiplexer::new(segment);
+    muxer.set_max_interleave_delay(500_000_000);
+    let video = muxer.add_video_track(64, 48, None, mux::VideoCodecId::VP8).unwrap();
+    let audio = muxer.add_audio_track(48_000, 1, None, mux::AudioCodecId::Opus).unwrap();
+    assert!(matches!(muxer.sender(1), Err(Error::InvalidArgument(_))));
+    assert!(matches!(muxer.sender(9), Err(Error::InvalidTrack)));
+
+    // The quiet video track holds audio back for at most 500 ms.
+    for i in 0..100u64 {
+        audio.add_frame(&[1; 10], i * 20_000_000, true).unwrap();
+    }
+    match video.add_frame(&[2; 30], 1_000_000_000, true) {
+        Err(Error::NonMonotonicTimestamp { last_timestamp_ns, .. }) => assert_eq!(last_timestamp_ns, 1_480_000_000),
+        other => panic!("{:?}", other),
+    }
+    video.add_frame(&[2; 30], 1_500_000_000, true).unwrap();
+    drop(video);
+    let bytes = muxer.finalize(None).ok().unwrap().into_inner().into_inner();
+
+    assert_eq!(read_blocks(&bytes).len(), 101);
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn async_sink() {
+    use mux::Track;
+    use std::thread;
+
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let (sink, forward) = mux::async_sink(Vec::new(), 1 << 20);
+    let muxing = thread::spawn(move || {
+        let segment = mux::Segment::new(mux::Writer::new_non_seekable(sink)).unwrap();
+        let mut vt = add_test_video(&segment, mux::VideoCodecId::VP8);
+        for i in 0..40u64 {
+            vt.add_frame(&[i as u8; 50], i * 40_000_000, i % 10 == 0).unwrap();
+        }
+        drop(segment.finalize(None).ok().unwrap());
+    });
+    let bytes = runtime.block_on(forward).unwrap();
+    muxing.join().unwrap();
+
+    let blocks = read_blocks(&bytes);
+    assert_eq!(blocks.len(), 40);
+    assert_eq!(blocks[39].data, vec![39; 50]);
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn async_sink_error() {
+    use mux::Track;
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    struct Refuse;
+    impl tokio::io::AsyncWrite for Refuse {
+        fn poll_write(self: Pin<&mut Self>, _: &mut Context<'_>, _: &[u8]) -> Poll<io::Result<usize>> {
+            Poll::Ready(Err(io::Error::other("upload failed")))
+        }
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let (sink, forward) = mux::async_sink(Refuse, 1 << 20);
+    let segment = mux::Segment::new(mux::Writer::new_non_seekable(sink)).unwrap();
+    let mut vt = add_test_video(&segment, mux::VideoCodecId::VP8);
+    vt.add_frame(&[0; 50], 0, true).unwrap();
+    assert!(runtime.block_on(forward).is_err());
+
+    let mut failed = false;
+    for i in 1..100u64 {
+        if let Err(e) = vt.add_frame(&[0; 50], i * 40_000_000, false) {
+            assert!(matches!(e, Error::Io(ref e) if e.kind() == io::ErrorKind::BrokenPipe));
+            failed = true;
+            break;
+        }
+    }
+    assert!(failed);
+    assert!(matches!(segment.finalize(None).map_err(|e| e.into_parts().0), Err(Error::Io(_))));
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn async_sink_full() {
+    use mux::Track;
+    use std::io;
+
+    // Nothing polls the forwarding task, so the sink fills up.
+    let (sink, _forward) = mux::async_sink(Vec::new(), 4096);
+    let segment = mux::Segment::new(mux::Writer::new_non_seekable(sink)).unwrap();
+    let mut vt = add_test_video(&segment, mux::VideoCodecId::VP8);
+    let mut added = 0;
+    let error = loop {
+        match vt.add_frame(&[0; 500], added * 40_000_000, added % 10 == 0) {
+            Ok(()) => added += 1,
+            Err(e) => break e,
+        }
+        assert!(added < 100, "the sink took more than its limit");
+    };
+    assert!(matches!(error, Error::Io(ref e) if e.kind() == io::ErrorKind::WouldBlock));
+    assert!(added < 8);
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn async_sink_level() {
+    use mux::Track;
+
+    // Skipping frames while the sink is nearly full keeps the recording
+    // going until the destination catches up.
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let (sink, forward) = mux::async_sink(Vec::new(), 4096);
+    let level = sink.level();
+    assert_eq!((level.buffered(), level.limit()), (0, 4096));
+    let segment = mux::Segment::new(mux::Writer::new_non_seekable(sink)).unwrap();
+    let mut vt = add_test_video(&segment, mux::VideoCodecId::VP8);
+    let mut added = 0;
+    for i in 0..100u64 {
+        if level.has_room(2 * 500 + 100) {
+            vt.add_frame(&[0; 500], i * 40_000_000, true).unwrap();
+            added += 1;
+        }
+    }
+    assert!(added < 100);
+    assert!(level.buffered() <= level.limit());
+
+    let forwarding = std::thread::spawn(move || runtime.block_on(forward));
+    drop(segment.finalize(None).ok().unwrap());
+    let bytes = forwarding.join().unwrap().unwrap();
+    assert_eq!(read_blocks(&bytes).len(), added);
+    assert_eq!(level.buffered(), 0);
 }
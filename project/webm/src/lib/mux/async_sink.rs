@@ -0,0 +1,192 @@
+//! Muxing into a `tokio::io::AsyncWrite`, such as an upload stream, from a
+//! thread that must not wait on the destination.
+
+use std::future::Future;
+use std::io::{self, Write};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::AsyncWrite;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+/// The `Write` end of `async_sink`. Every write is passed on to the
+/// `Forward` task without waiting for it, so muxing never blocks on the
+/// destination; bytes the destination has not taken yet are buffered in
+/// memory, up to the limit given to `async_sink`.
+///
+/// A write that would take the buffer over the limit fails with
+/// `WouldBlock` instead, which the muxer reports as `Error::Io`. The muxer
+/// does not retry, so that ends the recording for good: every later frame
+/// and `finalize` fail too. To ride out a stall in the destination instead,
+/// check `level` before adding each frame and skip or hold back frames
+/// while the buffer is nearly full.
+///
+/// The sink can not seek, so wrap it with `Writer::new_non_seekable` and
+/// mux in live mode.
+pub struct AsyncSink {
+    sender: UnboundedSender<Vec<u8>>,
+    buffered: Arc<AtomicUsize>,
+    limit: usize,
+}
+
+impl AsyncSink {
+    /// A handle on how full the buffer is, which stays usable after the
+    /// sink has been handed to the muxer.
+    pub fn level(&self) -> SinkLevel {
+        SinkLevel { buffered: self.buffered.clone(), limit: self.limit }
+    }
+}
+
+impl Write for AsyncSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.buffered.load(Ordering::Acquire) + buf.len() > self.limit {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "the async sink buffer is full"));
+        }
+        self.buffered.fetch_add(buf.len(), Ordering::AcqRel);
+        self.sender.send(buf.to_vec())
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "the async sink task has stopped"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// How many bytes an `AsyncSink` holds that the destination has not taken
+/// yet.
+#[derive(Clone, Debug)]
+pub struct SinkLevel {
+    buffered: Arc<AtomicUsize>,
+    limit: usize,
+}
+
+impl SinkLevel {
+    pub fn buffered(&self) -> usize {
+        self.buffered.load(Ordering::Acquire)
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Whether `len` more bytes fit in the buffer. The muxer may write a
+    /// frame only once the next one arrives, and adds block and cluster
+    /// headers to it, so leave room for more than the frame about to be
+    /// added.
+    pub fn has_room(&self, len: usize) -> bool {
+        self.buffered() + len <= self.limit
+    }
+}
+
+/// Copies what is written to an `AsyncSink` into the async destination.
+/// Resolves to the destination, flushed but not shut down, once the sink
+/// has been dropped and every byte written, or to the first error from the
+/// destination. After an error the sink's writes fail with `BrokenPipe`,
+/// which the muxer reports as `Error::Io`.
+pub struct Forward<A> {
+    receiver: UnboundedReceiver<Vec<u8>>,
+    buffered: Arc<AtomicUsize>,
+    dest: Option<A>,
+    chunk: Vec<u8>,
+    written: usize,
+    unflushed: bool,
+}
+
+/// Returns a sink for the muxer and the future that moves its bytes into
+/// `dest`, buffering at most `limit` bytes the destination has not taken
+/// yet. Spawn the future on the runtime that owns `dest` and hand the sink
+/// to `Writer::new_non_seekable`:
+///
+/// ```no_run
+/// # extern crate tokio;
+/// # extern crate webm;
+/// # use webm::mux::{self, Track};
+/// # use tokio::io::AsyncWrite;
+/// # fn run<A>(upload: A, runtime: &tokio::runtime::Handle) -> webm::Result<()>
+/// #     where A: AsyncWrite + Unpin + Send + 'static {
+/// // Room for several seconds of video, so a short stall in the upload does
+/// // not end the recording.
+/// let (sink, forward) = mux::async_sink(upload, 16 << 20);
+/// let level = sink.level();
+/// let task = runtime.spawn(forward);
+///
+/// let segment = mux::Segment::new(mux::Writer::new_non_seekable(sink))?;
+/// let mut video = segment.add_video_track(640, 480, None, mux::VideoCodecId::VP9)?;
+/// let frame = [0; 100];
+/// if level.has_room(4 * frame.len() + 1024) {
+///     video.add_frame(&frame, 0, true)?;
+/// }
+/// drop(segment.finalize(None)?);
+///
+/// let upload = runtime.block_on(task).unwrap()?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn async_sink<A: AsyncWrite + Unpin>(dest: A, limit: usize) -> (AsyncSink, Forward<A>) {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let buffered = Arc::new(AtomicUsize::new(0));
+    let forward = Forward {
+        receiver,
+        buffered: buffered.clone(),
+        dest: Some(dest),
+        chunk: Vec::new(),
+        written: 0,
+        unflushed: false,
+    };
+    (AsyncSink { sender, buffered, limit }, forward)
+}
+
+impl<A: AsyncWrite + Unpin> Forward<A> {
+    fn poll_forward(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let dest = self.dest.as_mut().expect("Forward polled after completion");
+        loop {
+            while self.written < self.chunk.len() {
+                match Pin::new(&mut *dest).poll_write(cx, &self.chunk[self.written..]) {
+                    Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                    Poll::Ready(Ok(n)) => {
+                        self.written += n;
+                        self.unflushed = true;
+                    }
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+            self.buffered.fetch_sub(self.chunk.len(), Ordering::AcqRel);
+            self.chunk.clear();
+            self.written = 0;
+
+            match self.receiver.poll_recv(cx) {
+                Poll::Ready(Some(chunk)) => self.chunk = chunk,
+                Poll::Ready(None) => break,
+                // The muxer has gone quiet, so push what we have onwards.
+                Poll::Pending if self.unflushed => {
+                    match Pin::new(&mut *dest).poll_flush(cx) {
+                        Poll::Ready(Ok(())) => self.unflushed = false,
+                        Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                        Poll::Pending => {}
+                    }
+                    return Poll::Pending;
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Pin::new(dest).poll_flush(cx)
+    }
+}
+
+impl<A: AsyncWrite + Unpin> Future for Forward<A> {
+    type Output = io::Result<A>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.poll_forward(cx) {
+            Poll::Ready(Ok(())) => Poll::Ready(Ok(self.dest.take().unwrap())),
+            Poll::Ready(Err(e)) => {
+                self.receiver.close();
+                Poll::Ready(Err(e))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,385 @@
+//! Builders for the CodecPrivate blobs Opus, Vorbis and AV1 tracks need:
+//! the OpusHead, the Vorbis header packets and the AV1 `av1C` record.
+
+/// The 80 ms seek pre-roll the Matroska Opus mapping asks for.
+pub const OPUS_SEEK_PRE_ROLL_NS: u64 = 80_000_000;
+
+/// Stream count, coupled stream count and channel mapping libopus' surround
+/// encoder uses for 3 to 8 channels (mapping family 1, Vorbis order).
+const SURROUND_MAPPINGS: [(u8, u8, &[u8]); 6] = [
+    (2, 1, &[0, 2, 1]),
+    (2, 2, &[0, 1, 2, 3]),
+    (3, 2, &[0, 4, 1, 2, 3]),
+    (4, 2, &[0, 4, 1, 2, 3, 5]),
+    (4, 3, &[0, 4, 1, 2, 3, 5, 6]),
+    (5, 3, &[0, 6, 1, 2, 3, 4, 5, 7]),
+];
+
+/// Builds the OpusHead identification header (RFC 7845, section 5.1) used
+/// as CodecPrivate of an Opus track.
+///
+/// `sample_rate` is the input rate, informational only; `pre_skip` counts
+/// 48 kHz samples. Mono and stereo use mapping family 0; 3 to 8 channels
+/// use family 1 with the stream layout libopus' surround encoder picks.
+/// Returns None for any other channel count.
+pub fn opus_head(sample_rate: u32, channels: u8, pre_skip: u16) -> Option<Vec<u8>> {
+    let mapping = match channels {
+        1 | 2 => None,
+        3..=8 => Some(SURROUND_MAPPINGS[channels as usize - 3]),
+        _ => return None,
+    };
+
+    let mut head = Vec::with_capacity(21 + channels as usize);
+    head.extend_from_slice(b"OpusHead");
+    head.push(1);
+    head.push(channels);
+    head.extend_from_slice(&pre_skip.to_le_bytes());
+    head.extend_from_slice(&sample_rate.to_le_bytes());
+    head.extend_from_slice(&0i16.to_le_bytes());
+    match mapping {
+        None => head.push(0),
+        Some((streams, coupled, table)) => {
+            head.push(1);
+            head.push(streams);
+            head.push(coupled);
+            head.extend_from_slice(table);
+        }
+    }
+    Some(head)
+}
+
+/// Packs the three Vorbis setup headers (identification, comment, setup)
+/// into CodecPrivate using Xiph lacing, as the Matroska Vorbis mapping
+/// requires.
+pub fn vorbis_codec_private(identification: &[u8], comment: &[u8], setup: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(identification.len() + comment.len() + setup.len() + 16);
+    data.push(2);
+    for header in &[identification, comment] {
+        data.extend(::std::iter::repeat_n(255, header.len() / 255));
+        data.push((header.len() % 255) as u8);
+    }
+    data.extend_from_slice(identification);
+    data.extend_from_slice(comment);
+    data.extend_from_slice(setup);
+    data
+}
+
+const OBU_SEQUENCE_HEADER: u8 = 1;
+
+/// Builds the `av1C` AV1CodecConfigurationRecord used as CodecPrivate of an
+/// AV1 track, from the encoder's sequence header.
+///
+/// `data` holds OBUs in the low-overhead format (each with a size field),
+/// such as the first temporal unit of the stream or a lone sequence header
+/// OBU. Returns None if there is no sequence header or it can't be parsed.
+pub fn av1_codec_private(data: &[u8]) -> Option<Vec<u8>> {
+    let (obu, payload) = find_obu(data, OBU_SEQUENCE_HEADER)?;
+    let seq = SequenceHeader::parse(payload)?;
+
+    let mut config = Vec::with_capacity(4 + obu.len());
+    config.push(0x81); // marker, version 1
+    config.push(seq.profile << 5 | seq.level_idx_0);
+    config.push((seq.tier_0 as u8) << 7 |
+                (seq.high_bitdepth as u8) << 6 |
+                (seq.twelve_bit as u8) << 5 |
+                (seq.monochrome as u8) << 4 |
+                (seq.subsampling_x as u8) << 3 |
+                (seq.subsampling_y as u8) << 2 |
+                seq.chroma_sample_position);
+    config.push(0); // no initial presentation delay
+    config.extend_from_slice(obu);
+    Some(config)
+}
+
+/// Returns the first OBU of type `obu_type`, whole and as its payload.
+fn find_obu(mut data: &[u8], obu_type: u8) -> Option<(&[u8], &[u8])> {
+    while !data.is_empty() {
+        let header = data[0];
+        let has_extension = header & 0x04 != 0;
+        if header & 0x02 == 0 {
+            // Without a size field the OBU would run to the end of the data,
+            // which Matroska does not allow.
+            return None;
+        }
+        let header_len = 1 + has_extension as usize;
+        let (size, size_len) = read_leb128(data.get(header_len..)?)?;
+        let end = (header_len + size_len).checked_add(size as usize)?;
+        let obu = data.get(..end)?;
+        if (header >> 3) & 0x0F == obu_type {
+            return Some((obu, &obu[header_len + size_len..]));
+        }
+        data = &data[end..];
+    }
+    None
+}
+
+fn read_leb128(bytes: &[u8]) -> Option<(u64, usize)> {
+    let mut value = 0u64;
+    for (i, &b) in bytes.iter().take(8).enumerate() {
+        value |= u64::from(b & 0x7F) << (7 * i);
+        if b & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn bits(&mut self, n: u32) -> Option<u32> {
+        let mut value = 0u32;
+        for _ in 0..n {
+            let byte = *self.data.get(self.pos / 8)?;
+            let bit = (byte >> (7 - self.pos % 8)) & 1;
+            value = (value << 1) | u32::from(bit);
+            self.pos += 1;
+        }
+        Some(value)
+    }
+
+    fn flag(&mut self) -> Option<bool> {
+        self.bits(1).map(|b| b == 1)
+    }
+
+    fn uvlc(&mut self) -> Option<u32> {
+        let mut leading_zeros = 0;
+        while !self.flag()? {
+            leading_zeros += 1;
+            if leading_zeros >= 32 {
+                return Some(u32::MAX);
+            }
+        }
+        Some(self.bits(leading_zeros)? + ((1u64 << leading_zeros) - 1) as u32)
+    }
+}
+
+/// The sequence header fields `av1C` repeats.
+struct SequenceHeader {
+    profile: u8,
+    level_idx_0: u8,
+    tier_0: bool,
+    high_bitdepth: bool,
+    twelve_bit: bool,
+    monochrome: bool,
+    subsampling_x: bool,
+    subsampling_y: bool,
+    chroma_sample_position: u8,
+}
+
+impl SequenceHeader {
+    /// Walks sequence_header_obu() of the AV1 spec (5.5) up to the end of
+    /// color_config().
+    fn parse(payload: &[u8]) -> Option<SequenceHeader> {
+        let mut r = BitReader { data: payload, pos: 0 };
+        let profile = r.bits(3)? as u8;
+        let _still_picture = r.flag()?;
+        let reduced_still_picture_header = r.flag()?;
+
+        let level_idx_0;
+        let mut tier_0 = false;
+        if reduced_still_picture_header {
+            level_idx_0 = r.bits(5)? as u8;
+        } else {
+            let mut decoder_model_info_present = false;
+            let mut buffer_delay_length = 0;
+            if r.flag()? {
+                // timing_info()
+                r.bits(32)?;
+                r.bits(32)?;
+                if r.flag()? {
+                    r.uvlc()?;
+                }
+                decoder_model_info_present = r.flag()?;
+                if decoder_model_info_present {
+                    buffer_delay_length = r.bits(5)? + 1;
+                    r.bits(32)?;
+                    r.bits(5)?;
+                    r.bits(5)?;
+                }
+            }
+            let initial_display_delay_present = r.flag()?;
+            let operating_points = r.bits(5)? + 1;
+            let mut first = None;
+            for _ in 0..operating_points {
+                r.bits(12)?;
+                let level = r.bits(5)? as u8;
+                let tier = level > 7 && r.flag()?;
+                if decoder_model_info_present && r.flag()? {
+                    r.bits(buffer_delay_length)?;
+                    r.bits(buffer_delay_length)?;
+                    r.flag()?;
+                }
+                if initial_display_delay_present && r.flag()? {
+                    r.bits(4)?;
+                }
+                first.get_or_insert((level, tier));
+            }
+            let (level, tier) = first?;
+            level_idx_0 = level;
+            tier_0 = tier;
+        }
+
+        let frame_width_bits = r.bits(4)? + 1;
+        let frame_height_bits = r.bits(4)? + 1;
+        r.bits(frame_width_bits)?;
+        r.bits(frame_height_bits)?;
+        if !reduced_still_picture_header && r.flag()? {
+            r.bits(4)?;
+            r.bits(3)?;
+        }
+        r.bits(3)?; // use_128x128_superblock, enable_filter_intra, enable_intra_edge_filter
+        if !reduced_still_picture_header {
+            r.bits(4)?; // interintra, masked compound, warped motion, dual filter
+            let enable_order_hint = r.flag()?;
+            if enable_order_hint {
+                r.bits(2)?; // jnt_comp, ref_frame_mvs
+            }
+            let force_screen_content_tools = if r.flag()? { 2 } else { r.bits(1)? };
+            if force_screen_content_tools > 0 && !r.flag()? {
+                r.bits(1)?;
+            }
+            if enable_order_hint {
+                r.bits(3)?;
+            }
+        }
+        r.bits(3)?; // superres, cdef, restoration
+
+        // color_config()
+        let high_bitdepth = r.flag()?;
+        let twelve_bit = profile == 2 && high_bitdepth && r.flag()?;
+        let monochrome = profile != 1 && r.flag()?;
+        let (mut primaries, mut transfer, mut matrix) = (2, 2, 2);
+        if r.flag()? {
+            primaries = r.bits(8)?;
+            transfer = r.bits(8)?;
+            matrix = r.bits(8)?;
+        }
+        let (subsampling_x, subsampling_y, chroma_sample_position) = if monochrome {
+            (true, true, 0)
+        } else if primaries == 1 && transfer == 13 && matrix == 0 {
+            // sRGB: 4:4:4, full range, nothing coded.
+            (false, false, 0)
+        } else {
+            r.flag()?; // color_range
+            let (x, y) = match profile {
+                0 => (true, true),
+                1 => (false, false),
+                _ if twelve_bit => {
+                    let x = r.flag()?;
+                    (x, x && r.flag()?)
+                }
+                _ => (true, false),
+            };
+            let position = if x && y { r.bits(2)? as u8 } else { 0 };
+            (x, y, position)
+        };
+
+        Some(SequenceHeader {
+            profile,
+            level_idx_0,
+            tier_0,
+            high_bitdepth,
+            twelve_bit,
+            monochrome,
+            subsampling_x,
+            subsampling_y,
+            chroma_sample_position,
+        })
+    }
+}
+
+#[test]
+fn opus_head_layout() {
+    assert_eq!(opus_head(44_100, 2, 312).unwrap(),
+               vec![b'O', b'p', b'u', b's', b'H', b'e', b'a', b'd', 1, 2, 0x38, 0x01,
+                    0x44, 0xAC, 0, 0, 0, 0, 0]);
+
+    let surround = opus_head(48_000, 6, 0).unwrap();
+    assert_eq!(surround.len(), 21 + 6);
+    assert_eq!(&surround[18..], &[1, 4, 2, 0, 4, 1, 2, 3, 5]);
+
+    assert!(opus_head(48_000, 0, 0).is_none());
+    assert!(opus_head(48_000, 9, 0).is_none());
+}
+
+#[test]
+fn vorbis_lacing() {
+    let identification = [1u8; 30];
+    let comment = [3u8; 300];
+    let setup = [5u8; 10];
+    let data = vorbis_codec_private(&identification, &comment, &setup);
+    assert_eq!(&data[..4], &[2, 30, 255, 45]);
+    assert_eq!(data.len(), 4 + 30 + 300 + 10);
+    assert_eq!(&data[4..34], &identification[..]);
+    assert_eq!(&data[334..], &setup[..]);
+}
+
+#[test]
+fn av1_config() {
+    struct BitWriter(Vec<u8>, usize);
+    impl BitWriter {
+        fn put(&mut self, n: u32, value: u32) {
+            for i in (0..n).rev() {
+                if self.1.is_multiple_of(8) {
+                    self.0.push(0);
+                }
+                let bit = ((value >> i) & 1) as u8;
+                *self.0.last_mut().unwrap() |= bit << (7 - self.1 % 8);
+                self.1 += 1;
+            }
+        }
+    }
+
+    // Main profile, level 4.0 (idx 8, so the tier bit is present), 10 bit
+    // 4:2:0 with colocated chroma, 1920x1080.
+    let mut w = BitWriter(Vec::new(), 0);
+    w.put(3, 0); // seq_profile
+    w.put(2, 0); // still_picture, reduced_still_picture_header
+    w.put(1, 0); // timing_info_present_flag
+    w.put(1, 0); // initial_display_delay_present_flag
+    w.put(5, 0); // operating_points_cnt_minus_1
+    w.put(12, 0);
+    w.put(5, 8); // seq_level_idx[0]
+    w.put(1, 0); // seq_tier[0]
+    w.put(4, 10);
+    w.put(4, 10);
+    w.put(11, 1919);
+    w.put(11, 1079);
+    w.put(1, 0); // frame_id_numbers_present_flag
+    w.put(3, 0);
+    w.put(4, 0);
+    w.put(1, 1); // enable_order_hint
+    w.put(2, 0);
+    w.put(1, 1); // seq_choose_screen_content_tools
+    w.put(1, 1); // seq_choose_integer_mv
+    w.put(3, 6); // order_hint_bits_minus_1
+    w.put(3, 0);
+    w.put(1, 1); // high_bitdepth
+    w.put(1, 0); // mono_chrome
+    w.put(1, 1); // color_description_present_flag
+    w.put(8, 9);
+    w.put(8, 16);
+    w.put(8, 9);
+    w.put(1, 0); // color_range
+    w.put(2, 2); // chroma_sample_position
+    w.put(1, 0); // separate_uv_delta_q
+    w.put(1, 1); // trailing bit
+    let payload = w.0;
+
+    let mut obu = vec![0x0A, payload.len() as u8];
+    obu.extend_from_slice(&payload);
+    let mut temporal_unit = vec![0x12, 0x00]; // temporal delimiter
+    temporal_unit.extend_from_slice(&obu);
+    temporal_unit.extend_from_slice(&[0x32, 0x02, 0xAB, 0xCD]); // frame OBU
+
+    let config = av1_codec_private(&temporal_unit).unwrap();
+    assert_eq!(&config[..4], &[0x81, 0x08, 0b0100_1110, 0]);
+    assert_eq!(&config[4..], &obu[..]);
+
+    assert!(av1_codec_private(&[0x12, 0x00]).is_none());
+    assert!(av1_codec_private(&[0x0A, 0x10, 0x00]).is_none());
+}
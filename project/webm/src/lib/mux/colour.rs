@@ -0,0 +1,163 @@
+//! Colour description of a video track: the Matroska `Colour` element and
+//! its HDR `MasteringMetadata`. Values follow ISO/IEC 23091-4 (H.273).
+
+#[cfg(feature = "libwebm")]
+use ffi;
+
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum MatrixCoefficients {
+    Gbr = 0,
+    Bt709 = 1,
+    Unspecified = 2,
+    Fcc = 4,
+    Bt470bg = 5,
+    Smpte170M = 6,
+    Smpte240M = 7,
+    YCoCg = 8,
+    Bt2020NonConstantLuminance = 9,
+    Bt2020ConstantLuminance = 10,
+}
+
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum TransferCharacteristics {
+    Bt709 = 1,
+    Unspecified = 2,
+    Gamma22 = 4,
+    Gamma28 = 5,
+    Smpte170M = 6,
+    Smpte240M = 7,
+    Linear = 8,
+    Log = 9,
+    LogSqrt = 10,
+    Iec61966_2_4 = 11,
+    Bt1361ExtendedColourGamut = 12,
+    Iec61966_2_1 = 13,
+    Bt2020TenBit = 14,
+    Bt2020TwelveBit = 15,
+    SmpteSt2084 = 16,
+    SmpteSt428_1 = 17,
+    AribStdB67Hlg = 18,
+}
+
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum Primaries {
+    Bt709 = 1,
+    Unspecified = 2,
+    Bt470M = 4,
+    Bt470Bg = 5,
+    Smpte170M = 6,
+    Smpte240M = 7,
+    Film = 8,
+    Bt2020 = 9,
+    SmpteSt428_1 = 10,
+    JedecP22Phosphors = 22,
+}
+
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum Range {
+    Unspecified = 0,
+    Broadcast = 1,
+    Full = 2,
+    /// Defined by the matrix coefficients and transfer characteristics.
+    Derived = 3,
+}
+
+/// Where chroma samples sit relative to luma, along one axis.
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum ChromaSiting {
+    Unspecified = 0,
+    /// Left (horizontally) or top (vertically) collocated.
+    Collocated = 1,
+    Half = 2,
+}
+
+/// Chromaticity coordinates (CIE 1931 x, y) and luminances (cd/m²) of the
+/// display the content was mastered on.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MasteringMetadata {
+    pub red: Option<(f32, f32)>,
+    pub green: Option<(f32, f32)>,
+    pub blue: Option<(f32, f32)>,
+    pub white_point: Option<(f32, f32)>,
+    pub luminance_max: Option<f32>,
+    pub luminance_min: Option<f32>,
+}
+
+/// Every field is optional; unset fields are left out of the file.
+///
+/// 8-bit BT.709 studio range content, for instance:
+///
+/// ```
+/// use webm::mux::{Colour, MatrixCoefficients, Primaries, Range, TransferCharacteristics};
+///
+/// let colour = Colour {
+///     bits_per_channel: Some(8),
+///     matrix_coefficients: Some(MatrixCoefficients::Bt709),
+///     transfer_characteristics: Some(TransferCharacteristics::Bt709),
+///     primaries: Some(Primaries::Bt709),
+///     range: Some(Range::Broadcast),
+///     ..Colour::default()
+/// };
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Colour {
+    pub matrix_coefficients: Option<MatrixCoefficients>,
+    pub bits_per_channel: Option<u8>,
+    /// Log2 of the chroma subsampling, (horizontal, vertical): (1, 1) for 4:2:0.
+    pub chroma_subsampling: Option<(u8, u8)>,
+    /// Log2 of the Cb subsampling on top of `chroma_subsampling`, (horizontal, vertical).
+    pub cb_subsampling: Option<(u8, u8)>,
+    /// (horizontal, vertical)
+    pub chroma_siting: Option<(ChromaSiting, ChromaSiting)>,
+    pub range: Option<Range>,
+    pub transfer_characteristics: Option<TransferCharacteristics>,
+    pub primaries: Option<Primaries>,
+    /// Maximum content light level, cd/m².
+    pub max_cll: Option<u64>,
+    /// Maximum frame-average light level, cd/m².
+    pub max_fall: Option<u64>,
+    pub mastering_metadata: Option<MasteringMetadata>,
+}
+
+#[cfg(feature = "libwebm")]
+impl Colour {
+    pub(crate) fn to_ffi(self) -> ffi::mux::Colour {
+        fn value<T: Into<u64>>(v: Option<T>) -> u64 {
+            v.map_or(ffi::mux::COLOUR_VALUE_NOT_PRESENT, Into::into)
+        }
+
+        let mut colour = ffi::mux::Colour {
+            matrix_coefficients: value(self.matrix_coefficients.map(|v| v as u64)),
+            bits_per_channel: value(self.bits_per_channel),
+            chroma_subsampling_horz: value(self.chroma_subsampling.map(|v| v.0)),
+            chroma_subsampling_vert: value(self.chroma_subsampling.map(|v| v.1)),
+            cb_subsampling_horz: value(self.cb_subsampling.map(|v| v.0)),
+            cb_subsampling_vert: value(self.cb_subsampling.map(|v| v.1)),
+            chroma_siting_horz: value(self.chroma_siting.map(|v| v.0 as u64)),
+            chroma_siting_vert: value(self.chroma_siting.map(|v| v.1 as u64)),
+            range: value(self.range.map(|v| v as u64)),
+            transfer_characteristics: value(self.transfer_characteristics.map(|v| v as u64)),
+            primaries: value(self.primaries.map(|v| v as u64)),
+            max_cll: value(self.max_cll),
+            max_fall: value(self.max_fall),
+            has_mastering_metadata: false,
+            chromaticities: [-1.0; 8],
+            luminance_max: f32::MAX,
+            luminance_min: f32::MAX,
+        };
+
+        if let Some(mastering) = self.mastering_metadata {
+            colour.has_mastering_metadata = true;
+            let points = [mastering.red, mastering.green, mastering.blue, mastering.white_point];
+            for (i, point) in points.iter().enumerate() {
+                if let Some((x, y)) = *point {
+                    colour.chromaticities[2 * i] = x;
+                    colour.chromaticities[2 * i + 1] = y;
+                }
+            }
+            colour.luminance_max = mastering.luminance_max.unwrap_or(f32::MAX);
+            colour.luminance_min = mastering.luminance_min.unwrap_or(f32::MAX);
+        }
+        colour
+    }
+}
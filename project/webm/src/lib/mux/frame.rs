@@ -0,0 +1,162 @@
+#[cfg(feature = "libwebm")]
+use ffi;
+#[cfg(feature = "libwebm")]
+use std::ptr;
+
+/// A frame with everything a block can carry, for `Track::add_generic_frame`.
+///
+/// Frames with a duration, discard padding or additional data are written
+/// as a BlockGroup, the rest as SimpleBlocks. Several short frames can share
+/// one block: pack them with `lace` and mark the frame `laced`.
+///
+/// ```
+/// use webm::mux::Frame;
+///
+/// // Last Opus packet of a stream, trimmed by 2.5 ms of padding.
+/// let packet = [0u8; 40];
+/// let frame = Frame::new(&packet, 960_000_000)
+///     .duration_ns(20_000_000)
+///     .discard_padding_ns(2_500_000);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Frame<'a> {
+    pub(super) data: &'a [u8],
+    pub(super) timestamp_ns: u64,
+    pub(super) keyframe: bool,
+    pub(super) duration_ns: Option<u64>,
+    pub(super) discard_padding_ns: i64,
+    pub(super) reference_timestamp_ns: Option<u64>,
+    pub(super) additional: Option<(&'a [u8], u64)>,
+    pub(super) lacing: Option<Lacing>,
+}
+
+impl<'a> Frame<'a> {
+    /// A keyframe at `timestamp_ns` with no duration.
+    pub fn new(data: &'a [u8], timestamp_ns: u64) -> Frame<'a> {
+        Frame {
+            data,
+            timestamp_ns,
+            keyframe: true,
+            duration_ns: None,
+            discard_padding_ns: 0,
+            reference_timestamp_ns: None,
+            additional: None,
+            lacing: None,
+        }
+    }
+
+    pub fn keyframe(mut self, keyframe: bool) -> Frame<'a> {
+        self.keyframe = keyframe;
+        self
+    }
+
+    /// Written as BlockDuration, in units of the 1 ms timecode scale.
+    pub fn duration_ns(mut self, duration_ns: u64) -> Frame<'a> {
+        self.duration_ns = Some(duration_ns);
+        self
+    }
+
+    /// Duration of audio to drop from the end of this frame (or from the
+    /// start, if negative) after decoding, as used to trim Opus streams.
+    pub fn discard_padding_ns(mut self, discard_padding_ns: i64) -> Frame<'a> {
+        self.discard_padding_ns = discard_padding_ns;
+        self
+    }
+
+    /// Timestamp of the frame this non-keyframe depends on. When not set,
+    /// the muxer uses the track's previous frame.
+    pub fn reference_timestamp_ns(mut self, timestamp_ns: u64) -> Frame<'a> {
+        self.reference_timestamp_ns = Some(timestamp_ns);
+        self
+    }
+
+    /// Stores `data` in a BlockAdditional tagged `add_id`; see
+    /// `Track::add_frame_with_additional`.
+    pub fn additional(mut self, data: &'a [u8], add_id: u64) -> Frame<'a> {
+        self.additional = Some((data, add_id));
+        self
+    }
+
+    /// Marks `data` as several frames packed by `lace` with `lacing`. The
+    /// block's timestamp, duration and keyframe flag cover all of them.
+    pub fn laced(mut self, lacing: Lacing) -> Frame<'a> {
+        self.lacing = Some(lacing);
+        self
+    }
+
+    #[cfg(feature = "libwebm")]
+    pub(crate) fn to_ffi(self) -> ffi::mux::Frame {
+        let (additional, add_id) = self.additional.unwrap_or((&[], 0));
+        ffi::mux::Frame {
+            data: self.data.as_ptr(),
+            length: self.data.len(),
+            additional: if additional.is_empty() { ptr::null() } else { additional.as_ptr() },
+            additional_length: additional.len(),
+            add_id,
+            timestamp_ns: self.timestamp_ns,
+            keyframe: self.keyframe,
+            has_duration: self.duration_ns.is_some(),
+            duration_ns: self.duration_ns.unwrap_or(0),
+            discard_padding_ns: self.discard_padding_ns,
+            has_reference: self.reference_timestamp_ns.is_some(),
+            reference_timestamp_ns: self.reference_timestamp_ns.unwrap_or(0) as i64,
+            lacing: self.lacing.map_or(0, |lacing| lacing as u8),
+        }
+    }
+}
+
+/// How `lace` records the sizes of the frames it packs, with the values of
+/// the block's lacing bits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Lacing {
+    /// Each size as a run of 255s plus the remainder; cheap for small frames.
+    Xiph = 1,
+    /// No sizes at all: every frame has the same length.
+    FixedSize = 2,
+    /// The first size as an EBML number, then the differences to it.
+    Ebml = 3,
+}
+
+/// Packs `frames` into the data of one laced block, for `Frame::laced`.
+/// `None` when there are no frames, more than 256, or, for `FixedSize`,
+/// frames of different lengths.
+pub fn lace(frames: &[&[u8]], lacing: Lacing) -> Option<Vec<u8>> {
+    let (last, rest) = frames.split_last()?;
+    if frames.len() > 256 {
+        return None;
+    }
+    let mut data = vec![rest.len() as u8];
+    match lacing {
+        Lacing::Xiph => for frame in rest {
+            data.extend(std::iter::repeat_n(255, frame.len() / 255));
+            data.push((frame.len() % 255) as u8);
+        },
+        Lacing::FixedSize => if rest.iter().any(|f| f.len() != last.len()) {
+            return None;
+        },
+        Lacing::Ebml => if let Some((first, others)) = rest.split_first() {
+            put_ebml_size(&mut data, first.len() as u64, 0);
+            let mut previous = first.len() as i64;
+            for frame in others {
+                // Signed differences are stored with a bias of half the range
+                // of the chosen width.
+                let diff = frame.len() as i64 - previous;
+                let width = (1..8).find(|&n| diff.abs() < 1 << (7 * n - 1))?;
+                let bias = (1i64 << (7 * width - 1)) - 1;
+                put_ebml_size(&mut data, (diff + bias) as u64, width);
+                previous = frame.len() as i64;
+            }
+        },
+    }
+    for frame in frames {
+        data.extend_from_slice(frame);
+    }
+    Some(data)
+}
+
+/// An EBML variable-size integer of at least `min_width` bytes.
+fn put_ebml_size(data: &mut Vec<u8>, value: u64, min_width: usize) {
+    let width = (1..8).find(|&n| n >= min_width && value < (1 << (7 * n)) - 1).unwrap_or(8);
+    let marked = value | 1 << (7 * width);
+    data.extend_from_slice(&marked.to_be_bytes()[8 - width..]);
+}
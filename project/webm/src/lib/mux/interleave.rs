@@ -0,0 +1,204 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use super::{AudioCodecId, MkvWriter, Segment, Track, TrackHandle, VideoCodecId, WebmWriter};
+use {Error, FinalizeError, Result};
+
+/// How far behind the newest frame a buffered frame may fall before it is
+/// written without waiting for the other tracks.
+pub const DEFAULT_MAX_INTERLEAVE_DELAY_NS: u64 = 1_000_000_000;
+
+struct QueuedFrame {
+    data: Vec<u8>,
+    timestamp_ns: u64,
+    keyframe: bool,
+}
+
+struct TrackQueue {
+    track: TrackHandle,
+    frames: VecDeque<QueuedFrame>,
+    last_timestamp_ns: Option<u64>,
+    /// Whether a `TrackSender` for the track is alive. Only open tracks
+    /// hold back the others.
+    open: bool,
+}
+
+struct State<W> {
+    segment: Option<Segment<W>>,
+    tracks: Vec<TrackQueue>,
+    max_delay_ns: u64,
+    newest_timestamp_ns: u64,
+    written_timestamp_ns: u64,
+}
+
+// The segment and its tracks are only ever reached through the mutex.
+unsafe impl<W: Send> Send for State<W> {}
+
+impl<W> State<W> {
+    fn segment(&self) -> Result<&Segment<W>> {
+        self.segment.as_ref().ok_or(Error::InvalidTrack)
+    }
+
+    fn queue(&mut self, track: TrackHandle) -> Result<usize> {
+        if let Some(index) = self.tracks.iter().position(|t| t.track == track) {
+            if self.tracks[index].open {
+                return Err(Error::InvalidArgument("track already has a sender"));
+            }
+            self.tracks[index].open = true;
+            return Ok(index);
+        }
+        self.tracks.push(TrackQueue {
+            track,
+            frames: VecDeque::new(),
+            last_timestamp_ns: None,
+            open: true,
+        });
+        Ok(self.tracks.len() - 1)
+    }
+
+    fn push(&mut self, index: usize, data: &[u8], timestamp_ns: u64, keyframe: bool) -> Result<()> {
+        self.segment()?;
+        let last_timestamp_ns = self.tracks[index].last_timestamp_ns.unwrap_or(0).max(self.written_timestamp_ns);
+        if timestamp_ns < last_timestamp_ns {
+            return Err(Error::NonMonotonicTimestamp { timestamp_ns, last_timestamp_ns });
+        }
+        let queue = &mut self.tracks[index];
+        queue.last_timestamp_ns = Some(timestamp_ns);
+        queue.frames.push_back(QueuedFrame { data: data.to_vec(), timestamp_ns, keyframe });
+        self.newest_timestamp_ns = self.newest_timestamp_ns.max(timestamp_ns);
+        self.write_ready(false)
+    }
+
+    /// Writes queued frames in timestamp order for as long as no open track
+    /// could still send an earlier one, or, with `all`, until every queue
+    /// is empty.
+    fn write_ready(&mut self, all: bool) -> Result<()> {
+        loop {
+            let next = self.tracks.iter()
+                .enumerate()
+                .filter_map(|(i, t)| t.frames.front().map(|f| (f.timestamp_ns, i)))
+                .min();
+            let (timestamp_ns, index) = match next {
+                Some(next) => next,
+                None => return Ok(()),
+            };
+            let waited_out = self.newest_timestamp_ns - timestamp_ns >= self.max_delay_ns;
+            if !all && !waited_out && self.tracks.iter().any(|t| t.open && t.frames.is_empty()) {
+                return Ok(());
+            }
+
+            let frame = self.tracks[index].frames.pop_front().unwrap();
+            self.written_timestamp_ns = frame.timestamp_ns;
+            self.segment()?.core.add_frame(self.tracks[index].track, &frame.data,
+                                           frame.timestamp_ns, frame.keyframe)?;
+        }
+    }
+}
+
+/// A panicking producer leaves the queues consistent, so a poisoned lock is
+/// still usable.
+fn lock<W>(state: &Mutex<State<W>>) -> MutexGuard<'_, State<W>> {
+    state.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// A `Segment` that any number of threads can feed, each through the
+/// `TrackSender` of its track.
+///
+/// The muxer needs the frames of all tracks in timestamp order. Frames are
+/// buffered per track and written out once every track with a live sender
+/// has caught up to them, or once they trail the newest frame by the
+/// maximum interleave delay, so a track that goes quiet holds the others
+/// back for no longer than that. A frame that arrives after later frames
+/// were written is refused with `NonMonotonicTimestamp`.
+pub struct Multiplexer<W = Box<WebmWriter>> {
+    state: Arc<Mutex<State<W>>>,
+}
+
+impl<W: MkvWriter> Multiplexer<W> {
+    /// Takes over `segment`. Tracks already added to it get their senders
+    /// from `sender`; they can be configured beforehand with the usual
+    /// `Track` methods.
+    pub fn new(segment: Segment<W>) -> Self {
+        Multiplexer {
+            state: Arc::new(Mutex::new(State {
+                segment: Some(segment),
+                tracks: Vec::new(),
+                max_delay_ns: DEFAULT_MAX_INTERLEAVE_DELAY_NS,
+                newest_timestamp_ns: 0,
+                written_timestamp_ns: 0,
+            })),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State<W>> {
+        lock(&self.state)
+    }
+
+    /// Sets how far, in media time, a frame may trail the newest frame
+    /// before it is written regardless of the other tracks. Zero writes
+    /// every frame straight away.
+    pub fn set_max_interleave_delay(&self, delay_ns: u64) {
+        self.lock().max_delay_ns = delay_ns;
+    }
+
+    /// A sender for the track numbered `number`, one per track at a time.
+    pub fn sender(&self, number: u64) -> Result<TrackSender<W>> {
+        let mut state = self.lock();
+        let track = state.segment()?.core.track_by_number(number).ok_or(Error::InvalidTrack)?;
+        let index = state.queue(track)?;
+        Ok(TrackSender { state: self.state.clone(), index })
+    }
+
+    pub fn add_video_track(&self, width: u32, height: u32, id: Option<i32>,
+                           codec: VideoCodecId) -> Result<TrackSender<W>> {
+        let number = self.lock().segment()?.add_video_track(width, height, id, codec)?.number();
+        self.sender(number)
+    }
+
+    pub fn add_audio_track(&self, sample_rate: i32, channels: i32, id: Option<i32>,
+                           codec: AudioCodecId) -> Result<TrackSender<W>> {
+        let number = self.lock().segment()?.add_audio_track(sample_rate, channels, id, codec)?.number();
+        self.sender(number)
+    }
+
+    /// Writes out everything still buffered and finalizes the segment.
+    /// Senders still alive get `InvalidTrack` from then on.
+    pub fn finalize(self, duration: Option<u64>) -> Result<W, FinalizeError<W>> {
+        let mut state = self.lock();
+        let written = state.write_ready(true);
+        let segment = state.segment.take().expect("only finalize takes the segment");
+        let writer = segment.finalize(duration)?;
+        match written {
+            Ok(()) => Ok(writer),
+            Err(e) => Err(FinalizeError::new(e, writer)),
+        }
+    }
+}
+
+/// Adds frames to one track of a `Multiplexer`, from any thread. Dropping
+/// it tells the multiplexer not to wait for the track any more.
+pub struct TrackSender<W = Box<WebmWriter>> {
+    state: Arc<Mutex<State<W>>>,
+    index: usize,
+}
+
+impl<W: MkvWriter> TrackSender<W> {
+    fn lock(&self) -> MutexGuard<'_, State<W>> {
+        lock(&self.state)
+    }
+
+    /// Queues a frame. Errors from writing out earlier frames of any track
+    /// may surface here.
+    pub fn add_frame(&self, data: &[u8], timestamp_ns: u64, keyframe: bool) -> Result<()> {
+        self.lock().push(self.index, data, timestamp_ns, keyframe)
+    }
+}
+
+impl<W> Drop for TrackSender<W> {
+    fn drop(&mut self) {
+        let mut state = lock(&self.state);
+        state.tracks[self.index].open = false;
+        // A write error here shows up again on the next call.
+        let _ = state.write_ready(false);
+    }
+}
@@ -0,0 +1,480 @@
+//! The libwebm side of `Segment` and its tracks, built with the `libwebm`
+//! feature. Text tracks, chapters, tags and `finalize_cues_first` are only
+//! available here.
+
+use ffi;
+use std::ffi::{CStr, CString};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::raw::{c_char, c_int, c_void};
+use std::ptr;
+use std::slice::from_raw_parts_mut;
+
+use super::{peek_error, AudioCodecId, Colour, Crop, ErrorCell, Frame, MkvWriter, Projection,
+            RawWriter, Segment, SegmentMode, StereoMode, Track, VideoCodecId, Writer};
+use {Error, FinalizeError, Result};
+
+pub type TrackHandle = ffi::mux::TrackMutPtr;
+pub type VideoHandle = ffi::mux::VideoTrackMutPtr;
+pub type AudioHandle = ffi::mux::AudioTrackMutPtr;
+
+/// An `IMkvWriter` that calls back into `writer`, for `delete_writer` to
+/// free.
+fn new_ffi_writer(writer: &RawWriter) -> ffi::mux::WriterMutPtr {
+    let mkv_writer = unsafe {
+        ffi::mux::new_writer(Some(writer.write),
+                             Some(writer.get_pos),
+                             writer.set_pos,
+                             writer.element_start,
+                             writer.dest)
+    };
+    debug_assert!(!mkv_writer.is_null());
+    mkv_writer
+}
+
+/// libwebm's segment and the `IMkvWriter` it writes through.
+#[doc(hidden)]
+#[derive(Eq, PartialEq)]
+pub struct SegmentCore {
+    ffi: ffi::mux::SegmentMutPtr,
+    writer: ffi::mux::WriterMutPtr,
+    writer_error: *const ErrorCell,
+}
+
+/// Frees libwebm's objects, whether or not the segment was finalized. A
+/// segment dropped without `finalize` leaves its output unfinished.
+impl Drop for SegmentCore {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::mux::delete_segment(self.ffi);
+            ffi::mux::delete_writer(self.writer);
+        }
+    }
+}
+
+impl SegmentCore {
+    pub(super) fn new(writer: RawWriter, writer_error: *const ErrorCell,
+                      mode: SegmentMode) -> Result<SegmentCore> {
+        let writer = new_ffi_writer(&writer);
+        let ffi = unsafe { ffi::mux::new_segment() };
+        let success = unsafe {
+            let ret = ffi::mux::initialize_segment(ffi, writer);
+            ffi::mux::segment_set_mode(ffi, match mode {
+                SegmentMode::Live => ffi::mux::SEGMENT_MODE_LIVE,
+                SegmentMode::File => ffi::mux::SEGMENT_MODE_FILE,
+            });
+            ret
+        };
+        let core = SegmentCore { ffi, writer, writer_error };
+        if !success {
+            return Err(Error::Muxer("could not initialize the segment"));
+        }
+        Ok(core)
+    }
+
+    /// Writes the closing elements and frees libwebm's objects.
+    pub(super) fn finalize(self, duration: u64) -> Result<()> {
+        if unsafe { ffi::mux::finalize_segment(self.ffi, duration) } {
+            Ok(())
+        } else {
+            Err(Error::Finalize)
+        }
+    }
+
+    /// Works out why libwebm refused a frame at `timestamp_ns`: the writer
+    /// failed, a track header could not be written, or the frame is older
+    /// than the last one written.
+    fn frame_error(&self, timestamp_ns: u64) -> Error {
+        if let Some(e) = peek_error(unsafe { &*self.writer_error }) {
+            return Error::Io(e);
+        }
+        let codec_id = unsafe { ffi::mux::segment_invalid_codec_id(self.ffi) };
+        if !codec_id.is_null() {
+            let codec_id = unsafe { CStr::from_ptr(codec_id) };
+            return Error::BadCodecId(codec_id.to_string_lossy().into_owned());
+        }
+        let last_timestamp_ns = unsafe { ffi::mux::segment_last_timestamp(self.ffi) };
+        if timestamp_ns < last_timestamp_ns {
+            return Error::NonMonotonicTimestamp { timestamp_ns, last_timestamp_ns };
+        }
+        Error::Muxer("frame rejected")
+    }
+
+    pub(super) fn track_number(&self, track: TrackHandle) -> u64 {
+        unsafe { ffi::mux::track_number(track) }
+    }
+
+    pub(super) fn track_by_number(&self, number: u64) -> Option<TrackHandle> {
+        let track = unsafe { ffi::mux::segment_get_track(self.ffi, number) };
+        if track.is_null() { None } else { Some(track) }
+    }
+
+    pub(super) fn add_frame(&self, track: TrackHandle, data: &[u8], timestamp_ns: u64,
+                            keyframe: bool) -> Result<()> {
+        let added = unsafe {
+            ffi::mux::segment_add_frame(self.ffi, track, data.as_ptr(), data.len(),
+                                        timestamp_ns, keyframe)
+        };
+        if added { Ok(()) } else { Err(self.frame_error(timestamp_ns)) }
+    }
+
+    pub(super) fn add_frame_with_additional(&self, track: TrackHandle, data: &[u8],
+                                            additional: &[u8], add_id: u64,
+                                            timestamp_ns: u64, keyframe: bool) -> Result<()> {
+        let added = unsafe {
+            ffi::mux::segment_add_frame_with_additional(self.ffi, track,
+                                                        data.as_ptr(), data.len(),
+                                                        additional.as_ptr(), additional.len(),
+                                                        add_id, timestamp_ns, keyframe)
+        };
+        if added { Ok(()) } else { Err(self.frame_error(timestamp_ns)) }
+    }
+
+    pub(super) fn add_generic_frame(&self, track: TrackHandle, frame: &Frame) -> Result<()> {
+        let frame = frame.to_ffi();
+        if unsafe { ffi::mux::segment_add_generic_frame(self.ffi, track, &frame) } {
+            Ok(())
+        } else {
+            Err(self.frame_error(frame.timestamp_ns))
+        }
+    }
+
+    pub(super) fn set_codec_private(&self, track: TrackHandle, data: &[u8]) -> Result<()> {
+        let set = unsafe {
+            ffi::mux::track_set_codec_private(track, data.as_ptr(), data.len() as u64)
+        };
+        if set { Ok(()) } else { Err(Error::Muxer("could not store the CodecPrivate")) }
+    }
+
+    pub(super) fn set_cues_track(&self, track: TrackHandle) -> Result<()> {
+        if unsafe { ffi::mux::segment_set_cues_track(self.ffi, track) } {
+            Ok(())
+        } else {
+            Err(Error::InvalidTrack)
+        }
+    }
+
+    pub(super) fn output_cues(&self, output_cues: bool) {
+        unsafe {
+            ffi::mux::segment_output_cues(self.ffi, output_cues);
+        }
+    }
+
+    pub(super) fn set_max_cluster_duration(&self, duration_ns: u64) {
+        unsafe {
+            ffi::mux::segment_set_max_cluster_duration(self.ffi, duration_ns);
+        }
+    }
+
+    pub(super) fn set_max_cluster_size(&self, size_bytes: u64) {
+        unsafe {
+            ffi::mux::segment_set_max_cluster_size(self.ffi, size_bytes);
+        }
+    }
+
+    pub(super) fn force_new_cluster(&self) {
+        unsafe {
+            ffi::mux::segment_force_new_cluster(self.ffi);
+        }
+    }
+
+    pub(super) fn set_app_name(&self, name: &str) -> Result<()> {
+        let name = CString::new(name).map_err(|_| Error::InvalidArgument("NUL byte in app name"))?;
+        unsafe {
+            ffi::mux::mux_set_writing_app(self.ffi, name.as_ptr());
+        }
+        Ok(())
+    }
+
+    pub(super) fn add_video_track(&self, width: u32, height: u32, id: i32,
+                                  codec: VideoCodecId) -> Result<VideoHandle> {
+        let vt = unsafe {
+            ffi::mux::segment_add_video_track(self.ffi, width as i32, height as i32, id,
+                                              codec.get_id())
+        };
+        if vt.is_null() { Err(Error::InvalidTrack) } else { Ok(vt) }
+    }
+
+    pub(super) fn add_audio_track(&self, sample_rate: i32, channels: i32, id: i32,
+                                  codec: AudioCodecId) -> Result<AudioHandle> {
+        let at = unsafe {
+            ffi::mux::segment_add_audio_track(self.ffi, sample_rate, channels, id, codec.get_id())
+        };
+        if at.is_null() { Err(Error::InvalidTrack) } else { Ok(at) }
+    }
+
+    pub(super) fn video_track(&self, video: VideoHandle) -> TrackHandle {
+        unsafe { ffi::mux::video_track_base_mut(video) }
+    }
+
+    pub(super) fn audio_track(&self, audio: AudioHandle) -> TrackHandle {
+        unsafe { ffi::mux::audio_track_base_mut(audio) }
+    }
+
+    pub(super) fn set_colour(&self, video: VideoHandle, colour: &Colour) -> Result<()> {
+        let colour = colour.to_ffi();
+        if unsafe { ffi::mux::video_track_set_colour(video, &colour) } {
+            Ok(())
+        } else {
+            Err(Error::InvalidArgument("colour value out of range"))
+        }
+    }
+
+    pub(super) fn set_display_size(&self, video: VideoHandle, width: u64, height: u64) {
+        unsafe {
+            ffi::mux::video_track_set_display_size(video, width, height);
+        }
+    }
+
+    pub(super) fn set_crop(&self, video: VideoHandle, crop: Crop) {
+        unsafe {
+            ffi::mux::video_track_set_crop(video, crop.left, crop.right, crop.top, crop.bottom);
+        }
+    }
+
+    pub(super) fn set_stereo_mode(&self, video: VideoHandle, mode: StereoMode) -> Result<()> {
+        if unsafe { ffi::mux::video_track_set_stereo_mode(video, mode as u64) } {
+            Ok(())
+        } else {
+            Err(Error::InvalidArgument("stereo mode"))
+        }
+    }
+
+    pub(super) fn set_alpha_mode(&self, video: VideoHandle, has_alpha: bool) -> Result<()> {
+        if unsafe { ffi::mux::video_track_set_alpha_mode(video, has_alpha as u64) } {
+            Ok(())
+        } else {
+            Err(Error::InvalidArgument("alpha mode"))
+        }
+    }
+
+    pub(super) fn set_frame_rate(&self, video: VideoHandle, frame_rate: f64) {
+        unsafe {
+            ffi::mux::video_track_set_frame_rate(video, frame_rate);
+        }
+    }
+
+    pub(super) fn set_projection(&self, video: VideoHandle, projection: &Projection) -> Result<()> {
+        let set = unsafe {
+            ffi::mux::video_track_set_projection(video,
+                                                 projection.projection_type as c_int,
+                                                 projection.private.as_ptr(),
+                                                 projection.private.len() as u64,
+                                                 projection.yaw,
+                                                 projection.pitch,
+                                                 projection.roll)
+        };
+        if set { Ok(()) } else { Err(Error::Muxer("could not store the projection")) }
+    }
+
+    pub(super) fn set_codec_delay(&self, audio: AudioHandle, delay_ns: u64) {
+        unsafe {
+            ffi::mux::track_set_codec_delay(self.audio_track(audio), delay_ns);
+        }
+    }
+
+    pub(super) fn set_seek_pre_roll(&self, audio: AudioHandle, pre_roll_ns: u64) {
+        unsafe {
+            ffi::mux::track_set_seek_pre_roll(self.audio_track(audio), pre_roll_ns);
+        }
+    }
+}
+
+impl AudioCodecId {
+    fn get_id(&self) -> u32 {
+        match self {
+            AudioCodecId::Opus => ffi::mux::OPUS_CODEC_ID,
+            AudioCodecId::Vorbis => ffi::mux::VORBIS_CODEC_ID,
+        }
+    }
+}
+
+impl VideoCodecId {
+    fn get_id(&self) -> u32 {
+        match self {
+            VideoCodecId::VP8 => ffi::mux::VP8_CODEC_ID,
+            VideoCodecId::VP9 => ffi::mux::VP9_CODEC_ID,
+            VideoCodecId::AV1 => ffi::mux::AV1_CODEC_ID,
+        }
+    }
+}
+
+/// A WebVTT track. Each cue is a block holding the cue payload, with its
+/// own duration.
+#[derive(Eq, PartialEq, Clone, Copy)]
+pub struct TextTrack<'a>(&'a SegmentCore, TrackHandle);
+
+impl<'a> TextTrack<'a> {
+    /// Adds a cue shown from `start_ns` for `duration_ns`. `payload` is
+    /// the cue text for subtitles and captions, or any UTF-8 data (JSON
+    /// events, say) for metadata tracks. Cues must not start before the
+    /// last frame added to the segment.
+    pub fn add_cue(&mut self, start_ns: u64, duration_ns: u64, payload: &[u8]) -> Result<()> {
+        let added = unsafe {
+            ffi::mux::segment_add_metadata(self.0.ffi, self.1,
+                                           payload.as_ptr(), payload.len(),
+                                           start_ns, duration_ns)
+        };
+        if added { Ok(()) } else { Err(self.0.frame_error(start_ns)) }
+    }
+}
+impl<'a> Track for TextTrack<'a> {
+    #[doc(hidden)]
+    fn get_segment(&self) -> &SegmentCore { self.0 }
+    #[doc(hidden)]
+    fn get_track(&self) -> TrackHandle { self.1 }
+}
+
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum TextTrackKind {
+    Subtitles,
+    Captions,
+    Descriptions,
+    /// Machine-readable timed data rather than text for viewers.
+    Metadata,
+}
+impl TextTrackKind {
+    fn get_id(&self) -> u32 {
+        match self {
+            TextTrackKind::Subtitles => ffi::mux::TEXT_KIND_SUBTITLES,
+            TextTrackKind::Captions => ffi::mux::TEXT_KIND_CAPTIONS,
+            TextTrackKind::Descriptions => ffi::mux::TEXT_KIND_DESCRIPTIONS,
+            TextTrackKind::Metadata => ffi::mux::TEXT_KIND_METADATA,
+        }
+    }
+}
+
+/// Owned C copies of string pairs, with the pointer arrays handed to
+/// libwebm. The pointers live as long as the strings.
+struct CStringPairs {
+    _strings: Vec<(CString, CString)>,
+    firsts: Vec<*const c_char>,
+    seconds: Vec<*const c_char>,
+}
+
+impl CStringPairs {
+    /// None if any string contains a NUL byte.
+    fn new(pairs: &[(&str, &str)]) -> Option<CStringPairs> {
+        let strings = pairs.iter()
+            .map(|&(a, b)| Some((CString::new(a).ok()?, CString::new(b).ok()?)))
+            .collect::<Option<Vec<_>>>()?;
+        Some(CStringPairs {
+            firsts: strings.iter().map(|(a, _)| a.as_ptr()).collect(),
+            seconds: strings.iter().map(|(_, b)| b.as_ptr()).collect(),
+            _strings: strings,
+        })
+    }
+}
+
+impl<W: MkvWriter> Segment<W> {
+    /// Adds a chapter spanning `start_ns..end_ns`. `id` is the chapter's
+    /// string ID and `titles` pairs each display title with its
+    /// ISO 639-2 language, e.g. `[("Intro", "eng"), ("Einleitung", "ger")]`.
+    ///
+    /// Chapters go out with the segment header, which is written with the
+    /// first frame, so they must be added before any frame.
+    pub fn add_chapter(&self, start_ns: u64, end_ns: u64, id: &str,
+                       titles: &[(&str, &str)]) -> Result<()> {
+        let (id, titles) = match (CString::new(id), CStringPairs::new(titles)) {
+            (Ok(id), Some(titles)) => (id, titles),
+            _ => return Err(Error::InvalidArgument("NUL byte in chapter string")),
+        };
+        let added = unsafe {
+            ffi::mux::segment_add_chapter(self.core.ffi, start_ns, end_ns, id.as_ptr(),
+                                          titles.firsts.as_ptr(), titles.seconds.as_ptr(),
+                                          titles.firsts.len())
+        };
+        if added { Ok(()) } else { Err(Error::Muxer("could not add the chapter")) }
+    }
+
+    /// Adds a Tag holding `tags` as (name, value) pairs for the whole
+    /// segment. Matroska's usual names include `TITLE`, `ARTIST`,
+    /// `DATE_RECORDED` and `COMMENT`; any other name works for our own
+    /// key/value pairs.
+    ///
+    /// Like chapters, tags are written with the segment header and must
+    /// be added before any frame.
+    pub fn add_tag(&self, tags: &[(&str, &str)]) -> Result<()> {
+        self.add_tag_for(ptr::null_mut(), tags)
+    }
+
+    /// Same as `add_tag`, but the tags apply to `track` only.
+    pub fn add_track_tag<T: Track>(&self, track: &T, tags: &[(&str, &str)]) -> Result<()> {
+        self.check_owns(track)?;
+        self.add_tag_for(track.get_track(), tags)
+    }
+
+    fn add_tag_for(&self, track: TrackHandle, tags: &[(&str, &str)]) -> Result<()> {
+        let tags = CStringPairs::new(tags).ok_or(Error::InvalidArgument("NUL byte in tag"))?;
+        let added = unsafe {
+            ffi::mux::segment_add_tag(self.core.ffi, track, tags.firsts.as_ptr(),
+                                      tags.seconds.as_ptr(), tags.firsts.len())
+        };
+        if added { Ok(()) } else { Err(Error::Muxer("could not add the tag")) }
+    }
+
+    /// Adds a `D_WEBVTT/*` track. `language` is an ISO 639-2 code such as
+    /// "eng"; Matroska assumes "eng" when it is not given.
+    pub fn add_text_track(&self, kind: TextTrackKind, language: Option<&str>) -> Result<TextTrack<'_>> {
+        let language = language.map(CString::new).transpose()
+            .map_err(|_| Error::InvalidArgument("NUL byte in language"))?;
+        let tt = unsafe {
+            ffi::mux::segment_add_text_track(self.core.ffi, kind.get_id(),
+                                             language.as_ref().map_or(ptr::null(), |l| l.as_ptr()))
+        };
+        if tt.is_null() {
+            return Err(Error::InvalidTrack);
+        }
+        Ok(TextTrack(&self.core, tt))
+    }
+}
+
+impl<T: Read + Write + Seek> Segment<Box<Writer<T>>> {
+    /// File mode only. Finalizes the segment, then copies it into `dest`
+    /// with the Cues moved in front of the clusters, so players can seek
+    /// without first reading to the end. What was written to the
+    /// segment's own writer is only a first pass and is dropped.
+    pub fn finalize_cues_first<D: Write + Seek>(mut self, duration: Option<u64>,
+                                                dest: D) -> Result<D, FinalizeError<D>> {
+        extern "C" fn read_fn<T: Read + Seek>(src: *mut c_void, pos: i64, len: i64,
+                                              buf: *mut u8) -> c_int {
+            let src = unsafe { &mut *(src as *mut T) };
+            let buf = unsafe { from_raw_parts_mut(buf, len as usize) };
+            let ok = src.seek(SeekFrom::Start(pos as u64)).is_ok() && src.read_exact(buf).is_ok();
+            if ok { 0 } else { -1 }
+        }
+        extern "C" fn length_fn<T: Read + Seek>(src: *mut c_void, total: *mut i64,
+                                                available: *mut i64) -> c_int {
+            let src = unsafe { &mut *(src as *mut T) };
+            match src.seek(SeekFrom::End(0)) {
+                Ok(len) => {
+                    unsafe {
+                        *total = len as i64;
+                        *available = len as i64;
+                    }
+                    0
+                }
+                Err(_) => -1,
+            }
+        }
+
+        let dest = Writer::new(dest);
+        let dest_writer = new_ffi_writer(&dest.mkv_writer());
+        let result = unsafe {
+            ffi::mux::finalize_segment(self.core.ffi, duration.unwrap_or(0)) &&
+                ffi::mux::segment_copy_and_move_cues(self.core.ffi,
+                                                     Some(read_fn::<T>),
+                                                     Some(length_fn::<T>),
+                                                     self.writer.get_mut() as *mut T as *mut c_void,
+                                                     dest_writer)
+        };
+        unsafe {
+            ffi::mux::delete_writer(dest_writer);
+        }
+        let error = match peek_error(&self.writer.error).or_else(|| peek_error(&dest.error)) {
+            Some(e) => Error::Io(e),
+            None if result => return Ok(dest.into_inner()),
+            None => Error::Finalize,
+        };
+        Err(FinalizeError::new(error, dest.into_inner()))
+    }
+}
@@ -0,0 +1,1077 @@
+//! The element writing behind the Rust `Segment`. It follows libwebm's
+//! mkvmuxer: the same elements in the same order, the same rules for when
+//! a cluster starts and for holding audio back until the video reaches it,
+//! so files from either backend look alike to players and to `validate`.
+
+use ebml::{self, element_type, parse_header, ElementType};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::mem;
+use std::os::raw::c_void;
+use super::{peek_error, Colour, Crop, ErrorCell, Frame, Lacing, Projection, RawWriter, SegmentMode};
+use {Error, Result};
+
+/// libwebm's default, which this crate never changes.
+const TIMECODE_SCALE_NS: u64 = 1_000_000;
+/// Block timecodes are 16-bit offsets from their cluster's timecode.
+const MAX_BLOCK_TIMECODE: u64 = 0x7FFF;
+const DEFAULT_MAX_CLUSTER_DURATION_NS: u64 = 30_000_000_000;
+const MAX_TRACK_NUMBER: u64 = 126;
+/// Room kept in front of Info for the SeekHead, which is only written by
+/// `finalize`: five entries with 4-byte IDs and 8-byte positions.
+const SEEK_HEAD_SIZE: usize = 110;
+/// An 8-byte size field saying the size is unknown.
+const UNKNOWN_SIZE: u64 = 0x01FF_FFFF_FFFF_FFFF;
+const MUXING_APP: &str = concat!("webm-rs-", env!("CARGO_PKG_VERSION"));
+
+fn uint_len(value: u64) -> usize {
+    (1..8).find(|&len| value < 1 << (8 * len)).unwrap_or(8)
+}
+
+fn int_len(value: i64) -> usize {
+    let magnitude = if value < 0 { !value } else { value } as u64;
+    uint_len(2 * magnitude)
+}
+
+/// Bytes the shortest size field for `size` takes; all ones is reserved
+/// for unknown sizes.
+fn size_len(size: u64) -> usize {
+    (1..8).find(|&len| size < (1 << (7 * len)) - 1).unwrap_or(8)
+}
+
+fn put_be(buf: &mut Vec<u8>, value: u64, len: usize) {
+    buf.extend_from_slice(&value.to_be_bytes()[8 - len..]);
+}
+
+/// IDs keep their length marker, so they are written as they are.
+fn put_id(buf: &mut Vec<u8>, id: u64) {
+    put_be(buf, id, uint_len(id));
+}
+
+fn put_size_len(buf: &mut Vec<u8>, size: u64, len: usize) {
+    put_be(buf, size | 1 << (7 * len), len);
+}
+
+fn put_size(buf: &mut Vec<u8>, size: u64) {
+    put_size_len(buf, size, size_len(size));
+}
+
+fn put_bytes(buf: &mut Vec<u8>, id: u64, data: &[u8]) {
+    put_id(buf, id);
+    put_size(buf, data.len() as u64);
+    buf.extend_from_slice(data);
+}
+
+fn put_uint(buf: &mut Vec<u8>, id: u64, value: u64) {
+    let len = uint_len(value);
+    put_id(buf, id);
+    put_size(buf, len as u64);
+    put_be(buf, value, len);
+}
+
+fn put_int(buf: &mut Vec<u8>, id: u64, value: i64) {
+    let len = int_len(value);
+    put_id(buf, id);
+    put_size(buf, len as u64);
+    put_be(buf, value as u64, len);
+}
+
+fn put_float(buf: &mut Vec<u8>, id: u64, value: f32) {
+    put_bytes(buf, id, &value.to_bits().to_be_bytes());
+}
+
+/// A Void element of `size` bytes in all, or nothing if `size` is 0. The
+/// smallest Void element takes 2 bytes, so `size` can not be 1.
+fn put_void(buf: &mut Vec<u8>, size: usize) {
+    if size == 0 {
+        return;
+    }
+    debug_assert!(size >= 2, "no Void element is 1 byte long");
+    let payload = size - 1 - size_len(size as u64 - 1);
+    put_id(buf, ebml::ID_VOID);
+    put_size(buf, payload as u64);
+    buf.resize(buf.len() + payload, 0);
+}
+
+/// A random TrackUID; 56 bits, as libwebm makes them, and never 0.
+fn make_uid(number: u64) -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(number);
+    (hasher.finish() >> 8).max(1)
+}
+
+/// Whether the muxer would store `colour` as it is, the same check libwebm
+/// makes: chromaticities within 0..=1 and a plausible luminance range.
+pub(super) fn colour_is_valid(colour: &Colour) -> bool {
+    let mastering = match colour.mastering_metadata {
+        Some(mastering) => mastering,
+        None => return true,
+    };
+    let points = [mastering.red, mastering.green, mastering.blue, mastering.white_point];
+    let points_valid = points.iter().flatten()
+        .all(|&(x, y)| (0.0..=1.0).contains(&x) && (0.0..=1.0).contains(&y));
+    let min_valid = mastering.luminance_min.is_none_or(|min| (0.0..=999.99).contains(&min));
+    let max_valid = mastering.luminance_max.is_none_or(|max| (0.0..=9999.99).contains(&max));
+    let ordered = match (mastering.luminance_min, mastering.luminance_max) {
+        (Some(min), Some(max)) => min <= max,
+        _ => true,
+    };
+    points_valid && min_valid && max_valid && ordered
+}
+
+fn put_colour(buf: &mut Vec<u8>, colour: &Colour) {
+    let values = [
+        (0x55B1, colour.matrix_coefficients.map(|v| v as u64)),
+        (0x55B2, colour.bits_per_channel.map(u64::from)),
+        (0x55B3, colour.chroma_subsampling.map(|v| v.0.into())),
+        (0x55B4, colour.chroma_subsampling.map(|v| v.1.into())),
+        (0x55B5, colour.cb_subsampling.map(|v| v.0.into())),
+        (0x55B6, colour.cb_subsampling.map(|v| v.1.into())),
+        (0x55B7, colour.chroma_siting.map(|v| v.0 as u64)),
+        (0x55B8, colour.chroma_siting.map(|v| v.1 as u64)),
+        (0x55B9, colour.range.map(|v| v as u64)),
+        (0x55BA, colour.transfer_characteristics.map(|v| v as u64)),
+        (0x55BB, colour.primaries.map(|v| v as u64)),
+        (0x55BC, colour.max_cll),
+        (0x55BD, colour.max_fall),
+    ];
+    let mut payload = Vec::new();
+    for &(id, value) in &values {
+        if let Some(value) = value {
+            put_uint(&mut payload, id, value);
+        }
+    }
+
+    if let Some(mastering) = colour.mastering_metadata {
+        let mut metadata = Vec::new();
+        if let Some(max) = mastering.luminance_max {
+            put_float(&mut metadata, ebml::ID_LUMINANCE_MAX, max);
+        }
+        if let Some(min) = mastering.luminance_min {
+            put_float(&mut metadata, ebml::ID_LUMINANCE_MIN, min);
+        }
+        let points = [(0x55D1, mastering.red), (0x55D3, mastering.green),
+                      (0x55D5, mastering.blue), (0x55D7, mastering.white_point)];
+        for &(id, point) in &points {
+            if let Some((x, y)) = point {
+                put_float(&mut metadata, id, x);
+                put_float(&mut metadata, id + 1, y);
+            }
+        }
+        if !metadata.is_empty() {
+            put_bytes(&mut payload, ebml::ID_MASTERING_METADATA, &metadata);
+        }
+    }
+
+    if !payload.is_empty() {
+        put_bytes(buf, ebml::ID_COLOUR, &payload);
+    }
+}
+
+fn put_projection(buf: &mut Vec<u8>, projection: &Projection) {
+    let mut payload = Vec::new();
+    put_uint(&mut payload, ebml::ID_PROJECTION_TYPE, projection.projection_type as u64);
+    if !projection.private.is_empty() {
+        put_bytes(&mut payload, ebml::ID_PROJECTION_PRIVATE, &projection.private);
+    }
+    put_float(&mut payload, ebml::ID_PROJECTION_POSE_YAW, projection.yaw);
+    put_float(&mut payload, ebml::ID_PROJECTION_POSE_PITCH, projection.pitch);
+    put_float(&mut payload, ebml::ID_PROJECTION_POSE_ROLL, projection.roll);
+    put_bytes(buf, ebml::ID_PROJECTION, &payload);
+}
+
+#[derive(Default)]
+pub(super) struct VideoSettings {
+    pub(super) width: u64,
+    pub(super) height: u64,
+    /// Not written while 0.
+    pub(super) display_size: (u64, u64),
+    pub(super) crop: Crop,
+    pub(super) stereo_mode: u64,
+    pub(super) alpha_mode: bool,
+    pub(super) frame_rate: f64,
+    pub(super) colour: Option<Colour>,
+    pub(super) projection: Option<Projection>,
+}
+
+pub(super) enum Settings {
+    Video(Box<VideoSettings>),
+    Audio { sample_rate: f64, channels: u64 },
+}
+
+pub(super) struct TrackEntry {
+    pub(super) number: u64,
+    uid: u64,
+    codec_id: &'static str,
+    pub(super) codec_private: Option<Vec<u8>>,
+    pub(super) codec_delay_ns: u64,
+    pub(super) seek_pre_roll_ns: u64,
+    pub(super) settings: Settings,
+    /// What a non-keyframe without an explicit reference refers to.
+    last_timestamp_ns: u64,
+}
+
+impl TrackEntry {
+    fn is_video(&self) -> bool {
+        matches!(self.settings, Settings::Video(_))
+    }
+
+    fn put(&self, buf: &mut Vec<u8>) {
+        let mut entry = Vec::new();
+        put_uint(&mut entry, ebml::ID_TRACK_NUMBER, self.number);
+        put_uint(&mut entry, ebml::ID_TRACK_UID, self.uid);
+        put_uint(&mut entry, ebml::ID_TRACK_TYPE, if self.is_video() { 1 } else { 2 });
+        if self.codec_delay_ns > 0 {
+            put_uint(&mut entry, ebml::ID_CODEC_DELAY, self.codec_delay_ns);
+        }
+        if self.seek_pre_roll_ns > 0 {
+            put_uint(&mut entry, ebml::ID_SEEK_PRE_ROLL, self.seek_pre_roll_ns);
+        }
+        put_bytes(&mut entry, ebml::ID_CODEC_ID, self.codec_id.as_bytes());
+        if let Some(ref private) = self.codec_private {
+            put_bytes(&mut entry, ebml::ID_CODEC_PRIVATE, private);
+        }
+
+        match self.settings {
+            Settings::Video(ref video) => {
+                let mut payload = Vec::new();
+                put_uint(&mut payload, ebml::ID_PIXEL_WIDTH, video.width);
+                put_uint(&mut payload, ebml::ID_PIXEL_HEIGHT, video.height);
+                let optional = [
+                    (ebml::ID_DISPLAY_WIDTH, video.display_size.0),
+                    (ebml::ID_DISPLAY_HEIGHT, video.display_size.1),
+                    (ebml::ID_PIXEL_CROP_LEFT, video.crop.left),
+                    (ebml::ID_PIXEL_CROP_RIGHT, video.crop.right),
+                    (ebml::ID_PIXEL_CROP_TOP, video.crop.top),
+                    (ebml::ID_PIXEL_CROP_BOTTOM, video.crop.bottom),
+                    (ebml::ID_STEREO_MODE, video.stereo_mode),
+                    (ebml::ID_ALPHA_MODE, video.alpha_mode as u64),
+                ];
+                for &(id, value) in &optional {
+                    if value > 0 {
+                        put_uint(&mut payload, id, value);
+                    }
+                }
+                if video.frame_rate > 0.0 {
+                    put_float(&mut payload, ebml::ID_FRAME_RATE, video.frame_rate as f32);
+                }
+                if let Some(ref colour) = video.colour {
+                    put_colour(&mut payload, colour);
+                }
+                if let Some(ref projection) = video.projection {
+                    put_projection(&mut payload, projection);
+                }
+                put_bytes(&mut entry, ebml::ID_VIDEO, &payload);
+            }
+            Settings::Audio { sample_rate, channels } => {
+                let mut payload = Vec::new();
+                put_float(&mut payload, ebml::ID_SAMPLING_FREQUENCY, sample_rate as f32);
+                put_uint(&mut payload, ebml::ID_CHANNELS, channels);
+                put_bytes(&mut entry, ebml::ID_AUDIO, &payload);
+            }
+        }
+        put_bytes(buf, ebml::ID_TRACK_ENTRY, &entry);
+    }
+}
+
+/// Frames with a duration, discard padding or additional data need a
+/// BlockGroup.
+fn is_simple(frame: &Frame) -> bool {
+    additional(frame).is_none() && frame.discard_padding_ns == 0 && frame.duration_ns.unwrap_or(0) == 0
+}
+
+fn additional<'a>(frame: &Frame<'a>) -> Option<(&'a [u8], u64)> {
+    frame.additional.filter(|&(data, _)| !data.is_empty())
+}
+
+fn put_block(buf: &mut Vec<u8>, track: u64, relative_timecode: i16, frame: &Frame) {
+    let mut header = Vec::with_capacity(4);
+    put_size(&mut header, track);
+    header.extend_from_slice(&relative_timecode.to_be_bytes());
+    let lacing = frame.lacing.map_or(0, |lacing| (lacing as u8) << 1);
+
+    if is_simple(frame) {
+        header.push(if frame.keyframe { 0x80 | lacing } else { lacing });
+        put_id(buf, ebml::ID_SIMPLE_BLOCK);
+        put_size(buf, (header.len() + frame.data.len()) as u64);
+        buf.extend_from_slice(&header);
+        buf.extend_from_slice(frame.data);
+        return;
+    }
+
+    header.push(lacing);
+    let mut group = Vec::new();
+    put_id(&mut group, ebml::ID_BLOCK);
+    put_size(&mut group, (header.len() + frame.data.len()) as u64);
+    group.extend_from_slice(&header);
+    group.extend_from_slice(frame.data);
+    if let Some((data, add_id)) = additional(frame) {
+        let mut more = Vec::new();
+        put_uint(&mut more, ebml::ID_BLOCK_ADD_ID, add_id);
+        put_bytes(&mut more, ebml::ID_BLOCK_ADDITIONAL, data);
+        let mut additions = Vec::new();
+        put_bytes(&mut additions, ebml::ID_BLOCK_MORE, &more);
+        put_bytes(&mut group, ebml::ID_BLOCK_ADDITIONS, &additions);
+    }
+    if frame.discard_padding_ns != 0 {
+        put_int(&mut group, ebml::ID_DISCARD_PADDING, frame.discard_padding_ns);
+    }
+    if !frame.keyframe {
+        if let Some(reference_ns) = frame.reference_timestamp_ns {
+            // Relative to this block, as the specification has it.
+            let reference = (reference_ns / TIMECODE_SCALE_NS) as i64 -
+                (frame.timestamp_ns / TIMECODE_SCALE_NS) as i64;
+            put_int(&mut group, ebml::ID_REFERENCE_BLOCK, reference);
+        }
+    }
+    let duration = frame.duration_ns.unwrap_or(0) / TIMECODE_SCALE_NS;
+    if duration > 0 {
+        put_uint(&mut group, ebml::ID_BLOCK_DURATION, duration);
+    }
+    put_bytes(buf, ebml::ID_BLOCK_GROUP, &group);
+}
+
+/// An audio frame held back until a video frame at or after it arrives.
+struct QueuedFrame {
+    track: usize,
+    data: Vec<u8>,
+    additional: Option<(Vec<u8>, u64)>,
+    timestamp_ns: u64,
+    keyframe: bool,
+    duration_ns: Option<u64>,
+    discard_padding_ns: i64,
+    reference_timestamp_ns: Option<u64>,
+    lacing: Option<Lacing>,
+}
+
+impl QueuedFrame {
+    fn new(track: usize, frame: &Frame) -> QueuedFrame {
+        QueuedFrame {
+            track,
+            data: frame.data.to_vec(),
+            additional: frame.additional.map(|(data, add_id)| (data.to_vec(), add_id)),
+            timestamp_ns: frame.timestamp_ns,
+            keyframe: frame.keyframe,
+            duration_ns: frame.duration_ns,
+            discard_padding_ns: frame.discard_padding_ns,
+            reference_timestamp_ns: frame.reference_timestamp_ns,
+            lacing: frame.lacing,
+        }
+    }
+
+    fn frame(&self) -> Frame<'_> {
+        Frame {
+            data: &self.data,
+            timestamp_ns: self.timestamp_ns,
+            keyframe: self.keyframe,
+            duration_ns: self.duration_ns,
+            discard_padding_ns: self.discard_padding_ns,
+            reference_timestamp_ns: self.reference_timestamp_ns,
+            additional: self.additional.as_ref().map(|(data, add_id)| (&data[..], *add_id)),
+            lacing: self.lacing,
+        }
+    }
+}
+
+struct Cluster {
+    timecode: u64,
+    /// From the start of the segment payload, as Cues count.
+    offset: u64,
+    /// Where the size field is, once the header has gone out with the
+    /// first block.
+    size_pos: Option<u64>,
+    payload_size: u64,
+    blocks: u64,
+}
+
+struct CuePoint {
+    time: u64,
+    track: u64,
+    cluster_offset: u64,
+    block: u64,
+}
+
+/// Positions in the output of what `finalize` fills in.
+#[derive(Clone, Copy)]
+struct Header {
+    start: u64,
+    ebml_header_len: usize,
+    doc_type_version: u64,
+    size_pos: u64,
+    payload_pos: u64,
+    seek_head_pos: Option<u64>,
+    duration_pos: Option<u64>,
+}
+
+#[derive(Eq, PartialEq)]
+enum ClusterChange {
+    Keep,
+    Start,
+    /// The frame is too far past the cluster timecode for a block, even
+    /// after a new cluster takes the queued audio.
+    Overflow,
+}
+
+/// The muxer's side of the writer.
+struct Output {
+    writer: RawWriter,
+    error: *const ErrorCell,
+}
+
+impl Output {
+    fn error(&self) -> Error {
+        match peek_error(unsafe { &*self.error }) {
+            Some(e) => Error::Io(e),
+            None => Error::Muxer("write failed"),
+        }
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<()> {
+        match self.writer.element_start {
+            Some(element_start) => self.write_elements(element_start, buf),
+            None => self.write_raw(buf),
+        }
+    }
+
+    fn write_raw(&self, buf: &[u8]) -> Result<()> {
+        let written = (self.writer.write)(self.writer.dest, buf.as_ptr() as *const c_void, buf.len());
+        if written { Ok(()) } else { Err(self.error()) }
+    }
+
+    /// Writes `buf` an element at a time, announcing each one as libwebm
+    /// does, so a `WebmWriter` sees where clusters and blocks begin. Master
+    /// elements are entered; one of unknown size runs to the end of `buf`.
+    fn write_elements(&self, element_start: extern "C" fn(*mut c_void, u64, i64),
+                      buf: &[u8]) -> Result<()> {
+        let mut pos = 0;
+        while pos < buf.len() {
+            let (id, size, header_len) = match parse_header(&buf[pos..]) {
+                Some(header) => header,
+                None => return self.write_raw(&buf[pos..]),
+            };
+            element_start(self.writer.dest, id, self.position() as i64);
+            let data_start = pos + header_len;
+            let end = size.map_or(buf.len(), |size| (data_start as u64 + size).min(buf.len() as u64) as usize);
+            if element_type(id) == ElementType::Master {
+                self.write_raw(&buf[pos..data_start])?;
+                self.write_elements(element_start, &buf[data_start..end])?;
+            } else {
+                self.write_raw(&buf[pos..end])?;
+            }
+            pos = end;
+        }
+        Ok(())
+    }
+
+    fn position(&self) -> u64 {
+        (self.writer.get_pos)(self.writer.dest)
+    }
+
+    fn is_seekable(&self) -> bool {
+        self.writer.set_pos.is_some()
+    }
+
+    /// Overwrites what was written at `pos` and goes back to the end.
+    /// Does nothing on a writer that can not seek.
+    fn patch(&self, pos: u64, buf: &[u8]) -> Result<()> {
+        let set_pos = match self.writer.set_pos {
+            Some(set_pos) => set_pos,
+            None => return Ok(()),
+        };
+        let end = self.position();
+        if !set_pos(self.writer.dest, pos) {
+            return Err(self.error());
+        }
+        self.write_raw(buf)?;
+        if set_pos(self.writer.dest, end) { Ok(()) } else { Err(self.error()) }
+    }
+}
+
+pub(super) struct Muxer {
+    out: Output,
+    mode: SegmentMode,
+    pub(super) tracks: Vec<TrackEntry>,
+    pub(super) writing_app: Option<String>,
+    pub(super) output_cues: bool,
+    pub(super) cues_track: Option<usize>,
+    pub(super) max_cluster_duration_ns: u64,
+    pub(super) max_cluster_size: u64,
+    pub(super) force_new_cluster: bool,
+    header: Option<Header>,
+    doc_type_version: u64,
+    seek_entries: Vec<(u64, u64)>,
+    cluster: Option<Cluster>,
+    queued: Vec<QueuedFrame>,
+    cue_points: Vec<CuePoint>,
+    new_cue_point: bool,
+    last_timestamp_ns: u64,
+    last_block_duration_ns: u64,
+}
+
+impl Muxer {
+    pub(super) fn new(writer: RawWriter, error: *const ErrorCell, mode: SegmentMode) -> Muxer {
+        Muxer {
+            out: Output { writer, error },
+            mode,
+            tracks: Vec::new(),
+            writing_app: None,
+            output_cues: true,
+            cues_track: None,
+            max_cluster_duration_ns: DEFAULT_MAX_CLUSTER_DURATION_NS,
+            max_cluster_size: 0,
+            force_new_cluster: false,
+            header: None,
+            doc_type_version: 2,
+            seek_entries: Vec::new(),
+            cluster: None,
+            queued: Vec::new(),
+            cue_points: Vec::new(),
+            new_cue_point: false,
+            last_timestamp_ns: 0,
+            last_block_duration_ns: 0,
+        }
+    }
+
+    /// Numbers run from 1 to 126; 0 takes the lowest free one from the
+    /// track count on. Tracks can not be added once frames are written.
+    pub(super) fn add_track(&mut self, number: i32, codec_id: &'static str,
+                            settings: Settings) -> Result<usize> {
+        if self.header.is_some() || number < 0 || number as u64 > MAX_TRACK_NUMBER {
+            return Err(Error::InvalidTrack);
+        }
+        let taken = |n: u64| self.tracks.iter().any(|t| t.number == n);
+        let number = if number > 0 {
+            if taken(number as u64) {
+                return Err(Error::InvalidTrack);
+            }
+            number as u64
+        } else {
+            let mut n = self.tracks.len() as u64 + 1;
+            while taken(n) {
+                n += 1;
+            }
+            if n > MAX_TRACK_NUMBER {
+                return Err(Error::InvalidTrack);
+            }
+            n
+        };
+
+        self.tracks.push(TrackEntry {
+            number,
+            uid: make_uid(number),
+            codec_id,
+            codec_private: None,
+            codec_delay_ns: 0,
+            seek_pre_roll_ns: 0,
+            settings,
+            last_timestamp_ns: 0,
+        });
+        Ok(self.tracks.len() - 1)
+    }
+
+    fn has_video(&self) -> bool {
+        self.tracks.iter().any(TrackEntry::is_video)
+    }
+
+    /// Position relative to the segment payload.
+    fn offset(&self) -> u64 {
+        self.out.position() - self.header.map_or(0, |h| h.payload_pos)
+    }
+
+    fn update_doc_type_version(&mut self) {
+        if self.tracks.iter().any(|t| t.codec_delay_ns > 0 || t.seek_pre_roll_ns > 0) {
+            self.doc_type_version = 4;
+        }
+    }
+
+    /// Writes the EBML header, Info and Tracks before the first frame.
+    fn check_header(&mut self) -> Result<()> {
+        if self.header.is_some() {
+            return Ok(());
+        }
+        self.write_header()?;
+        let offset = self.offset();
+        self.seek_entries.push((ebml::ID_CLUSTER, offset));
+        if self.output_cues && self.cues_track.is_none() && !self.tracks.is_empty() {
+            self.cues_track = Some(self.tracks.iter().position(TrackEntry::is_video).unwrap_or(0));
+        }
+        Ok(())
+    }
+
+    fn write_header(&mut self) -> Result<()> {
+        if let Some(track) = self.tracks.iter().find(|t| t.codec_id == "V_AV1" && t.codec_private.is_none()) {
+            return Err(Error::BadCodecId(track.codec_id.to_string()));
+        }
+        self.update_doc_type_version();
+
+        let start = self.out.position();
+        let mut buf = Vec::new();
+        put_ebml_header(&mut buf, self.doc_type_version);
+        let ebml_header_len = buf.len();
+        put_id(&mut buf, ebml::ID_SEGMENT);
+        let size_pos = start + buf.len() as u64;
+        put_be(&mut buf, UNKNOWN_SIZE, 8);
+        let payload_pos = start + buf.len() as u64;
+
+        // Only a seekable file gets a SeekHead and Duration, both filled
+        // in by `finalize`.
+        let seekable_file = self.mode == SegmentMode::File && self.out.is_seekable();
+        let seek_head_pos = if seekable_file {
+            let pos = start + buf.len() as u64;
+            put_void(&mut buf, SEEK_HEAD_SIZE);
+            Some(pos)
+        } else {
+            None
+        };
+
+        self.seek_entries.push((ebml::ID_INFO, start + buf.len() as u64 - payload_pos));
+        let mut info = Vec::new();
+        put_uint(&mut info, ebml::ID_TIMECODE_SCALE, TIMECODE_SCALE_NS);
+        let duration_offset = if seekable_file {
+            let offset = info.len();
+            put_float(&mut info, ebml::ID_DURATION, 1.0);
+            Some(offset)
+        } else {
+            None
+        };
+        put_bytes(&mut info, ebml::ID_MUXING_APP, MUXING_APP.as_bytes());
+        put_bytes(&mut info, ebml::ID_WRITING_APP, self.writing_app.as_deref().unwrap_or(MUXING_APP).as_bytes());
+        put_id(&mut buf, ebml::ID_INFO);
+        put_size(&mut buf, info.len() as u64);
+        let duration_pos = duration_offset.map(|offset| start + (buf.len() + offset) as u64);
+        buf.extend_from_slice(&info);
+
+        self.seek_entries.push((ebml::ID_TRACKS, start + buf.len() as u64 - payload_pos));
+        let mut tracks = Vec::new();
+        for track in &self.tracks {
+            track.put(&mut tracks);
+        }
+        put_bytes(&mut buf, ebml::ID_TRACKS, &tracks);
+
+        self.out.write(&buf)?;
+        self.header = Some(Header {
+            start,
+            ebml_header_len,
+            doc_type_version: self.doc_type_version,
+            size_pos,
+            payload_pos,
+            seek_head_pos,
+            duration_pos,
+        });
+        Ok(())
+    }
+
+    pub(super) fn add_frame(&mut self, track: usize, frame: &Frame) -> Result<()> {
+        if frame.data.is_empty() {
+            return Err(Error::InvalidArgument("empty frame"));
+        }
+        self.check_header()?;
+        if frame.timestamp_ns < self.last_timestamp_ns {
+            return Err(Error::NonMonotonicTimestamp {
+                timestamp_ns: frame.timestamp_ns,
+                last_timestamp_ns: self.last_timestamp_ns,
+            });
+        }
+        if frame.discard_padding_ns != 0 {
+            self.doc_type_version = 4;
+        }
+        if let Some(ref cluster) = self.cluster {
+            if (frame.timestamp_ns / TIMECODE_SCALE_NS).saturating_sub(cluster.timecode) > MAX_BLOCK_TIMECODE {
+                self.force_new_cluster = true;
+            }
+        }
+
+        // Audio waits for the video, so that clusters start at video
+        // frames and blocks come out in timestamp order.
+        if !self.tracks[track].is_video() && self.has_video() && !self.force_new_cluster {
+            self.queued.push(QueuedFrame::new(track, frame));
+            return Ok(());
+        }
+
+        self.start_cluster_if_due(track, frame.timestamp_ns, frame.keyframe)?;
+        self.write_frame(track, frame)?;
+        self.last_timestamp_ns = frame.timestamp_ns;
+        self.last_block_duration_ns = frame.duration_ns.unwrap_or(0);
+        Ok(())
+    }
+
+    fn should_start_cluster(&self, track: usize, timestamp_ns: u64, keyframe: bool) -> Result<ClusterChange> {
+        let cluster = match self.cluster {
+            Some(ref cluster) if !self.force_new_cluster => cluster,
+            _ => return Ok(ClusterChange::Start),
+        };
+        let timecode = timestamp_ns / TIMECODE_SCALE_NS;
+        if timecode < cluster.timecode {
+            return Err(Error::Muxer("frame comes before its cluster"));
+        }
+        let delta = timecode - cluster.timecode;
+        if delta > MAX_BLOCK_TIMECODE {
+            return Ok(ClusterChange::Overflow);
+        }
+        let full = (self.tracks[track].is_video() && keyframe) ||
+            (self.max_cluster_duration_ns > 0 && delta * TIMECODE_SCALE_NS >= self.max_cluster_duration_ns) ||
+            (self.max_cluster_size > 0 && cluster.payload_size >= self.max_cluster_size);
+        Ok(if full { ClusterChange::Start } else { ClusterChange::Keep })
+    }
+
+    fn start_cluster_if_due(&mut self, track: usize, timestamp_ns: u64, keyframe: bool) -> Result<()> {
+        loop {
+            let change = self.should_start_cluster(track, timestamp_ns, keyframe)?;
+            self.force_new_cluster = false;
+            if change != ClusterChange::Keep {
+                self.start_cluster(timestamp_ns)?;
+            }
+            self.write_queued()?;
+            if change != ClusterChange::Overflow {
+                return Ok(());
+            }
+        }
+    }
+
+    fn start_cluster(&mut self, timestamp_ns: u64) -> Result<()> {
+        if self.cluster.is_some() {
+            self.write_queued_before(timestamp_ns)?;
+        }
+        if let Some(cluster) = self.cluster.take() {
+            self.finish_cluster(&cluster)?;
+        }
+        if self.output_cues {
+            self.new_cue_point = true;
+        }
+        let mut timecode = timestamp_ns / TIMECODE_SCALE_NS;
+        if let Some(first) = self.queued.first() {
+            timecode = timecode.min(first.timestamp_ns / TIMECODE_SCALE_NS);
+        }
+        self.cluster = Some(Cluster {
+            timecode,
+            offset: self.offset(),
+            size_pos: None,
+            payload_size: 0,
+            blocks: 0,
+        });
+        Ok(())
+    }
+
+    /// Fills in the cluster's size, where the writer can seek.
+    fn finish_cluster(&self, cluster: &Cluster) -> Result<()> {
+        match cluster.size_pos {
+            Some(pos) => {
+                let mut size = Vec::with_capacity(8);
+                put_size_len(&mut size, cluster.payload_size, 8);
+                self.out.patch(pos, &size)
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn write_queued_frame(&mut self, queued: &QueuedFrame) -> Result<()> {
+        self.write_frame(queued.track, &queued.frame())?;
+        self.last_timestamp_ns = self.last_timestamp_ns.max(queued.timestamp_ns);
+        Ok(())
+    }
+
+    fn write_queued(&mut self) -> Result<()> {
+        for queued in mem::take(&mut self.queued) {
+            self.write_queued_frame(&queued)?;
+        }
+        Ok(())
+    }
+
+    /// Writes queued audio into the cluster about to be closed. As in
+    /// libwebm, the last frame not after `timestamp_ns` stays queued and
+    /// opens the next cluster.
+    fn write_queued_before(&mut self, timestamp_ns: u64) -> Result<()> {
+        let count = self.queued.windows(2).take_while(|w| w[1].timestamp_ns <= timestamp_ns).count();
+        let written: Vec<QueuedFrame> = self.queued.drain(..count).collect();
+        for queued in &written {
+            self.write_queued_frame(queued)?;
+        }
+        Ok(())
+    }
+
+    fn write_frame(&mut self, track: usize, frame: &Frame) -> Result<()> {
+        let mut frame = *frame;
+        if !is_simple(&frame) && !frame.keyframe && frame.reference_timestamp_ns.is_none() {
+            frame.reference_timestamp_ns = Some(self.tracks[track].last_timestamp_ns);
+        }
+        let number = self.tracks[track].number;
+        let start = self.out.position();
+        let cluster = self.cluster.as_mut().ok_or(Error::Muxer("no cluster to write to"))?;
+        let relative_timecode = (frame.timestamp_ns / TIMECODE_SCALE_NS) as i64 - cluster.timecode as i64;
+        if relative_timecode < 0 || relative_timecode > MAX_BLOCK_TIMECODE as i64 {
+            return Err(Error::Muxer("frame too far from its cluster timecode"));
+        }
+
+        let mut buf = Vec::new();
+        if cluster.size_pos.is_none() {
+            put_id(&mut buf, ebml::ID_CLUSTER);
+            put_be(&mut buf, UNKNOWN_SIZE, 8);
+            put_uint(&mut buf, ebml::ID_TIMECODE, cluster.timecode);
+        }
+        let header_len = buf.len();
+        put_block(&mut buf, number, relative_timecode as i16, &frame);
+        self.out.write(&buf)?;
+
+        if header_len > 0 {
+            cluster.size_pos = Some(start + 4);
+            cluster.payload_size = header_len as u64 - 12;
+        }
+        cluster.payload_size += (buf.len() - header_len) as u64;
+        cluster.blocks += 1;
+
+        if self.new_cue_point && self.cues_track == Some(track) {
+            self.cue_points.push(CuePoint {
+                time: frame.timestamp_ns / TIMECODE_SCALE_NS,
+                track: number,
+                cluster_offset: cluster.offset,
+                block: cluster.blocks,
+            });
+            self.new_cue_point = false;
+        }
+        self.tracks[track].last_timestamp_ns = frame.timestamp_ns;
+        Ok(())
+    }
+
+    /// `duration` is in timecode units (ms); 0 takes the end of the last
+    /// block.
+    pub(super) fn finalize(&mut self, duration: u64) -> Result<()> {
+        self.check_header()?;
+        if self.cluster.is_none() && !self.queued.is_empty() {
+            let first = self.queued[0].timestamp_ns;
+            self.start_cluster(first)?;
+        }
+        self.write_queued()?;
+        if self.mode == SegmentMode::Live {
+            return Ok(());
+        }
+        if let Some(cluster) = self.cluster.take() {
+            self.finish_cluster(&cluster)?;
+        }
+
+        let header = self.header.expect("check_header wrote the header");
+        let duration = if duration > 0 {
+            duration as f64
+        } else {
+            (self.last_timestamp_ns + self.last_block_duration_ns) as f64 / TIMECODE_SCALE_NS as f64
+        };
+        if let Some(pos) = header.duration_pos {
+            if duration > 0.0 {
+                let mut element = Vec::new();
+                put_float(&mut element, ebml::ID_DURATION, duration as f32);
+                self.out.patch(pos, &element)?;
+            }
+        }
+
+        if self.output_cues {
+            let offset = self.offset();
+            self.seek_entries.push((ebml::ID_CUES, offset));
+            let mut cues = Vec::new();
+            for cue in &self.cue_points {
+                cue.put(&mut cues);
+            }
+            let mut buf = Vec::new();
+            put_bytes(&mut buf, ebml::ID_CUES, &cues);
+            self.out.write(&buf)?;
+        }
+
+        if !self.out.is_seekable() {
+            return Ok(());
+        }
+        if let Some(pos) = header.seek_head_pos {
+            let mut entries = Vec::new();
+            for &(id, position) in &self.seek_entries {
+                let mut seek = Vec::new();
+                put_uint(&mut seek, ebml::ID_SEEK_ID, id);
+                put_uint(&mut seek, ebml::ID_SEEK_POSITION, position);
+                put_bytes(&mut entries, ebml::ID_SEEK, &seek);
+            }
+            let mut buf = Vec::new();
+            put_bytes(&mut buf, ebml::ID_SEEK_HEAD, &entries);
+            let rest = SEEK_HEAD_SIZE - buf.len();
+            put_void(&mut buf, rest);
+            self.out.patch(pos, &buf)?;
+        }
+
+        let segment_size = self.offset();
+        self.update_doc_type_version();
+        if self.doc_type_version != header.doc_type_version {
+            let mut buf = Vec::new();
+            put_ebml_header(&mut buf, self.doc_type_version);
+            if buf.len() != header.ebml_header_len {
+                return Err(Error::Muxer("EBML header changed size"));
+            }
+            self.out.patch(header.start, &buf)?;
+        }
+        let mut size = Vec::with_capacity(8);
+        put_size_len(&mut size, segment_size, 8);
+        self.out.patch(header.size_pos, &size)
+    }
+}
+
+impl CuePoint {
+    fn put(&self, buf: &mut Vec<u8>) {
+        let mut positions = Vec::new();
+        put_uint(&mut positions, ebml::ID_CUE_TRACK, self.track);
+        put_uint(&mut positions, ebml::ID_CUE_CLUSTER_POSITION, self.cluster_offset);
+        if self.block > 1 {
+            put_uint(&mut positions, ebml::ID_CUE_BLOCK_NUMBER, self.block);
+        }
+        let mut point = Vec::new();
+        put_uint(&mut point, ebml::ID_CUE_TIME, self.time);
+        put_bytes(&mut point, ebml::ID_CUE_TRACK_POSITIONS, &positions);
+        put_bytes(buf, ebml::ID_CUE_POINT, &point);
+    }
+}
+
+fn put_ebml_header(buf: &mut Vec<u8>, doc_type_version: u64) {
+    let mut header = Vec::new();
+    put_uint(&mut header, ebml::ID_EBML_VERSION, 1);
+    put_uint(&mut header, ebml::ID_EBML_READ_VERSION, 1);
+    put_uint(&mut header, ebml::ID_EBML_MAX_ID_LENGTH, 4);
+    put_uint(&mut header, ebml::ID_EBML_MAX_SIZE_LENGTH, 8);
+    put_bytes(&mut header, ebml::ID_DOC_TYPE, b"webm");
+    put_uint(&mut header, ebml::ID_DOC_TYPE_VERSION, doc_type_version);
+    put_uint(&mut header, ebml::ID_DOC_TYPE_READ_VERSION, 2);
+    put_bytes(buf, ebml::ID_EBML, &header);
+}
+
+#[cfg(test)]
+fn record(mode: SegmentMode, writer: Box<super::Writer<::std::io::Cursor<Vec<u8>>>>) -> Vec<u8> {
+    use super::{AudioCodecId, Segment, Track, VideoCodecId};
+    use add_test_video;
+
+    let segment = Segment::with_mode(writer, mode).unwrap();
+    let mut vt = add_test_video(&segment, VideoCodecId::VP9);
+    let mut at = segment.add_audio_track(48_000, 2, None, AudioCodecId::Opus).unwrap();
+    at.set_codec_private(&super::opus_head(48_000, 2, 312).unwrap()).unwrap();
+    for i in 0..100u64 {
+        vt.add_frame(&[i as u8; 200], i * 40_000_000, i % 25 == 0).unwrap();
+        at.add_frame(&[i as u8; 30], i * 40_000_000 + 10_000_000, true).unwrap();
+    }
+    segment.finalize(None).ok().unwrap().into_inner().into_inner()
+}
+
+#[test]
+fn file_layout() {
+    use ebml::{Reader, Value};
+    use std::io::Cursor;
+    use super::Writer;
+
+    let bytes = record(SegmentMode::File, Writer::new(Cursor::new(Vec::new())));
+    assert_eq!(::validate::validate(Cursor::new(bytes.clone())).unwrap(), vec![]);
+
+    let mut reader = Reader::new(Cursor::new(bytes.clone())).unwrap();
+    let top = reader.elements_from(0).unwrap();
+    assert_eq!(top.iter().map(|e| e.id).collect::<Vec<_>>(), vec![ebml::ID_EBML, ebml::ID_SEGMENT]);
+    let segment = top[1];
+    assert_eq!(segment.end(), Some(bytes.len() as u64));
+    let children = reader.children(&segment).unwrap();
+    let ids: Vec<u64> = children.iter().map(|e| e.id).filter(|&id| id != ebml::ID_CLUSTER).collect();
+    assert_eq!(ids, vec![ebml::ID_SEEK_HEAD, ebml::ID_VOID, ebml::ID_INFO, ebml::ID_TRACKS, ebml::ID_CUES]);
+    let clusters: Vec<_> = children.iter().filter(|e| e.id == ebml::ID_CLUSTER).collect();
+    assert_eq!(clusters.len(), 4);
+    assert!(clusters.iter().all(|c| c.size.is_some()));
+
+    // Every SeekHead entry points at the element it names.
+    let seek_head = children[0];
+    let mut targets = Vec::new();
+    for seek in reader.children(&seek_head).unwrap() {
+        let fields = reader.children(&seek).unwrap();
+        let id = reader.read_data(&fields[0], 8).unwrap().iter().fold(0u64, |v, &b| (v << 8) | u64::from(b));
+        let position = match reader.read_value(&fields[1]).unwrap() {
+            Some(Value::Uint(position)) => position,
+            other => panic!("{:?}", other),
+        };
+        let target = reader.element_at(segment.data_offset() + position).unwrap().unwrap();
+        assert_eq!(target.id, id);
+        targets.push(id);
+    }
+    assert_eq!(targets, vec![ebml::ID_INFO, ebml::ID_TRACKS, ebml::ID_CLUSTER, ebml::ID_CUES]);
+
+    let info = children.iter().find(|e| e.id == ebml::ID_INFO).unwrap();
+    let duration = reader.children(info).unwrap().into_iter().find(|e| e.id == ebml::ID_DURATION).unwrap();
+    assert_eq!(reader.read_value(&duration).unwrap(), Some(Value::Float(3970.0)));
+
+    // One cue per cluster, pointing at its video keyframe; the audio held
+    // back from before the keyframe opens the cluster.
+    let cues = children.iter().find(|e| e.id == ebml::ID_CUES).unwrap();
+    let cue_points = reader.children(cues).unwrap();
+    assert_eq!(cue_points.len(), clusters.len());
+    for (i, (point, cluster)) in cue_points.iter().zip(&clusters).enumerate() {
+        let fields = reader.children(point).unwrap();
+        assert_eq!(reader.read_value(&fields[0]).unwrap(), Some(Value::Uint(i as u64 * 1000)));
+        let positions: Vec<Option<Value>> = reader.children(&fields[1]).unwrap().iter()
+            .map(|e| reader.read_value(e).unwrap()).collect();
+        assert_eq!(positions[1], Some(Value::Uint(cluster.offset - segment.data_offset())));
+        let block_number = match positions.get(2) {
+            Some(&Some(Value::Uint(n))) => n as usize,
+            _ => 1,
+        };
+        let block = reader.children(cluster).unwrap()[block_number];
+        let data = reader.read_data(&block, 4).unwrap();
+        let header = ::ebml::parse_block_header(&data, true).unwrap();
+        assert_eq!((header.track, header.keyframe), (1, true));
+    }
+}
+
+#[test]
+fn live_layout() {
+    use ebml::{parse_block_header, Reader};
+    use std::io::Cursor;
+    use super::Writer;
+
+    let bytes = record(SegmentMode::Live, Writer::new_non_seekable(Cursor::new(Vec::new())));
+    let mut reader = Reader::new(Cursor::new(bytes)).unwrap();
+    let segment = reader.elements_from(0).unwrap()[1];
+    assert_eq!(segment.size, None);
+    let children = reader.children(&segment).unwrap();
+    assert_eq!(children.iter().map(|e| e.id).filter(|&id| id != ebml::ID_CLUSTER).collect::<Vec<_>>(),
+               vec![ebml::ID_INFO, ebml::ID_TRACKS]);
+
+    // Blocks come out in timestamp order across tracks.
+    let mut timestamps = Vec::new();
+    for cluster in children.iter().filter(|e| e.id == ebml::ID_CLUSTER) {
+        assert_eq!(cluster.size, None);
+        let blocks = reader.children(cluster).unwrap();
+        let timecode = reader.read_data(&blocks[0], 8).unwrap().iter().fold(0i64, |v, &b| (v << 8) | i64::from(b));
+        for block in &blocks[1..] {
+            let header = parse_block_header(&reader.read_data(block, 4).unwrap(), true).unwrap();
+            timestamps.push(timecode + i64::from(header.timecode));
+        }
+    }
+    assert_eq!(timestamps.len(), 200);
+    assert!(timestamps.windows(2).all(|w| w[0] <= w[1]));
+}
+
+#[test]
+fn block_groups() {
+    use ebml::{Reader, Value};
+    use std::io::Cursor;
+    use super::{SegmentMode, Track, VideoCodecId};
+    use {add_test_video, mux_to_vec};
+
+    let bytes = mux_to_vec(SegmentMode::Live, |segment| {
+        let mut vt = add_test_video(segment, VideoCodecId::VP8);
+        vt.add_frame(&[1; 10], 0, true).unwrap();
+        vt.add_generic_frame(&Frame::new(&[2; 10], 40_000_000).keyframe(false).duration_ns(40_000_000)).unwrap();
+        vt.add_frame_with_additional(&[3; 10], &[9; 4], 1, 80_000_000, false).unwrap();
+        assert!(matches!(vt.add_frame(&[], 120_000_000, false), Err(Error::InvalidArgument(_))));
+        assert!(matches!(vt.add_frame(&[4; 10], 20_000_000, false),
+                         Err(Error::NonMonotonicTimestamp { last_timestamp_ns: 80_000_000, .. })));
+    });
+
+    let mut reader = Reader::new(Cursor::new(bytes)).unwrap();
+    let segment = reader.elements_from(0).unwrap()[1];
+    let cluster = reader.children(&segment).unwrap().into_iter().find(|e| e.id == ebml::ID_CLUSTER).unwrap();
+    let blocks = reader.children(&cluster).unwrap();
+    assert_eq!(blocks.iter().map(|e| e.id).collect::<Vec<_>>(),
+               vec![ebml::ID_TIMECODE, ebml::ID_SIMPLE_BLOCK, ebml::ID_BLOCK_GROUP, ebml::ID_BLOCK_GROUP]);
+
+    // The reference defaults to the track's previous frame, 40 ms back.
+    let group: Vec<(u64, Option<Value>)> = reader.children(&blocks[2]).unwrap().iter()
+        .map(|e| (e.id, reader.read_value(e).unwrap())).collect();
+    assert_eq!(group[1..].to_vec(), vec![(ebml::ID_REFERENCE_BLOCK, Some(Value::Int(-40))),
+                                         (ebml::ID_BLOCK_DURATION, Some(Value::Uint(40)))]);
+    let group: Vec<u64> = reader.children(&blocks[3]).unwrap().iter().map(|e| e.id).collect();
+    assert_eq!(group, vec![ebml::ID_BLOCK, ebml::ID_BLOCK_ADDITIONS, ebml::ID_REFERENCE_BLOCK]);
+}
@@ -0,0 +1,202 @@
+//! The Rust side of `Segment` and its tracks, built instead of libwebm with
+//! the `pure-rust` feature when `libwebm` is off. It produces the same
+//! layout; text tracks, chapters, tags and `finalize_cues_first` lean on
+//! libwebm internals and are left out.
+
+use std::cell::RefCell;
+use std::ptr;
+
+use super::muxer::{self, Muxer, Settings, VideoSettings};
+use super::{AudioCodecId, Colour, Crop, ErrorCell, Frame, Projection, RawWriter, SegmentMode,
+            StereoMode, VideoCodecId};
+use {Error, Result};
+
+/// Tracks are referred to by their index in the muxer.
+pub type TrackHandle = usize;
+pub type VideoHandle = usize;
+pub type AudioHandle = usize;
+
+/// The muxer behind a `Segment`.
+#[doc(hidden)]
+pub struct SegmentCore {
+    muxer: RefCell<Muxer>,
+}
+
+impl PartialEq for SegmentCore {
+    fn eq(&self, other: &SegmentCore) -> bool {
+        ptr::eq(self, other)
+    }
+}
+impl Eq for SegmentCore {}
+
+impl SegmentCore {
+    pub(super) fn new(writer: RawWriter, writer_error: *const ErrorCell,
+                      mode: SegmentMode) -> Result<SegmentCore> {
+        Ok(SegmentCore {
+            muxer: RefCell::new(Muxer::new(writer, writer_error, mode)),
+        })
+    }
+
+    pub(super) fn finalize(self, duration: u64) -> Result<()> {
+        self.muxer.borrow_mut().finalize(duration)
+    }
+
+    pub(super) fn track_number(&self, track: TrackHandle) -> u64 {
+        self.muxer.borrow().tracks[track].number
+    }
+
+    pub(super) fn track_by_number(&self, number: u64) -> Option<TrackHandle> {
+        self.muxer.borrow().tracks.iter().position(|t| t.number == number)
+    }
+
+    pub(super) fn add_frame(&self, track: TrackHandle, data: &[u8], timestamp_ns: u64,
+                            keyframe: bool) -> Result<()> {
+        self.add_generic_frame(track, &Frame::new(data, timestamp_ns).keyframe(keyframe))
+    }
+
+    pub(super) fn add_frame_with_additional(&self, track: TrackHandle, data: &[u8],
+                                            additional: &[u8], add_id: u64,
+                                            timestamp_ns: u64, keyframe: bool) -> Result<()> {
+        let frame = Frame::new(data, timestamp_ns).keyframe(keyframe).additional(additional, add_id);
+        self.add_generic_frame(track, &frame)
+    }
+
+    pub(super) fn add_generic_frame(&self, track: TrackHandle, frame: &Frame) -> Result<()> {
+        self.muxer.borrow_mut().add_frame(track, frame)
+    }
+
+    pub(super) fn set_codec_private(&self, track: TrackHandle, data: &[u8]) -> Result<()> {
+        if data.is_empty() {
+            return Err(Error::Muxer("could not store the CodecPrivate"));
+        }
+        self.muxer.borrow_mut().tracks[track].codec_private = Some(data.to_vec());
+        Ok(())
+    }
+
+    pub(super) fn set_cues_track(&self, track: TrackHandle) -> Result<()> {
+        self.muxer.borrow_mut().cues_track = Some(track);
+        Ok(())
+    }
+
+    pub(super) fn output_cues(&self, output_cues: bool) {
+        self.muxer.borrow_mut().output_cues = output_cues;
+    }
+
+    pub(super) fn set_max_cluster_duration(&self, duration_ns: u64) {
+        self.muxer.borrow_mut().max_cluster_duration_ns = duration_ns;
+    }
+
+    pub(super) fn set_max_cluster_size(&self, size_bytes: u64) {
+        self.muxer.borrow_mut().max_cluster_size = size_bytes;
+    }
+
+    pub(super) fn force_new_cluster(&self) {
+        self.muxer.borrow_mut().force_new_cluster = true;
+    }
+
+    pub(super) fn set_app_name(&self, name: &str) -> Result<()> {
+        if name.contains('\0') {
+            return Err(Error::InvalidArgument("NUL byte in app name"));
+        }
+        self.muxer.borrow_mut().writing_app = Some(name.to_string());
+        Ok(())
+    }
+
+    pub(super) fn add_video_track(&self, width: u32, height: u32, id: i32,
+                                  codec: VideoCodecId) -> Result<VideoHandle> {
+        let settings = Settings::Video(Box::new(VideoSettings {
+            width: width.into(),
+            height: height.into(),
+            ..VideoSettings::default()
+        }));
+        self.muxer.borrow_mut().add_track(id, codec.get_id(), settings)
+    }
+
+    pub(super) fn add_audio_track(&self, sample_rate: i32, channels: i32, id: i32,
+                                  codec: AudioCodecId) -> Result<AudioHandle> {
+        let settings = Settings::Audio {
+            sample_rate: sample_rate.into(),
+            channels: channels.max(0) as u64,
+        };
+        self.muxer.borrow_mut().add_track(id, codec.get_id(), settings)
+    }
+
+    pub(super) fn video_track(&self, video: VideoHandle) -> TrackHandle {
+        video
+    }
+
+    pub(super) fn audio_track(&self, audio: AudioHandle) -> TrackHandle {
+        audio
+    }
+
+    fn update_video(&self, video: VideoHandle, f: impl FnOnce(&mut VideoSettings)) {
+        if let Settings::Video(ref mut settings) = self.muxer.borrow_mut().tracks[video].settings {
+            f(settings);
+        }
+    }
+
+    pub(super) fn set_colour(&self, video: VideoHandle, colour: &Colour) -> Result<()> {
+        if !muxer::colour_is_valid(colour) {
+            return Err(Error::InvalidArgument("colour value out of range"));
+        }
+        self.update_video(video, |settings| settings.colour = Some(*colour));
+        Ok(())
+    }
+
+    pub(super) fn set_display_size(&self, video: VideoHandle, width: u64, height: u64) {
+        self.update_video(video, |settings| settings.display_size = (width, height));
+    }
+
+    pub(super) fn set_crop(&self, video: VideoHandle, crop: Crop) {
+        self.update_video(video, |settings| settings.crop = crop);
+    }
+
+    pub(super) fn set_stereo_mode(&self, video: VideoHandle, mode: StereoMode) -> Result<()> {
+        // libwebm refuses the values `StereoMode` has no variant for, so
+        // there is nothing left to check.
+        self.update_video(video, |settings| settings.stereo_mode = mode as u64);
+        Ok(())
+    }
+
+    pub(super) fn set_alpha_mode(&self, video: VideoHandle, has_alpha: bool) -> Result<()> {
+        self.update_video(video, |settings| settings.alpha_mode = has_alpha);
+        Ok(())
+    }
+
+    pub(super) fn set_frame_rate(&self, video: VideoHandle, frame_rate: f64) {
+        self.update_video(video, |settings| settings.frame_rate = frame_rate);
+    }
+
+    pub(super) fn set_projection(&self, video: VideoHandle, projection: &Projection) -> Result<()> {
+        // libwebm stores any projection too; it only fails to allocate.
+        self.update_video(video, |settings| settings.projection = Some(projection.clone()));
+        Ok(())
+    }
+
+    pub(super) fn set_codec_delay(&self, audio: AudioHandle, delay_ns: u64) {
+        self.muxer.borrow_mut().tracks[audio].codec_delay_ns = delay_ns;
+    }
+
+    pub(super) fn set_seek_pre_roll(&self, audio: AudioHandle, pre_roll_ns: u64) {
+        self.muxer.borrow_mut().tracks[audio].seek_pre_roll_ns = pre_roll_ns;
+    }
+}
+
+impl AudioCodecId {
+    fn get_id(&self) -> &'static str {
+        match self {
+            AudioCodecId::Opus => "A_OPUS",
+            AudioCodecId::Vorbis => "A_VORBIS",
+        }
+    }
+}
+
+impl VideoCodecId {
+    fn get_id(&self) -> &'static str {
+        match self {
+            VideoCodecId::VP8 => "V_VP8",
+            VideoCodecId::VP9 => "V_VP9",
+            VideoCodecId::AV1 => "V_AV1",
+        }
+    }
+}
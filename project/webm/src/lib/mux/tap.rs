@@ -0,0 +1,148 @@
+//! Follows the byte stream the muxer hands to the writer closely enough to see
+//! cluster timecodes, track types and block headers as they go by, without
+//! keeping more than a few header bytes around.
+
+use ebml;
+
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum Event {
+    ClusterTimecode(u64),
+    TrackNumber(u64),
+    TrackType(u64),
+    /// `timecode` is relative to the enclosing cluster.
+    Block { track: u64, timecode: i16, keyframe: bool },
+}
+
+#[derive(Default)]
+pub struct ElementTap {
+    capture: Option<(u64, Vec<u8>)>,
+    /// A `Block` whose keyframe flag is only settled by the absence of a
+    /// `ReferenceBlock` among the rest of its `BlockGroup`.
+    group_block: Option<Event>,
+}
+
+impl ElementTap {
+    /// Called when the muxer is about to write the ID of element `id`.
+    pub fn start(&mut self, id: u64) -> Option<Event> {
+        self.capture = None;
+
+        let mut settled = None;
+        if let Some(Event::Block { track, timecode, .. }) = self.group_block {
+            if id == ebml::ID_REFERENCE_BLOCK {
+                self.group_block = None;
+                settled = Some(Event::Block { track, timecode, keyframe: false });
+            } else if !is_block_group_child(id) {
+                settled = self.group_block.take();
+            }
+        }
+
+        if id == ebml::ID_TIMECODE ||
+            id == ebml::ID_TRACK_NUMBER ||
+            id == ebml::ID_TRACK_TYPE ||
+            id == ebml::ID_BLOCK ||
+            id == ebml::ID_SIMPLE_BLOCK {
+            self.capture = Some((id, Vec::with_capacity(16)));
+        }
+        settled
+    }
+
+    /// Called with bytes appended to the stream.
+    pub fn feed(&mut self, buf: &[u8]) -> Option<Event> {
+        let (id, event) = {
+            let (id, bytes) = self.capture.as_mut()?;
+            let mut event = None;
+            for &b in buf.iter().take(MAX_HEADER_LEN) {
+                bytes.push(b);
+                event = parse(*id, bytes);
+                if event.is_some() {
+                    break;
+                }
+            }
+            if event.is_none() && bytes.len() < MAX_HEADER_LEN {
+                return None;
+            }
+            (*id, event)
+        };
+        self.capture = None;
+
+        if id == ebml::ID_BLOCK {
+            // Inside a BlockGroup the flags byte is always zero; the block is
+            // a keyframe unless a ReferenceBlock follows it.
+            if let Some(Event::Block { track, timecode, .. }) = event {
+                self.group_block = Some(Event::Block { track, timecode, keyframe: true });
+            }
+            return None;
+        }
+        event
+    }
+
+    /// Hands over a block still waiting on the rest of its group, once
+    /// nothing more will be written.
+    pub fn finish(&mut self) -> Option<Event> {
+        self.capture = None;
+        self.group_block.take()
+    }
+}
+
+/// Longest header we ever need: a 4 byte ID, an 8 byte size and either an 8
+/// byte value or a block's track number, timecode and flags.
+const MAX_HEADER_LEN: usize = 4 + 8 + 12;
+
+fn id_len(id: u64) -> usize {
+    match id {
+        0..=0xFF => 1,
+        0x100..=0xFFFF => 2,
+        0x1_0000..=0xFF_FFFF => 3,
+        _ => 4,
+    }
+}
+
+/// Decodes an EBML variable length integer, returning the value with its
+/// length marker removed and the number of bytes it took.
+fn read_vint(bytes: &[u8]) -> Option<(u64, usize)> {
+    let first = *bytes.first()?;
+    if first == 0 {
+        return None;
+    }
+    let len = first.leading_zeros() as usize + 1;
+    if bytes.len() < len {
+        return None;
+    }
+    let mut value = u64::from(first) & ((1u64 << (8 - len)) - 1);
+    for &b in &bytes[1..len] {
+        value = (value << 8) | u64::from(b);
+    }
+    Some((value, len))
+}
+
+fn parse(id: u64, bytes: &[u8]) -> Option<Event> {
+    let payload_start = id_len(id);
+    let (size, size_len) = read_vint(bytes.get(payload_start..)?)?;
+    let payload = &bytes[payload_start + size_len..];
+
+    if id == ebml::ID_BLOCK || id == ebml::ID_SIMPLE_BLOCK {
+        let (track, track_len) = read_vint(payload)?;
+        let header = payload.get(track_len..track_len + 3)?;
+        return Some(Event::Block {
+            track,
+            timecode: i16::from_be_bytes([header[0], header[1]]),
+            keyframe: id == ebml::ID_SIMPLE_BLOCK && header[2] & 0x80 != 0,
+        });
+    }
+
+    let value = payload.get(..size as usize)?.iter().fold(0u64, |v, &b| (v << 8) | u64::from(b));
+    Some(match id {
+        ebml::ID_TIMECODE => Event::ClusterTimecode(value),
+        ebml::ID_TRACK_NUMBER => Event::TrackNumber(value),
+        _ => Event::TrackType(value),
+    })
+}
+
+fn is_block_group_child(id: u64) -> bool {
+    id == ebml::ID_BLOCK_ADDITIONS ||
+        id == ebml::ID_BLOCK_MORE ||
+        id == ebml::ID_BLOCK_ADD_ID ||
+        id == ebml::ID_BLOCK_ADDITIONAL ||
+        id == ebml::ID_BLOCK_DURATION ||
+        id == ebml::ID_DISCARD_PADDING
+}
@@ -0,0 +1,42 @@
+//! Video track properties beyond size and colour: how to display the
+//! picture, stereo layout and spherical projection.
+
+/// Layout of the two views of stereoscopic video.
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum StereoMode {
+    Mono = 0,
+    SideBySideLeftFirst = 1,
+    TopBottomRightFirst = 2,
+    TopBottomLeftFirst = 3,
+    SideBySideRightFirst = 11,
+}
+
+/// Pixels to remove from each edge of the decoded picture before display.
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Default)]
+pub struct Crop {
+    pub left: u64,
+    pub right: u64,
+    pub top: u64,
+    pub bottom: u64,
+}
+
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Default)]
+pub enum ProjectionType {
+    #[default]
+    Rectangular = 0,
+    Equirectangular = 1,
+    CubeMap = 2,
+    Mesh = 3,
+}
+
+/// Spherical video projection, as described by the Matroska `Projection`
+/// element. `private` holds the ProjectionPrivate data for the type (e.g.
+/// the `equi` or `cbmp` box payload); pose angles are in degrees.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Projection {
+    pub projection_type: ProjectionType,
+    pub private: Vec<u8>,
+    pub yaw: f32,
+    pub pitch: f32,
+    pub roll: f32,
+}
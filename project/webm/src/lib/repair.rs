@@ -0,0 +1,228 @@
+//! Recovering recordings that never got to `Segment::finalize`.
+//!
+//! A live recording that is killed leaves a stream with unknown element
+//! sizes, no Cues and no Duration, often ending part way through a cluster.
+//! `repair` reads what the parser can make of it and muxes it again in file
+//! mode, leaving out the incomplete trailing cluster.
+
+use demux::{Demuxer, Frame, TrackInfo, TrackType};
+use ebml;
+use mux::{self, Track};
+use std::fs::File;
+use std::io::{self, Read, Seek, Write};
+use std::path::Path;
+use {Error, Result};
+
+/// What `repair` recovered.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    /// Frames copied to the output.
+    pub frames: u64,
+    /// Frames of an incomplete last cluster that were left out.
+    pub dropped_frames: u64,
+    /// Tracks the muxer can not recreate, such as text tracks, left out
+    /// along with their frames.
+    pub skipped_tracks: Vec<u64>,
+    /// Timestamp of the last frame kept.
+    pub last_timestamp_ns: Option<u64>,
+    /// Whether the source ends inside an element or holds a cluster or
+    /// block the demuxer could not read. Whatever followed that point is
+    /// lost.
+    pub truncated: bool,
+}
+
+/// Muxes the readable part of `source` into `dest` as a finalized file mode
+/// segment, with element sizes, Duration and Cues. Track numbers, codec
+/// settings and frame timestamps are kept; frames are not re-encoded.
+///
+/// A cluster the stream ends in the middle of is left out entirely, as are
+/// any bytes after it. For a chunked recording, repair the chunks
+/// concatenated in order.
+pub fn repair<R, W>(source: R, dest: W) -> Result<(W, RepairReport)>
+    where R: Read + Seek + 'static, W: Write + Seek
+{
+    let mut demuxer = Demuxer::new(source)?;
+    let mut report = RepairReport::default();
+
+    let segment = mux::Segment::with_mode(mux::Writer::new(dest), mux::SegmentMode::File)?;
+    let mut tracks = Vec::new();
+    for info in demuxer.tracks().to_vec() {
+        match add_track(&segment, &info)? {
+            Some(track) => tracks.push((info.number, track)),
+            None => report.skipped_tracks.push(info.number),
+        }
+    }
+
+    let mut cluster: Vec<Frame> = Vec::new();
+    while let Some(frame) = demuxer.next_frame()? {
+        if cluster.first().is_some_and(|f| f.cluster_offset != frame.cluster_offset) {
+            copy_frames(&mut tracks, cluster.drain(..), &mut report)?;
+        }
+        cluster.push(frame);
+    }
+    if let Some(offset) = cluster.first().map(|f| f.cluster_offset) {
+        if cluster_complete(&mut demuxer, offset)? {
+            copy_frames(&mut tracks, cluster.drain(..), &mut report)?;
+        } else {
+            report.dropped_frames = cluster.len() as u64;
+        }
+    }
+    report.truncated = demuxer.is_truncated();
+
+    drop(tracks);
+    let writer = segment.finalize(None)?;
+    Ok((writer.into_inner(), report))
+}
+
+/// Repairs the recording at `input` into a new file at `output`.
+pub fn repair_file<P: AsRef<Path>, Q: AsRef<Path>>(input: P, output: Q) -> Result<RepairReport> {
+    let source = File::open(input)?;
+    let (_, report) = repair(source, File::create(output)?)?;
+    Ok(report)
+}
+
+/// Recreates a demuxed track in `segment` under the same number, or returns
+/// `None` for a track the muxer has no codec for.
+pub(crate) fn add_track<'a, W: mux::MkvWriter>(segment: &'a mux::Segment<W>,
+                                               info: &TrackInfo) -> Result<Option<Box<dyn Track + 'a>>> {
+    let id = Some(info.number as i32);
+    let mut track: Box<dyn Track + 'a> = match (info.track_type, &info.video, &info.audio) {
+        (TrackType::Video, Some(video), _) => {
+            let codec = match info.codec_id.as_str() {
+                "V_VP8" => mux::VideoCodecId::VP8,
+                "V_VP9" => mux::VideoCodecId::VP9,
+                "V_AV1" => mux::VideoCodecId::AV1,
+                _ => return Ok(None),
+            };
+            Box::new(segment.add_video_track(video.width, video.height, id, codec)?)
+        }
+        (TrackType::Audio, _, Some(audio)) => {
+            let codec = match info.codec_id.as_str() {
+                "A_OPUS" => mux::AudioCodecId::Opus,
+                "A_VORBIS" => mux::AudioCodecId::Vorbis,
+                _ => return Ok(None),
+            };
+            let mut track = segment.add_audio_track(audio.sample_rate as i32, audio.channels as i32,
+                                                    id, codec)?;
+            track.set_codec_delay(info.codec_delay_ns);
+            track.set_seek_pre_roll(info.seek_pre_roll_ns);
+            Box::new(track)
+        }
+        _ => return Ok(None),
+    };
+    if !info.codec_private.is_empty() {
+        track.set_codec_private(&info.codec_private)?;
+    }
+    Ok(Some(track))
+}
+
+fn copy_frames<I>(tracks: &mut [(u64, Box<dyn Track + '_>)], frames: I,
+                  report: &mut RepairReport) -> Result<()>
+    where I: IntoIterator<Item = Frame>
+{
+    for frame in frames {
+        let track = match tracks.iter_mut().find(|(number, _)| *number == frame.track) {
+            Some((_, track)) => track,
+            None => continue,
+        };
+        track.add_frame(&frame.data, frame.timestamp_ns, frame.keyframe)?;
+        report.frames += 1;
+        report.last_timestamp_ns = Some(frame.timestamp_ns);
+    }
+    Ok(())
+}
+
+/// Whether the cluster at `offset` is all there: a cluster of known size
+/// must fit in the stream, one of unknown size must consist of whole child
+/// elements up to the next top level element or the end of the stream.
+fn cluster_complete(demuxer: &mut Demuxer, offset: u64) -> Result<bool> {
+    let len = demuxer.stream_len();
+    let (id, size, header_len) = match element_header(demuxer, offset)? {
+        Some(header) => header,
+        None => return Ok(false),
+    };
+    if id != ebml::ID_CLUSTER {
+        return Err(Error::Parse("frame does not lie in a cluster"));
+    }
+    let mut pos = offset + header_len;
+    if let Some(size) = size {
+        return Ok(pos + size <= len);
+    }
+
+    while pos < len {
+        let (id, size, header_len) = match element_header(demuxer, pos)? {
+            Some(header) => header,
+            None => return Ok(false),
+        };
+        // Cluster children have IDs of at most two bytes, top level
+        // elements four.
+        if id > 0xFF_FFFF {
+            return Ok(true);
+        }
+        match size {
+            Some(size) => pos += header_len + size,
+            None => return Ok(false),
+        }
+    }
+    Ok(pos == len)
+}
+
+/// Reads the ID, size (`None` if unknown) and header length of the element
+/// at `pos`, or `None` if the stream ends within the header or holds
+/// something else there.
+fn element_header(demuxer: &mut Demuxer, pos: u64) -> io::Result<Option<(u64, Option<u64>, u64)>> {
+    let mut buf = [0u8; 12];
+    let available = demuxer.stream_len().saturating_sub(pos).min(buf.len() as u64) as usize;
+    let buf = &mut buf[..available];
+    demuxer.read_at(pos, buf)?;
+    Ok(ebml::parse_header(buf).map(|(id, size, header_len)| (id, size, header_len as u64)))
+}
+
+#[test]
+fn truncated_live_recording() {
+    use std::io::Cursor;
+    use {add_test_video, mux_to_vec};
+
+    let bytes = mux_to_vec(mux::SegmentMode::Live, |segment| {
+        let mut vt = add_test_video(segment, mux::VideoCodecId::VP8);
+        let mut at = segment.add_audio_track(48_000, 1, None, mux::AudioCodecId::Opus).unwrap();
+        at.set_codec_private(&mux::opus_head(48_000, 1, 312).unwrap()).unwrap();
+        at.set_codec_delay(6_500_000);
+        for i in 0..90u64 {
+            vt.add_frame(&[i as u8; 500], i * 40_000_000, i % 30 == 0).unwrap();
+            at.add_frame(&[i as u8; 40], i * 40_000_000 + 20_000_000, true).unwrap();
+        }
+    });
+
+    let mut demuxer = Demuxer::new(Cursor::new(bytes.clone())).unwrap();
+    let original: Vec<Frame> = demuxer.frames().collect::<Result<_>>().unwrap();
+    let last_cluster = original.last().unwrap().cluster_offset;
+    let complete = original.iter().filter(|f| f.cluster_offset != last_cluster).count();
+
+    // Cut in the middle of the last block: the last cluster goes.
+    let (repaired, report) = repair(Cursor::new(bytes[..bytes.len() - 10].to_vec()),
+                                    Cursor::new(Vec::new())).unwrap();
+    assert_eq!(report.frames, complete as u64);
+    assert!(report.dropped_frames > 0);
+    assert!(report.truncated);
+    assert!(report.skipped_tracks.is_empty());
+    let repaired = repaired.into_inner();
+    assert!(repaired.windows(4).any(|w| w == [0x1C, 0x53, 0xBB, 0x6B]), "no Cues");
+
+    let mut demuxer = Demuxer::new(Cursor::new(repaired)).unwrap();
+    assert!(demuxer.duration_ns().is_some());
+    assert_eq!(demuxer.tracks()[1].codec_private, mux::opus_head(48_000, 1, 312).unwrap());
+    assert_eq!(demuxer.tracks()[1].codec_delay_ns, 6_500_000);
+    let frames: Vec<Frame> = demuxer.frames().collect::<Result<_>>().unwrap();
+    assert_eq!(frames.len(), complete);
+    for (frame, original) in frames.iter().zip(&original) {
+        assert_eq!((frame.track, frame.timestamp_ns, frame.keyframe, &frame.data),
+                   (original.track, original.timestamp_ns, original.keyframe, &original.data));
+    }
+
+    // Nothing is lost from a recording that ends between clusters.
+    let (_, report) = repair(Cursor::new(bytes), Cursor::new(Vec::new())).unwrap();
+    assert_eq!(report.frames, 180);
+    assert_eq!(report.dropped_frames, 0);
+    assert!(!report.truncated);
+}
@@ -0,0 +1,348 @@
+//! Cutting recordings without re-encoding them.
+//!
+//! Video can only start at a keyframe, so a cut is moved back to the
+//! keyframe at or before the requested time. Audio is kept from that point
+//! on to the sample: the audio frames a cut falls into are kept whole, with
+//! discard padding telling the decoder which part of them to drop. A piece
+//! starts at its earliest kept frame, so the video after such a frame is
+//! offset by the audio the decoder drops and both still start together.
+
+use demux::{Demuxer, TrackType};
+use mux;
+use repair::add_track;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, Write};
+use std::path::Path;
+use {Error, Result};
+
+/// What went into one piece of a `trim` or `split`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TrimReport {
+    /// Where the piece starts in the source: the requested start, moved back
+    /// to the video keyframe at or before it.
+    pub start_ns: u64,
+    /// Where timestamps in the piece start from zero in the source:
+    /// `start_ns`, or earlier when an audio frame the cut falls into is kept.
+    pub origin_ns: u64,
+    /// Where the piece ends in the source, or `None` at the end of the stream.
+    pub end_ns: Option<u64>,
+    /// Frames copied to the piece.
+    pub frames: u64,
+    /// Tracks the muxer can not recreate, such as text tracks, left out
+    /// along with their frames.
+    pub skipped_tracks: Vec<u64>,
+    /// Whether the source ends inside an element or holds a cluster or
+    /// block the demuxer could not read, so that the piece may be missing
+    /// frames from after that point.
+    pub truncated: bool,
+}
+
+/// Where the keyframes and audio frames of a stream are.
+struct Layout {
+    /// Keyframe timestamps of the first video track.
+    keyframes: Vec<u64>,
+    /// Timestamps of the frames of each audio track, in order.
+    audio: HashMap<u64, Vec<u64>>,
+    end_ns: u64,
+}
+
+impl Layout {
+    fn scan(demuxer: &mut Demuxer) -> Result<Layout> {
+        let video = demuxer.tracks().iter().find(|t| t.track_type == TrackType::Video).map(|t| t.number);
+        let mut audio: HashMap<u64, Vec<u64>> = demuxer.tracks().iter()
+            .filter(|t| t.track_type == TrackType::Audio)
+            .map(|t| (t.number, Vec::new()))
+            .collect();
+        let mut layout = Layout { keyframes: Vec::new(), audio: HashMap::new(), end_ns: 0 };
+        while let Some(frame) = demuxer.next_frame()? {
+            if Some(frame.track) == video && frame.keyframe {
+                layout.keyframes.push(frame.timestamp_ns);
+            }
+            if let Some(timestamps) = audio.get_mut(&frame.track) {
+                timestamps.push(frame.timestamp_ns);
+            }
+            layout.end_ns = layout.end_ns.max(frame.timestamp_ns);
+        }
+        layout.end_ns = demuxer.duration_ns().unwrap_or(0).max(layout.end_ns);
+        layout.audio = audio;
+        demuxer.rewind()?;
+        Ok(layout)
+    }
+
+    /// The keyframe at or before `timestamp_ns`, or `timestamp_ns` itself
+    /// if there is none (or no video).
+    fn snap(&self, timestamp_ns: u64) -> u64 {
+        match self.keyframes.iter().rev().find(|&&k| k <= timestamp_ns) {
+            Some(&keyframe) => keyframe,
+            None => timestamp_ns,
+        }
+    }
+
+    /// The start of the earliest audio frame a cut at `start_ns` falls
+    /// into, or `start_ns` if it falls between frames on every track.
+    fn origin(&self, start_ns: u64) -> u64 {
+        self.audio.values()
+            .filter_map(|timestamps| (0..timestamps.len()).find(|&i| {
+                timestamps[i] < start_ns && timestamps[i] + Layout::audio_duration(timestamps, i) > start_ns
+            }).map(|i| timestamps[i]))
+            .fold(start_ns, u64::min)
+    }
+
+    /// Duration of the `index`th frame of an audio track: up to the next
+    /// frame, or as long as the one before for the last.
+    fn audio_duration(timestamps: &[u64], index: usize) -> u64 {
+        match (timestamps.get(index + 1), index.checked_sub(1).map(|i| timestamps[i])) {
+            (Some(&next), _) => next - timestamps[index],
+            (None, Some(previous)) => timestamps[index] - previous,
+            (None, None) => 0,
+        }
+    }
+}
+
+/// Muxes the part of `source` from `start_ns` (moved back to a keyframe) up
+/// to `end_ns` into `dest` as a finalized file mode segment.
+///
+/// Video frames are kept up to, not including, `end_ns`; audio is cut at
+/// `start` and `end_ns` exactly using discard padding.
+pub fn trim<R, W>(source: R, dest: W, start_ns: u64, end_ns: Option<u64>) -> Result<(W, TrimReport)>
+    where R: Read + Seek + 'static, W: Write + Seek
+{
+    let mut demuxer = Demuxer::new(source)?;
+    let layout = Layout::scan(&mut demuxer)?;
+    let start_ns = layout.snap(start_ns);
+    if end_ns.is_some_and(|end| end <= start_ns) {
+        return Err(Error::InvalidArgument("trim ends before it starts"));
+    }
+    cut(&mut demuxer, &layout, start_ns, end_ns, dest)
+}
+
+/// Trims the recording at `input` into a new file at `output`.
+pub fn trim_file<P: AsRef<Path>, Q: AsRef<Path>>(input: P, output: Q, start_ns: u64,
+                                                 end_ns: Option<u64>) -> Result<TrimReport> {
+    let (_, report) = trim(File::open(input)?, File::create(output)?, start_ns, end_ns)?;
+    Ok(report)
+}
+
+/// Splits `source` into `pieces` of about equal duration, each cut moved
+/// back to a keyframe. `dest` is called with the index of each piece for
+/// the writer to mux it into.
+///
+/// Fails with `InvalidArgument` if there are not enough keyframes to start
+/// every piece at a different one.
+pub fn split<R, W, F>(source: R, pieces: usize, mut dest: F) -> Result<Vec<(W, TrimReport)>>
+    where R: Read + Seek + 'static, W: Write + Seek, F: FnMut(usize) -> Result<W>
+{
+    if pieces == 0 {
+        return Err(Error::InvalidArgument("no pieces to split into"));
+    }
+    let mut demuxer = Demuxer::new(source)?;
+    let layout = Layout::scan(&mut demuxer)?;
+    let mut cuts: Vec<u64> = (0..pieces as u64)
+        .map(|i| if i == 0 { 0 } else { layout.snap(layout.end_ns * i / pieces as u64) })
+        .collect();
+    cuts.dedup();
+    if cuts.len() < pieces {
+        return Err(Error::InvalidArgument("too few keyframes for that many pieces"));
+    }
+
+    let mut outputs = Vec::with_capacity(pieces);
+    for (index, &start_ns) in cuts.iter().enumerate() {
+        let end_ns = cuts.get(index + 1).cloned();
+        outputs.push(cut(&mut demuxer, &layout, start_ns, end_ns, dest(index)?)?);
+        demuxer.rewind()?;
+    }
+    Ok(outputs)
+}
+
+/// Splits the recording at `input` into one new file per path in `outputs`.
+pub fn split_file<P: AsRef<Path>, Q: AsRef<Path>>(input: P, outputs: &[Q]) -> Result<Vec<TrimReport>> {
+    let pieces = split(File::open(input)?, outputs.len(), |index| Ok(File::create(&outputs[index])?))?;
+    Ok(pieces.into_iter().map(|(_, report)| report).collect())
+}
+
+fn cut<W: Write + Seek>(demuxer: &mut Demuxer, layout: &Layout, start_ns: u64, end_ns: Option<u64>,
+                        dest: W) -> Result<(W, TrimReport)> {
+    let origin_ns = layout.origin(start_ns);
+    let mut report = TrimReport { start_ns, origin_ns, end_ns, ..TrimReport::default() };
+    let segment = mux::Segment::with_mode(mux::Writer::new(dest), mux::SegmentMode::File)?;
+    let mut tracks = Vec::new();
+    for info in demuxer.tracks().to_vec() {
+        match add_track(&segment, &info)? {
+            Some(track) => tracks.push((info.number, track)),
+            None => report.skipped_tracks.push(info.number),
+        }
+    }
+
+    let end = end_ns.unwrap_or(u64::MAX);
+    let mut audio_index: HashMap<u64, usize> = HashMap::new();
+    while let Some(frame) = demuxer.next_frame()? {
+        let track = match tracks.iter_mut().find(|(number, _)| *number == frame.track) {
+            Some((_, track)) => track,
+            None => continue,
+        };
+
+        let timestamp_ns = frame.timestamp_ns;
+        let mut discard_padding_ns = 0;
+        if let Some(timestamps) = layout.audio.get(&frame.track) {
+            let index = audio_index.entry(frame.track).or_insert(0);
+            let duration_ns = Layout::audio_duration(timestamps, *index);
+            *index += 1;
+            let frame_end = timestamp_ns + duration_ns;
+            if frame_end <= start_ns || timestamp_ns >= end {
+                continue;
+            }
+            if timestamp_ns < start_ns {
+                discard_padding_ns = -((start_ns - timestamp_ns) as i64);
+            } else if frame_end > end {
+                discard_padding_ns = (frame_end - end) as i64;
+            }
+        } else if timestamp_ns < start_ns || timestamp_ns >= end {
+            continue;
+        }
+
+        let frame = mux::Frame::new(&frame.data, timestamp_ns - origin_ns)
+            .keyframe(frame.keyframe)
+            .discard_padding_ns(discard_padding_ns);
+        track.add_generic_frame(&frame)?;
+        report.frames += 1;
+    }
+    report.truncated = demuxer.is_truncated();
+
+    drop(tracks);
+    let writer = segment.finalize(None)?;
+    Ok((writer.into_inner(), report))
+}
+
+#[cfg(test)]
+fn record() -> Vec<u8> {
+    use mux::Track;
+    use {add_test_video, mux_to_vec};
+
+    mux_to_vec(mux::SegmentMode::Live, |segment| {
+        let mut vt = add_test_video(segment, mux::VideoCodecId::VP8);
+        let mut at = segment.add_audio_track(48_000, 1, None, mux::AudioCodecId::Opus).unwrap();
+        at.set_codec_private(&mux::opus_head(48_000, 1, 312).unwrap()).unwrap();
+        // 2 s of 25 fps video with a keyframe every 400 ms, and 20 ms audio
+        // frames 10 ms off the video frames.
+        for i in 0..100u64 {
+            if i % 2 == 0 {
+                vt.add_frame(&[i as u8; 100], i * 20_000_000, i % 20 == 0).unwrap();
+            }
+            at.add_frame(&[i as u8; 20], i * 20_000_000 + 10_000_000, true).unwrap();
+        }
+    })
+}
+
+/// The frames of track `number` in `bytes`, with their discard padding.
+#[cfg(test)]
+fn frames(bytes: &[u8], number: u64) -> Vec<(u64, bool, i64)> {
+    use demux::Frame;
+    use ebml::{self, Reader, Value};
+    use std::io::Cursor;
+
+    let mut paddings = Vec::new();
+    let mut reader = Reader::new(Cursor::new(bytes.to_vec())).unwrap();
+    let segment = reader.elements_from(0).unwrap().into_iter().find(|e| e.id == ebml::ID_SEGMENT).unwrap();
+    for cluster in reader.children(&segment).unwrap().into_iter().filter(|e| e.id == ebml::ID_CLUSTER) {
+        for block in reader.children(&cluster).unwrap() {
+            let (header, padding) = match block.id {
+                ebml::ID_SIMPLE_BLOCK => (ebml::parse_block_header(&reader.read_data(&block, 12).unwrap(), true), 0),
+                ebml::ID_BLOCK_GROUP => {
+                    let children = reader.children(&block).unwrap();
+                    let data = children.iter().find(|c| c.id == ebml::ID_BLOCK).unwrap();
+                    let header = ebml::parse_block_header(&reader.read_data(data, 12).unwrap(), false);
+                    let padding = match children.iter().find(|c| c.id == ebml::ID_DISCARD_PADDING) {
+                        Some(padding) => match reader.read_value(padding).unwrap() {
+                            Some(Value::Int(padding)) => padding,
+                            other => panic!("{:?}", other),
+                        },
+                        None => 0,
+                    };
+                    (header, padding)
+                }
+                _ => continue,
+            };
+            if header.unwrap().track == number {
+                paddings.push(padding);
+            }
+        }
+    }
+
+    let mut demuxer = Demuxer::new(Cursor::new(bytes.to_vec())).unwrap();
+    let frames: Vec<Frame> = demuxer.frames().collect::<Result<_>>().unwrap();
+    let frames: Vec<(u64, bool)> = frames.into_iter().filter(|f| f.track == number)
+        .map(|f| (f.timestamp_ns, f.keyframe)).collect();
+    assert_eq!(frames.len(), paddings.len());
+    frames.into_iter().zip(paddings).map(|((t, k), p)| (t, k, p)).collect()
+}
+
+#[test]
+fn trim_to_keyframe() {
+    use std::io::Cursor;
+
+    let (trimmed, report) = trim(Cursor::new(record()), Cursor::new(Vec::new()),
+                                 500_000_000, Some(1_000_000_000)).unwrap();
+    assert_eq!((report.start_ns, report.end_ns), (400_000_000, Some(1_000_000_000)));
+    let trimmed = trimmed.into_inner();
+
+    // The piece starts at the audio frame the cut falls into, at 390 ms.
+    assert_eq!(report.origin_ns, 390_000_000);
+
+    // 400 ms up to 1 s: video from the keyframe at 400 ms on.
+    let video = frames(&trimmed, 1);
+    assert_eq!(video.len(), 15);
+    assert_eq!(video[0], (10_000_000, true, 0));
+    assert_eq!(video[14], (570_000_000, false, 0));
+
+    // Audio from the frame at 390 ms, less its first 10 ms, to the frame at
+    // 990 ms, less its last 10 ms.
+    let audio = frames(&trimmed, 2);
+    assert_eq!(audio.len(), 31);
+    assert_eq!(audio[0], (0, true, -10_000_000));
+    assert_eq!(audio[1], (20_000_000, true, 0));
+    assert_eq!(audio[30], (600_000_000, true, 10_000_000));
+    assert_eq!(report.frames, 46);
+    assert!(!report.truncated);
+
+    // Decoded, the audio starts with the first video frame.
+    assert_eq!(audio[0].0 as i64 - audio[0].2, video[0].0 as i64);
+
+    assert!(matches!(trim(Cursor::new(record()), Cursor::new(Vec::new()), 500_000_000, Some(400_000_000)),
+                     Err(Error::InvalidArgument(_))));
+
+    let mut cut_off = record();
+    cut_off.truncate(cut_off.len() - 10);
+    let (_, report) = trim(Cursor::new(cut_off), Cursor::new(Vec::new()), 0, None).unwrap();
+    assert!(report.truncated);
+}
+
+#[test]
+fn split_in_pieces() {
+    use std::io::Cursor;
+
+    let pieces = split(Cursor::new(record()), 3, |_| Ok(Cursor::new(Vec::new()))).unwrap();
+    let cuts: Vec<(u64, Option<u64>)> = pieces.iter().map(|(_, r)| (r.start_ns, r.end_ns)).collect();
+    assert_eq!(cuts, vec![(0, Some(400_000_000)), (400_000_000, Some(1_200_000_000)),
+                          (1_200_000_000, None)]);
+
+    let mut video = 0;
+    let mut audio = 0;
+    for (piece, report) in &pieces {
+        let piece = piece.get_ref();
+        let piece_video = frames(piece, 1);
+        assert_eq!(piece_video[0], (report.start_ns - report.origin_ns, true, 0));
+        let piece_audio = frames(piece, 2);
+        if piece_audio[0].2 < 0 {
+            assert_eq!(piece_audio[0].0 as i64 - piece_audio[0].2, piece_video[0].0 as i64);
+        }
+        video += piece_video.len();
+        audio += piece_audio.iter().filter(|f| f.2 >= 0).count();
+    }
+    // Every frame lands in one piece; audio frames across a cut in both,
+    // trimmed from the end of one and the start of the next.
+    assert_eq!((video, audio), (50, 100));
+
+    assert!(matches!(split(Cursor::new(record()), 10, |_| Ok(Cursor::new(Vec::new()))),
+                     Err(Error::InvalidArgument(_))));
+}
@@ -0,0 +1,476 @@
+//! Checks muxed output for mistakes players trip over, reporting each as a
+//! `Diagnostic` that tests can match on.
+
+use ebml::{self, Element, Reader, Value};
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek};
+use std::path::Path;
+use Result;
+
+const TRACK_TYPE_VIDEO: u64 = 1;
+
+/// A rule the stream breaks.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Violation {
+    /// The first frame of a video track in a stream is not a keyframe, so
+    /// nothing can be shown until the next one.
+    FirstVideoFrameNotKeyframe { track: u64 },
+    /// A block of `track` is timestamped before the block before it.
+    NonMonotonicTimestamp { track: u64, timestamp_ns: i64, previous_ns: i64 },
+    /// A block's timecode relative to its cluster is negative: the cluster
+    /// ran on past the 16 bit range and the timecode wrapped.
+    ClusterTimecodeOverflow { track: u64, relative_timecode: i16 },
+    /// A block's timestamp in nanoseconds does not fit in 64 bits: the
+    /// cluster timecode or the timecode scale is garbage.
+    TimestampOutOfRange { track: u64 },
+    /// An Opus track without its OpusHead CodecPrivate.
+    MissingCodecPrivate { track: u64, codec_id: String },
+    /// A Segment or Cluster (or any other element) whose size was never
+    /// filled in. Expected of live mode output, an error in file mode.
+    UnknownSize { id: u64 },
+    /// An element runs past the end of the file.
+    Truncated { id: u64 },
+    /// A chunk does not start with a header or at a cluster, or ends
+    /// inside an element.
+    ChunkBoundary(&'static str),
+}
+
+/// Where a `Violation` was found.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// Index of the chunk, when validating a chunk set.
+    pub chunk: Option<usize>,
+    /// Position of the offending element in the file or chunk.
+    pub offset: u64,
+    pub violation: Violation,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = |id: u64| ebml::element_name(id).map_or(format!("0x{:X}", id), |n| n.to_string());
+        match self {
+            Violation::FirstVideoFrameNotKeyframe { track } => {
+                write!(f, "first frame of video track {} is not a keyframe", track)
+            }
+            Violation::NonMonotonicTimestamp { track, timestamp_ns, previous_ns } => {
+                write!(f, "track {} goes back from {} ns to {} ns", track, previous_ns, timestamp_ns)
+            }
+            Violation::ClusterTimecodeOverflow { track, relative_timecode } => {
+                write!(f, "block of track {} has relative timecode {}", track, relative_timecode)
+            }
+            Violation::TimestampOutOfRange { track } => {
+                write!(f, "block of track {} has a timestamp out of range", track)
+            }
+            Violation::MissingCodecPrivate { track, codec_id } => {
+                write!(f, "{} track {} has no CodecPrivate", codec_id, track)
+            }
+            Violation::UnknownSize { id } => write!(f, "{} has an unknown size", name(*id)),
+            Violation::Truncated { id } => write!(f, "{} runs past the end of the file", name(*id)),
+            Violation::ChunkBoundary(what) => write!(f, "chunk {}", what),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(chunk) = self.chunk {
+            write!(f, "chunk {}, ", chunk)?;
+        }
+        write!(f, "offset {}: {}", self.offset, self.violation)
+    }
+}
+
+struct TrackState {
+    number: u64,
+    track_type: u64,
+    /// Timestamp of the last block.
+    last_ns: Option<i64>,
+    /// Whether the first block of the current stream went by.
+    started: bool,
+}
+
+impl TrackState {
+    fn new(number: u64) -> TrackState {
+        TrackState { number, track_type: 0, last_ns: None, started: false }
+    }
+}
+
+/// What carries over from one chunk to the next.
+struct Validator {
+    chunk: Option<usize>,
+    timecode_scale: i64,
+    tracks: Vec<TrackState>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Validator {
+    fn new() -> Validator {
+        Validator {
+            chunk: None,
+            timecode_scale: 1_000_000,
+            tracks: Vec::new(),
+            diagnostics: Vec::new(),
+        }
+    }
+
+    fn report(&mut self, offset: u64, violation: Violation) {
+        self.diagnostics.push(Diagnostic { chunk: self.chunk, offset, violation });
+    }
+
+    fn check<R: Read + Seek>(&mut self, reader: &mut Reader<R>) -> Result<()> {
+        let elements = reader.elements_from(0)?;
+        if self.chunk.unwrap_or(0) > 0 {
+            match elements.first() {
+                Some(first) if first.id == ebml::ID_EBML || first.id == ebml::ID_CLUSTER => {}
+                _ => self.report(0, Violation::ChunkBoundary("does not start with a header or a cluster")),
+            }
+        }
+        for element in elements {
+            self.element(reader, &element)?;
+        }
+        Ok(())
+    }
+
+    fn element<R: Read + Seek>(&mut self, reader: &mut Reader<R>, element: &Element) -> Result<()> {
+        if element.size.is_none() {
+            self.report(element.offset, Violation::UnknownSize { id: element.id });
+        }
+        if element.end().is_some_and(|end| end > reader.stream_len()) {
+            let violation = match self.chunk {
+                Some(_) => Violation::ChunkBoundary("ends inside an element"),
+                None => Violation::Truncated { id: element.id },
+            };
+            self.report(element.offset, violation);
+        }
+
+        match element.id {
+            // A new stream. The chunks of one recording carry on from each
+            // other's timestamps; anything else starts over.
+            ebml::ID_EBML if self.chunk.is_some() => {
+                for track in &mut self.tracks {
+                    track.started = false;
+                }
+            }
+            ebml::ID_EBML => self.tracks.clear(),
+            ebml::ID_SEGMENT => {
+                for child in reader.children(element)? {
+                    self.element(reader, &child)?;
+                }
+            }
+            ebml::ID_INFO => {
+                let children = reader.children(element)?;
+                if let Some(scale) = children.iter().find(|c| c.id == ebml::ID_TIMECODE_SCALE) {
+                    if let Some(Value::Uint(scale)) = reader.read_value(scale)? {
+                        self.timecode_scale = i64::try_from(scale).unwrap_or(i64::MAX);
+                    }
+                }
+            }
+            ebml::ID_TRACKS => {
+                for entry in reader.children(element)? {
+                    if entry.id == ebml::ID_TRACK_ENTRY {
+                        self.track_entry(reader, &entry)?;
+                    }
+                }
+            }
+            ebml::ID_CLUSTER => self.cluster(reader, element)?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn track_entry<R: Read + Seek>(&mut self, reader: &mut Reader<R>, entry: &Element) -> Result<()> {
+        let mut number = 0;
+        let mut track_type = 0;
+        let mut codec_id = String::new();
+        let mut has_private = false;
+        for child in reader.children(entry)? {
+            match (child.id, reader.read_value(&child)?) {
+                (ebml::ID_TRACK_NUMBER, Some(Value::Uint(value))) => number = value,
+                (ebml::ID_TRACK_TYPE, Some(Value::Uint(value))) => track_type = value,
+                (ebml::ID_CODEC_ID, Some(Value::String(id))) => codec_id = id,
+                (ebml::ID_CODEC_PRIVATE, Some(Value::Binary(len))) => has_private = len > 0,
+                _ => {}
+            }
+        }
+        if codec_id == "A_OPUS" && !has_private {
+            self.report(entry.offset, Violation::MissingCodecPrivate { track: number, codec_id });
+        }
+        let index = self.track_index(number);
+        self.tracks[index].track_type = track_type;
+        Ok(())
+    }
+
+    fn cluster<R: Read + Seek>(&mut self, reader: &mut Reader<R>, cluster: &Element) -> Result<()> {
+        let mut cluster_timecode = 0;
+        for child in reader.children(cluster)? {
+            let header = match child.id {
+                ebml::ID_TIMECODE => {
+                    if let Some(Value::Uint(timecode)) = reader.read_value(&child)? {
+                        cluster_timecode = i64::try_from(timecode).unwrap_or(i64::MAX);
+                    }
+                    continue;
+                }
+                ebml::ID_SIMPLE_BLOCK => ebml::parse_block_header(&reader.read_data(&child, 12)?, true),
+                ebml::ID_BLOCK_GROUP => {
+                    let children = reader.children(&child)?;
+                    let referenced = children.iter().any(|c| c.id == ebml::ID_REFERENCE_BLOCK);
+                    match children.iter().find(|c| c.id == ebml::ID_BLOCK) {
+                        Some(block) => ebml::parse_block_header(&reader.read_data(block, 12)?, false)
+                            .map(|h| ebml::BlockHeader { keyframe: !referenced, ..h }),
+                        None => None,
+                    }
+                }
+                _ => continue,
+            };
+            if let Some(header) = header {
+                self.block(child.offset, cluster_timecode, header);
+            }
+        }
+        Ok(())
+    }
+
+    fn block(&mut self, offset: u64, cluster_timecode: i64, header: ebml::BlockHeader) {
+        if header.timecode < 0 {
+            self.report(offset, Violation::ClusterTimecodeOverflow {
+                track: header.track,
+                relative_timecode: header.timecode,
+            });
+        }
+
+        let timestamp_ns = match cluster_timecode.checked_add(i64::from(header.timecode))
+            .and_then(|timecode| timecode.checked_mul(self.timecode_scale)) {
+            Some(timestamp_ns) => timestamp_ns,
+            None => {
+                self.report(offset, Violation::TimestampOutOfRange { track: header.track });
+                return;
+            }
+        };
+        // Unknown only for a media chunk checked without its header.
+        let index = self.track_index(header.track);
+
+        let previous = self.tracks[index].last_ns.replace(timestamp_ns);
+        if let Some(previous_ns) = previous.filter(|&p| timestamp_ns < p) {
+            self.report(offset, Violation::NonMonotonicTimestamp {
+                track: header.track,
+                timestamp_ns,
+                previous_ns,
+            });
+        }
+
+        let track = &mut self.tracks[index];
+        if !track.started {
+            track.started = true;
+            if track.track_type == TRACK_TYPE_VIDEO && !header.keyframe {
+                self.report(offset, Violation::FirstVideoFrameNotKeyframe { track: header.track });
+            }
+        }
+    }
+
+    /// Index of the track numbered `number`, added if it is new.
+    fn track_index(&mut self, number: u64) -> usize {
+        match self.tracks.iter().position(|t| t.number == number) {
+            Some(index) => index,
+            None => {
+                self.tracks.push(TrackState::new(number));
+                self.tracks.len() - 1
+            }
+        }
+    }
+}
+
+/// Checks a WebM stream. An empty list means nothing was found wrong.
+pub fn validate<R: Read + Seek>(source: R) -> Result<Vec<Diagnostic>> {
+    let mut validator = Validator::new();
+    validator.check(&mut Reader::new(source)?)?;
+    Ok(validator.diagnostics)
+}
+
+/// Checks the chunks of a recording, in order, as written by `WebmWriter`
+/// in any `ChunkMode`. Timestamps must keep increasing from one chunk to
+/// the next; diagnostics carry the index of their chunk.
+pub fn validate_chunks<I, R>(chunks: I) -> Result<Vec<Diagnostic>>
+    where I: IntoIterator<Item = R>, R: Read + Seek
+{
+    let mut validator = Validator::new();
+    for (index, chunk) in chunks.into_iter().enumerate() {
+        validator.chunk = Some(index);
+        validator.check(&mut Reader::new(chunk)?)?;
+    }
+    Ok(validator.diagnostics)
+}
+
+pub fn validate_file<P: AsRef<Path>>(path: P) -> Result<Vec<Diagnostic>> {
+    validate(BufReader::new(File::open(path)?))
+}
+
+pub fn validate_chunk_files<P: AsRef<Path>>(paths: &[P]) -> Result<Vec<Diagnostic>> {
+    let chunks = paths.iter()
+        .map(|path| File::open(path).map(BufReader::new))
+        .collect::<::std::io::Result<Vec<_>>>()?;
+    validate_chunks(chunks)
+}
+
+#[cfg(test)]
+fn record(mode: ::mux::SegmentMode, opus_head: bool, first_keyframe: bool) -> Vec<u8> {
+    use mux::{self, Track};
+    use {add_test_video, mux_to_vec};
+
+    mux_to_vec(mode, |segment| {
+        let mut vt = add_test_video(segment, mux::VideoCodecId::VP8);
+        let mut at = segment.add_audio_track(48_000, 1, None, mux::AudioCodecId::Opus).unwrap();
+        if opus_head {
+            at.set_codec_private(&mux::opus_head(48_000, 1, 312).unwrap()).unwrap();
+        }
+        for i in 0..30u64 {
+            vt.add_frame(&[i as u8; 100], i * 40_000_000, i % 10 == 0 && (i > 0 || first_keyframe)).unwrap();
+            at.add_frame(&[i as u8; 20], i * 40_000_000 + 20_000_000, true).unwrap();
+        }
+    })
+}
+
+#[test]
+fn single_file() {
+    use mux::SegmentMode;
+    use std::io::Cursor;
+
+    let bytes = record(SegmentMode::File, true, true);
+    assert_eq!(validate(Cursor::new(bytes.clone())).unwrap(), vec![]);
+
+    let violations = |bytes: Vec<u8>| -> Vec<Violation> {
+        validate(Cursor::new(bytes)).unwrap().into_iter().map(|d| d.violation).collect()
+    };
+    assert_eq!(violations(record(SegmentMode::File, false, false)), vec![
+        Violation::MissingCodecPrivate { track: 2, codec_id: "A_OPUS".to_string() },
+        Violation::FirstVideoFrameNotKeyframe { track: 1 },
+    ]);
+    assert!(violations(record(SegmentMode::Live, true, true)).iter()
+        .all(|v| *v == Violation::UnknownSize { id: ebml::ID_SEGMENT }
+             || *v == Violation::UnknownSize { id: ebml::ID_CLUSTER }));
+    assert_eq!(violations(bytes[..bytes.len() - 10].to_vec()),
+               vec![Violation::Truncated { id: ebml::ID_SEGMENT },
+                    Violation::Truncated { id: ebml::ID_CUES }]);
+
+    // Wind the third block back to before the start of its cluster.
+    let mut reader = Reader::new(Cursor::new(bytes.clone())).unwrap();
+    let segment = reader.elements_from(0).unwrap().into_iter().find(|e| e.id == ebml::ID_SEGMENT).unwrap();
+    let cluster = reader.children(&segment).unwrap().into_iter().find(|e| e.id == ebml::ID_CLUSTER).unwrap();
+    let block = reader.children(&cluster).unwrap().into_iter()
+        .filter(|e| e.id == ebml::ID_SIMPLE_BLOCK).nth(2).unwrap();
+    let mut bytes = bytes;
+    let at = block.data_offset() as usize + 1;
+    bytes[at..at + 2].copy_from_slice(&(-5i16).to_be_bytes());
+    assert_eq!(validate(Cursor::new(bytes)).unwrap(), vec![
+        Diagnostic {
+            chunk: None,
+            offset: block.offset,
+            violation: Violation::ClusterTimecodeOverflow { track: 1, relative_timecode: -5 },
+        },
+        Diagnostic {
+            chunk: None,
+            offset: block.offset,
+            violation: Violation::NonMonotonicTimestamp { track: 1, timestamp_ns: -5_000_000, previous_ns: 0 },
+        },
+    ]);
+
+    let mut validator = Validator::new();
+    validator.block(7, i64::MAX / 1000, ebml::BlockHeader { track: 1, timecode: 0, keyframe: true });
+    validator.block(9, i64::MAX, ebml::BlockHeader { track: 1, timecode: 1, keyframe: true });
+    assert_eq!(validator.diagnostics.iter().map(|d| (d.offset, d.violation.clone())).collect::<Vec<_>>(), vec![
+        (7, Violation::TimestampOutOfRange { track: 1 }),
+        (9, Violation::TimestampOutOfRange { track: 1 }),
+    ]);
+}
+
+#[test]
+fn chunk_set() {
+    use add_test_video;
+    use mux::{self, Track};
+    use std::fs;
+
+    let dir = ::std::env::temp_dir().join(format!("webm-validate-{}", ::std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let (writer, chunk_rx) = mux::WebmWriter::with_channel(dir.to_str().unwrap(), "rec", mux::ChunkMode::Split).unwrap();
+    let segment = mux::Segment::new(writer).unwrap();
+    segment.set_max_cluster_duration(500_000_000);
+    let mut vt = add_test_video(&segment, mux::VideoCodecId::VP9);
+    for i in 0..60u64 {
+        vt.add_frame(&[i as u8; 100], i * 40_000_000, i % 15 == 0).unwrap();
+    }
+    drop(segment.finalize(None).ok().unwrap());
+
+    let paths: Vec<_> = chunk_rx.try_iter().map(|info| info.path).collect();
+    assert!(paths.len() > 3, "{:?}", paths);
+    let boundary_problems = |chunks: Vec<Vec<u8>>| -> Vec<Diagnostic> {
+        validate_chunks(chunks.into_iter().map(::std::io::Cursor::new)).unwrap().into_iter()
+            .filter(|d| !matches!(d.violation, Violation::UnknownSize { .. }))
+            .collect()
+    };
+    let chunks: Vec<Vec<u8>> = paths.iter().map(|p| fs::read(p).unwrap()).collect();
+    assert_eq!(validate_chunk_files(&paths).unwrap().iter()
+                   .filter(|d| !matches!(d.violation, Violation::UnknownSize { .. })).count(), 0);
+
+    let mut swapped = chunks.clone();
+    swapped.swap(1, 2);
+    let diagnostics = boundary_problems(swapped);
+    assert!(matches!(diagnostics[0], Diagnostic {
+        chunk: Some(1),
+        violation: Violation::FirstVideoFrameNotKeyframe { track: 1 }, ..
+    }), "{:?}", diagnostics);
+    assert!(diagnostics.iter().any(|d| d.chunk == Some(2)
+        && matches!(d.violation, Violation::NonMonotonicTimestamp { track: 1, .. })));
+
+    let mut cut = chunks.clone();
+    let half = cut[1].len() / 2;
+    let tail = cut[1].split_off(half);
+    cut[2].splice(..0, tail);
+    assert_eq!(boundary_problems(cut).iter().map(|d| (d.chunk, d.violation.clone())).collect::<Vec<_>>(), vec![
+        (Some(1), Violation::ChunkBoundary("ends inside an element")),
+        (Some(2), Violation::ChunkBoundary("does not start with a header or a cluster")),
+    ]);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn self_contained_chunks() {
+    use add_test_video;
+    use mux::{self, Track};
+    use std::fs;
+
+    let dir = ::std::env::temp_dir().join(format!("webm-validate-sc-{}", ::std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let (writer, chunk_rx) = mux::WebmWriter::with_channel(dir.to_str().unwrap(), "rec",
+                                                           mux::ChunkMode::SelfContained).unwrap();
+    let segment = mux::Segment::new(writer).unwrap();
+    segment.set_max_cluster_duration(500_000_000);
+    let mut vt = add_test_video(&segment, mux::VideoCodecId::VP9);
+    let mut at = segment.add_audio_track(48_000, 1, None, mux::AudioCodecId::Opus).unwrap();
+    at.set_codec_private(&mux::opus_head(48_000, 1, 312).unwrap()).unwrap();
+    for i in 0..60u64 {
+        vt.add_frame(&[i as u8; 100], i * 40_000_000, i % 15 == 0).unwrap();
+        at.add_frame(&[i as u8; 20], i * 40_000_000 + 20_000_000, true).unwrap();
+    }
+    drop(segment.finalize(None).ok().unwrap());
+
+    let chunks: Vec<Vec<u8>> = chunk_rx.try_iter().map(|info| fs::read(info.path).unwrap()).collect();
+    assert!(chunks.len() > 2);
+    let problems = |chunks: Vec<Vec<u8>>| -> Vec<(Option<usize>, Violation)> {
+        validate_chunks(chunks.into_iter().map(::std::io::Cursor::new)).unwrap().into_iter()
+            .filter(|d| !matches!(d.violation, Violation::UnknownSize { .. }))
+            .map(|d| (d.chunk, d.violation))
+            .collect()
+    };
+    assert_eq!(problems(chunks.clone()), vec![]);
+
+    // Every chunk repeats the header, and timestamps still carry on from
+    // one chunk to the next on every track.
+    let mut swapped = chunks;
+    swapped.swap(0, 1);
+    let problems = problems(swapped);
+    for track in 1..3 {
+        assert!(problems.iter().any(|p| matches!(p, (Some(1), Violation::NonMonotonicTimestamp { track: t, .. })
+                                                 if *t == track)), "{:?}", problems);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}